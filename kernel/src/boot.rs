@@ -1,8 +1,10 @@
 use crate::main;
 use kernel::memory::paging::MemoryMapFrameAllocator;
 use kernel::{
-    LIMINE_BASE_REVISION, graphics, init_globals, interrupts, memory, memory::allocator,
-    serial_println, util::cpuinfo::init_cpu_info,
+    LIMINE_BASE_REVISION, graphics, init_globals, interrupts, memory,
+    memory::allocator,
+    process, serial_println,
+    util::cpuinfo::{CpuFeatureFlags, get_cpu_info, init_cpu_info},
 };
 use limine::{
     BaseRevision, RequestsEndMarker, RequestsStartMarker,
@@ -70,6 +72,9 @@ unsafe extern "C" fn kmain() -> ! {
     serial_println!("BigOS Booted!");
 
     unsafe { init_cpu_info() };
+    if get_cpu_info().features.contains(CpuFeatureFlags::NX) {
+        unsafe { memory::paging::enable_no_execute() };
+    }
 
     // memory::paging::init_acpi_memory_map(rsdp_phys_addr);
 
@@ -112,7 +117,7 @@ unsafe extern "C" fn kmain() -> ! {
 
     serial_println!("Creating frame_allocator");
     let mut frame_allocator =
-        unsafe { MemoryMapFrameAllocator::init(memory_map_response.entries()) };
+        unsafe { MemoryMapFrameAllocator::init(memory_map_response.entries(), hhdm_offset) };
 
     serial_println!("Initializing heap");
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Failed to initialize heap");
@@ -134,6 +139,8 @@ unsafe extern "C" fn kmain() -> ! {
     memory::init_memory_globals(frame_allocator, user_memory_manager);
     serial_println!("Global memory managers initialized");
 
+    process::scheduler::init();
+
     interrupts::enable_interrupts();
 
     main()
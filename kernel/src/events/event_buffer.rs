@@ -18,6 +18,8 @@ pub enum Keys {
 }
 pub struct AsciiChar;
 impl AsciiChar {
+    /// Ctrl+C
+    pub const END_OF_TEXT: char = '\x03';
     pub const BACKSPACE: char = '\x08';
     pub const TAB: char = '\x09';
     pub const NEWLINE: char = '\n';
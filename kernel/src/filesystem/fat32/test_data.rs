@@ -6,7 +6,13 @@ use alloc::alloc::{Layout, alloc};
 use alloc::boxed::Box;
 use core::ptr;
 
-const IMAGE_SIZE: usize = 64 * 1024;
+const IMAGE_SIZE: usize = 256 * 1024;
+
+// user programs are built before the kernel, clippy only needs the type
+#[cfg(not(clippy))]
+const FIRST_ELF: &[u8] = include_bytes!("../../../../target/user/programs/first/first");
+#[cfg(clippy)]
+const FIRST_ELF: &[u8] = &[];
 
 #[inline(never)]
 pub fn create_fat32_image() -> Box<[u8; IMAGE_SIZE]> {
@@ -130,6 +136,23 @@ pub fn create_fat32_image() -> Box<[u8; IMAGE_SIZE]> {
     // Entry 6: file inside nested_dir (end of chain)
     fat[24..28].copy_from_slice(&END_OF_CHAIN.to_le_bytes());
 
+    // Entry 7: bin directory (end of chain)
+    fat[28..32].copy_from_slice(&END_OF_CHAIN.to_le_bytes());
+
+    // Entries 8..: bin/first, chained over as many clusters as the program needs
+    const CLUSTER_SIZE: usize = SECTORS_PER_CLUSTER * BYTES_PER_SECTOR;
+    let first_elf_cluster: usize = 8;
+    let first_elf_cluster_count = FIRST_ELF.len().div_ceil(CLUSTER_SIZE).max(1);
+    for i in 0..first_elf_cluster_count {
+        let cluster = first_elf_cluster + i;
+        let next = if i + 1 == first_elf_cluster_count {
+            END_OF_CHAIN
+        } else {
+            (cluster + 1) as u32
+        };
+        fat[cluster * 4..cluster * 4 + 4].copy_from_slice(&next.to_le_bytes());
+    }
+
     // Copy FAT tables
     let fat1_offset = fat1_start * BYTES_PER_SECTOR;
     let fat2_offset = fat2_start * BYTES_PER_SECTOR;
@@ -185,6 +208,13 @@ Sed id dui fringilla, tincidunt neque scelerisque, pharetra dolor. Sed ultrices
     root_dir[nested_dir_offset + 11] = FatFileAttributes::Directory as u8;
     root_dir[nested_dir_offset + 26..nested_dir_offset + 28].copy_from_slice(&5u16.to_le_bytes()); // First cluster low (cluster 5)
 
+    // bin directory entry
+    let bin_dir_offset = 128;
+    root_dir[bin_dir_offset..bin_dir_offset + 8].copy_from_slice(b"BIN     ");
+    root_dir[bin_dir_offset + 8..bin_dir_offset + 11].copy_from_slice(b"   ");
+    root_dir[bin_dir_offset + 11] = FatFileAttributes::Directory as u8;
+    root_dir[bin_dir_offset + 26..bin_dir_offset + 28].copy_from_slice(&7u16.to_le_bytes()); // First cluster low (cluster 7)
+
     // Copy root directory
     image[root_offset..root_offset + root_dir.len()].copy_from_slice(&root_dir);
 
@@ -256,6 +286,36 @@ Sed id dui fringilla, tincidunt neque scelerisque, pharetra dolor. Sed ultrices
     image[fat2_offset + cluster4_entry_offset..fat2_offset + cluster4_entry_offset + 4]
         .copy_from_slice(&END_OF_CHAIN.to_le_bytes());
 
+    // bin directory at cluster 7, holding the user programs
+    let mut bin_dir = [0u8; FAT_SIZE_BYTES];
+    bin_dir[0..8].copy_from_slice(dot_name);
+    bin_dir[8..11].copy_from_slice(b"   ");
+    bin_dir[11] = FatFileAttributes::Directory as u8;
+    bin_dir[26..28].copy_from_slice(&7u16.to_le_bytes()); // Points to itself (cluster 7)
+    bin_dir[32..40].copy_from_slice(dotdot_name);
+    bin_dir[40..43].copy_from_slice(b"   ");
+    bin_dir[43] = FatFileAttributes::Directory as u8;
+    bin_dir[58..60].copy_from_slice(&2u16.to_le_bytes()); // Points to root (cluster 2)
+
+    let first_entry_offset = 64;
+    bin_dir[first_entry_offset..first_entry_offset + 8].copy_from_slice(b"FIRST   ");
+    bin_dir[first_entry_offset + 8..first_entry_offset + 11].copy_from_slice(b"   ");
+    bin_dir[first_entry_offset + 11] = FatFileAttributes::Archive as u8;
+    bin_dir[first_entry_offset + 26..first_entry_offset + 28]
+        .copy_from_slice(&(first_elf_cluster as u16).to_le_bytes());
+    bin_dir[first_entry_offset + 28..first_entry_offset + 32]
+        .copy_from_slice(&(FIRST_ELF.len() as u32).to_le_bytes());
+
+    let bin_dir_cluster: usize = 7;
+    let bin_dir_data_offset =
+        (data_start + ((bin_dir_cluster - 2) * SECTORS_PER_CLUSTER)) * BYTES_PER_SECTOR;
+    image[bin_dir_data_offset..bin_dir_data_offset + bin_dir.len()].copy_from_slice(&bin_dir);
+
+    // the chain of bin/first is contiguous
+    let first_elf_offset =
+        (data_start + ((first_elf_cluster - 2) * SECTORS_PER_CLUSTER)) * BYTES_PER_SECTOR;
+    image[first_elf_offset..first_elf_offset + FIRST_ELF.len()].copy_from_slice(FIRST_ELF);
+
     serial_println!("FAT32 image creation complete");
     serial_println!("    Total sectors: {}", total_sector_count);
     serial_println!("    Reserved sectors: {}", RESERVED_SECTORS);
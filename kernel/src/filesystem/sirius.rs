@@ -176,6 +176,26 @@ impl Sirius {
         self.driver.read_file(node.node_id, offset, buffer)
     }
 
+    /// Reads the whole file at `path` into memory
+    pub fn read_whole_file(&mut self, path: &str) -> FileSystemResult<Vec<u8>> {
+        let node = self.open_file(path)?;
+        let mut data = alloc::vec![0u8; node.size];
+
+        let mut offset = 0;
+        while offset < node.size {
+            let read = self
+                .driver
+                .read_file(node.node_id, offset, &mut data[offset..])?;
+            if read == 0 {
+                break;
+            }
+            offset += read;
+        }
+        data.truncate(offset);
+
+        Ok(data)
+    }
+
     pub fn write_file(
         &mut self,
         path: &str,
@@ -189,6 +209,37 @@ impl Sirius {
         self.driver.write_file(node.node_id, offset, data)
     }
 
+    /// Reads from an already opened file, without resolving its path again
+    pub fn read_node(
+        &mut self,
+        node: &FileNode,
+        offset: usize,
+        buffer: &mut [u8],
+    ) -> FileSystemResult<usize> {
+        if node.file_type != FileType::File {
+            return Err(FileSystemError::IsDirectory);
+        }
+        self.driver.read_file(node.node_id, offset, buffer)
+    }
+
+    /// Writes to an already opened file, without resolving its path again
+    pub fn write_node(
+        &mut self,
+        node: &FileNode,
+        offset: usize,
+        data: &[u8],
+    ) -> FileSystemResult<usize> {
+        if node.file_type != FileType::File {
+            return Err(FileSystemError::IsDirectory);
+        }
+        self.driver.write_file(node.node_id, offset, data)
+    }
+
+    /// Current state of `node`, its size changes as it is written to
+    pub fn refresh_node(&self, node: &FileNode) -> FileSystemResult<FileNode> {
+        self.driver.get_node(node.node_id)
+    }
+
     pub fn create_file(&mut self, path: &str) -> FileSystemResult<FileNode> {
         let (parent_path, name) = self.split_path(path)?;
         let parent = self.resolve_path(parent_path.as_str())?;
@@ -1,5 +1,8 @@
+use crate::memory::stack::GuardedStack;
 use crate::serial_println;
+use core::cell::UnsafeCell;
 use lazy_static::lazy_static;
+use spin::Once;
 use x86_64::{
     VirtAddr,
     instructions::tables::load_tss,
@@ -11,18 +14,37 @@ use x86_64::{
 };
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+const DOUBLE_FAULT_STACK_SIZE: usize = 4 * 1024 * 1024;
+const PRIVILEGE_STACK_SIZE: usize = 64 * 1024;
+
+/// The CPU reads RSP0 from the TSS on every interrupt from ring 3,
+/// so it is changed in place whenever the scheduler switches tasks
+struct TssCell(UnsafeCell<TaskStateSegment>);
+
+unsafe impl Sync for TssCell {}
 
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    // the stacks here have nothing unmapped below them, they only serve until memory is set
+    // up and `install_guarded_stacks` replaces them
+    static ref TSS: TssCell = {
         let mut tss = TaskStateSegment::new();
-        const STACK_SIZE: usize = 4 * 1024 * 1024;
 
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
 
             let stack_start = VirtAddr::from_ptr(&raw const STACK);
 
-            stack_start + STACK_SIZE as u64
+            stack_start + DOUBLE_FAULT_STACK_SIZE as u64
+        };
+
+        // stack used when an interrupt arrives in ring 3 before the scheduler installs
+        // the running process's own kernel stack
+        tss.privilege_stack_table[0] = {
+            static mut KERNEL_STACK: [u8; PRIVILEGE_STACK_SIZE] = [0; PRIVILEGE_STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(&raw const KERNEL_STACK);
+
+            stack_start + PRIVILEGE_STACK_SIZE as u64
         };
 
         let val = tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize];
@@ -31,7 +53,7 @@ lazy_static! {
             val
         );
 
-        tss
+        TssCell(UnsafeCell::new(tss))
     };
 }
 
@@ -46,10 +68,12 @@ lazy_static! {
         let kernel_code_selector = table.append(Descriptor::kernel_code_segment());
         let kernel_data_selector = table.append(Descriptor::kernel_data_segment());
 
-        let user_code_selector = table.append(Descriptor::user_code_segment());
+        // sysret loads SS from STAR[63:48] + 8 and CS from STAR[63:48] + 16,
+        // so user data has to come right before user code
         let user_data_selector = table.append(Descriptor::user_data_segment());
+        let user_code_selector = table.append(Descriptor::user_code_segment());
 
-        let tss_selector = table.append(Descriptor::tss_segment(&TSS));
+        let tss_selector = table.append(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));
 
         Gdt {
             table,
@@ -104,6 +128,41 @@ pub fn init() {
     serial_println!("  TSS selector: {:?}", GDT.selectors.tss_selector);
 }
 
+/// Stacks from the guarded stack region which replaced the boot ones in the TSS
+struct GuardedStacks {
+    double_fault: GuardedStack,
+    privilege: GuardedStack,
+}
+
+static GUARDED_STACKS: Once<GuardedStacks> = Once::new();
+
+/// Moves the double fault handler and ring 3 interrupts before the first task switch onto
+/// stacks with guard pages. Needs the memory globals.
+pub fn install_guarded_stacks() {
+    let stacks = GUARDED_STACKS.call_once(|| GuardedStacks {
+        double_fault: GuardedStack::new(DOUBLE_FAULT_STACK_SIZE)
+            .expect("failed to map the double fault stack"),
+        privilege: GuardedStack::new(PRIVILEGE_STACK_SIZE)
+            .expect("failed to map the privilege stack"),
+    });
+    unsafe {
+        let tss = &mut *TSS.0.get();
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stacks.double_fault.top();
+        tss.privilege_stack_table[0] = stacks.privilege.top();
+    }
+    serial_println!(
+        "Guarded stacks installed: double fault stack at {:#x}",
+        stacks.double_fault.top()
+    );
+}
+
+/// Sets the stack the CPU switches to when an interrupt arrives while running in ring 3
+pub fn set_privilege_stack(stack_top: VirtAddr) {
+    unsafe {
+        (*TSS.0.get()).privilege_stack_table[0] = stack_top;
+    }
+}
+
 pub fn get_user_code_selector() -> SegmentSelector {
     GDT.selectors.user_code_selector
 }
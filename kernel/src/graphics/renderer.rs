@@ -2,6 +2,7 @@ use crate::graphics::color::Rgba8888UNORM;
 use crate::graphics::pipeline::{PSIn, PipelineState, RenderTarget, VSOut, Vertex2D};
 use crate::graphics::resources::{ConstantBuffer, RWBuffer, Texture};
 use crate::graphics::window::WindowBackBuffer;
+use crate::process::fpu::KernelFpu;
 use alloc::vec::Vec;
 use core::arch::x86_64::*;
 use core::simd::{cmp::SimdPartialOrd, f32x4};
@@ -66,6 +67,7 @@ impl RenderContext {
         render_target: &mut RenderTarget<'_>,
         pipeline: &PipelineState,
     ) {
+        let _fpu = KernelFpu::begin();
         self.draw_single_triangle_vertex_list(&[v0, v1, v2], render_target, pipeline);
     }
 
@@ -78,6 +80,7 @@ impl RenderContext {
         render_target: &mut RenderTarget<'_>,
         pipeline: &PipelineState,
     ) {
+        let _fpu = KernelFpu::begin();
         let vertices = [
             Vertex2D::new(x, y, 0.0, 0.0),
             Vertex2D::new(x + width, y, 1.0, 0.0),
@@ -270,6 +273,7 @@ impl RenderContext {
 
     #[inline]
     pub fn clear(&self, render_target: &mut RenderTarget<'_>, color: Rgba8888UNORM) {
+        let _fpu = KernelFpu::begin();
         unsafe {
             let buffer = render_target.get_buffer_mut();
             let len = buffer.len();
@@ -1,14 +1,22 @@
 #![allow(unused)]
 use crate::process::syscall::init_syscall;
 use crate::process::{
+    execution::InterruptFrame,
+    fault::{Exception, Fault},
+    fpu,
     process_manager::{ARCHE_PID, PROCESS_MANAGER},
+    process_mem::PageFaultError,
+    scheduler::{self, ScheduleReason},
     task::INVALID_PID,
 };
 use crate::util::msr::{msr_read, msr_write};
 use crate::{
     events::event_buffer::{EVENT_BUFFER, InputEvent, KeyState, Keys},
     gdt, hlt_loop,
-    memory::paging::{IdendtityAcpiHandler, MemoryMapFrameAllocator},
+    memory::{
+        paging::{IdendtityAcpiHandler, MemoryMapFrameAllocator},
+        stack, try_get_frame_allocator, try_get_user_mem_mgr,
+    },
     serial_print, serial_println,
     util::cpuinfo::{CpuFeatureFlags, get_cpu_info},
 };
@@ -24,7 +32,7 @@ use acpi::{
         },
     },
 };
-use core::arch::asm;
+use core::arch::{asm, naked_asm};
 use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use pc_keyboard::KeyCode;
@@ -48,6 +56,11 @@ const TIMER_TICK_FREQ_HZ: u64 = 2000 / TIMER_TICK_INTERVAL_MS;
 const TSC_CYCLES_PER_TICK: u64 = TSC_MOCK_FREQUENCY / TIMER_TICK_FREQ_HZ;
 const MSR_IA32_TSC_DEADLINE: u32 = 0x6E0;
 
+// APIC registers are mapped in the kernel half, so they stay reachable after switching to a
+// user address space
+const LOCAL_APIC_VIRT_ADDR: u64 = 0xFFFF_80A0_0000_0000;
+const IO_APIC_VIRT_ADDR: u64 = LOCAL_APIC_VIRT_ADDR + 0x1000;
+
 static SYSTEM_TICKS: AtomicU64 = AtomicU64::new(0);
 const TICK_DURATION_NS: u64 = 1_000_000_000 / TIMER_TICK_FREQ_HZ;
 const TICK_DURATION_US: u64 = 1_000_000 / TIMER_TICK_FREQ_HZ;
@@ -102,11 +115,12 @@ pub unsafe fn interrupt_over() {
 
 unsafe fn map_apic_mem(
     phys_address: u32,
+    virt_address: u64,
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> VirtAddr {
     let physical_address = PhysAddr::new(phys_address as u64);
-    let page = Page::containing_address(VirtAddr::new(physical_address.as_u64()));
+    let page = Page::containing_address(VirtAddr::new(virt_address));
     let frame = PhysFrame::containing_address(physical_address);
     let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
 
@@ -122,7 +136,7 @@ unsafe fn map_apic_mem(
             .flush();
     }
 
-    page.start_address()
+    page.start_address() + (physical_address.as_u64() & 0xFFF)
 }
 
 unsafe fn init_io_apic(
@@ -132,7 +146,8 @@ unsafe fn init_io_apic(
 ) {
     serial_println!("Mapping IO APIC");
 
-    let virt_addr = unsafe { map_apic_mem(phys_address, mapper, frame_allocator) };
+    let virt_addr =
+        unsafe { map_apic_mem(phys_address, IO_APIC_VIRT_ADDR, mapper, frame_allocator) };
 
     let io_apic_ptr = virt_addr.as_mut_ptr::<u32>();
 
@@ -151,7 +166,8 @@ unsafe fn init_local_apic(
 ) {
     serial_println!("Mapping Local APIC");
 
-    let virt_addr = unsafe { map_apic_mem(phys_address, mapper, frame_allocator) };
+    let virt_addr =
+        unsafe { map_apic_mem(phys_address, LOCAL_APIC_VIRT_ADDR, mapper, frame_allocator) };
 
     let local_apic_ptr = virt_addr.as_mut_ptr::<u32>();
 
@@ -433,27 +449,39 @@ lazy_static! {
         let mut idt = InterruptDescriptorTable::new();
 
         // CPU exceptions without error codes
-        idt.divide_error.set_handler_fn(divide_by_zero_handler);
+        unsafe {
+            idt.divide_error.set_handler_addr(entry_addr(divide_by_zero_entry));
+            idt.overflow.set_handler_addr(entry_addr(overflow_entry));
+            idt.bound_range_exceeded.set_handler_addr(entry_addr(bound_range_exceeded_entry));
+            idt.invalid_opcode.set_handler_addr(entry_addr(invalid_opcode_entry));
+            idt.device_not_available.set_handler_addr(entry_addr(device_not_available_entry));
+            idt.simd_floating_point.set_handler_addr(entry_addr(simd_floating_point_entry));
+        }
         idt.debug.set_handler_fn(debug_handler);
         idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.overflow.set_handler_fn(overflow_handler);
-        idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
-        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
-        idt.device_not_available.set_handler_fn(device_not_available_handler);
-        idt.alignment_check.set_handler_fn(alignment_check_handler);
 
         // CPU exceptions with error codes
-        idt.invalid_tss.set_handler_fn(invalid_tss_handler);
-        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
-        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
-        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
-        idt.page_fault.set_handler_fn(pagefault_handler);
-        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+        unsafe {
+            idt.alignment_check.set_handler_addr(entry_addr(alignment_check_entry));
+            idt.invalid_tss.set_handler_addr(entry_addr(invalid_tss_entry));
+            idt.segment_not_present.set_handler_addr(entry_addr(segment_not_present_entry));
+            idt.stack_segment_fault.set_handler_addr(entry_addr(stack_segment_fault_entry));
+            idt.general_protection_fault
+                .set_handler_addr(entry_addr(general_protection_fault_entry));
+            idt.page_fault.set_handler_addr(entry_addr(pagefault_entry));
+        }
 
         // Hardware interrupts
-        idt[InterruptIndex::Timer as u8].set_handler_fn(timer_interrupt_handler);
+        unsafe {
+            idt[InterruptIndex::Timer as u8]
+                .set_handler_addr(VirtAddr::new(timer_interrupt_entry as *const () as u64));
+        }
         idt[InterruptIndex::Keyboard as u8].set_handler_fn(keyboard_interrupt_handler);
+        unsafe {
+            idt[InterruptIndex::Yield as u8]
+                .set_handler_addr(VirtAddr::new(yield_interrupt_entry as *const () as u64));
+        }
 
         //unsafe {idt[0x80].set_handler_fn(syscall_int80_handler).set_stack_index(1)};
 
@@ -471,11 +499,6 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn divide_by_zero_handler(stack_frame: InterruptStackFrame) {
-    serial_println!("EXCEPTION: DIVIDE BY ZERO\n{:#?}", stack_frame);
-    hlt_loop();
-}
-
 extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
     serial_println!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
 }
@@ -485,102 +508,249 @@ extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
     hlt_loop();
 }
 
-extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
-    serial_println!("EXCEPTION: OVERFLOW\n{:#?}", stack_frame);
-    hlt_loop();
+/// A kernel stack running into its guard ends up here, the page fault it causes can not
+/// push its frame on the same stack
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    use x86_64::registers::control::Cr2;
+
+    let overflow =
+        report_kernel_stack_overflow(Cr2::read_raw(), stack_frame.stack_pointer.as_u64());
+    panic!(
+        "EXCEPTION: DOUBLE FAULT{}\n{:#?}",
+        if overflow { " (stack overflow)" } else { "" },
+        stack_frame
+    );
 }
 
-extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: InterruptStackFrame) {
-    serial_println!("EXCEPTION: BOUND RANGE EXCEEDED\n{:#?}", stack_frame);
-    hlt_loop();
+/// Names the task whose kernel stack ran into its guard, if the fault on `addr` is one. The
+/// process manager is only tried, the overflow may have happened while it was held.
+fn report_kernel_stack_overflow(addr: u64, rsp: u64) -> bool {
+    if !stack::is_stack_overflow(addr, rsp) {
+        return false;
+    }
+    let Some(pm) = PROCESS_MANAGER.try_lock() else {
+        serial_println!("stack overflow in the running task at {:#x}", addr);
+        return true;
+    };
+    match pm
+        .scheduler
+        .current_pid()
+        .and_then(|pid| pm.get_process(pid).ok())
+    {
+        Some(process) => serial_println!(
+            "stack overflow in {} (PID {}) at {:#x}",
+            process.name,
+            process.pid,
+            addr
+        ),
+        None => serial_println!("stack overflow in the kernel at {:#x}", addr),
+    }
+    true
+}
+
+/// Defines a naked interrupt entry which saves all general purpose registers on top of the CPU's
+/// interrupt frame and calls `$handler` with a pointer to the resulting `InterruptFrame`.
+/// The scheduler can swap the interrupted task for another one before `iretq`.
+macro_rules! interrupt_entry_stub {
+    ($name:ident, $handler:ident) => {
+        #[unsafe(naked)]
+        unsafe extern "C" fn $name() {
+            naked_asm!(
+                "push rax",
+                "push rbx",
+                "push rcx",
+                "push rdx",
+                "push rsi",
+                "push rdi",
+                "push rbp",
+                "push r8",
+                "push r9",
+                "push r10",
+                "push r11",
+                "push r12",
+                "push r13",
+                "push r14",
+                "push r15",
+
+                "cld",
+                "mov rdi, rsp",
+                "call {handler}",
+
+                "pop r15",
+                "pop r14",
+                "pop r13",
+                "pop r12",
+                "pop r11",
+                "pop r10",
+                "pop r9",
+                "pop r8",
+                "pop rbp",
+                "pop rdi",
+                "pop rsi",
+                "pop rdx",
+                "pop rcx",
+                "pop rbx",
+                "pop rax",
+
+                "iretq",
+
+                handler = sym $handler,
+            )
+        }
+    };
 }
 
-extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
-    serial_println!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
-    hlt_loop();
+/// Like `interrupt_entry_stub`, for exceptions which push an error code. The error code is
+/// swapped with rax, which leaves the registers laid out as an `InterruptFrame` again, and
+/// passed to `$handler` as its second argument.
+macro_rules! error_code_entry_stub {
+    ($name:ident, $handler:ident) => {
+        #[unsafe(naked)]
+        unsafe extern "C" fn $name() {
+            naked_asm!(
+                "xchg rax, [rsp]",
+                "push rbx",
+                "push rcx",
+                "push rdx",
+                "push rsi",
+                "push rdi",
+                "push rbp",
+                "push r8",
+                "push r9",
+                "push r10",
+                "push r11",
+                "push r12",
+                "push r13",
+                "push r14",
+                "push r15",
+
+                "cld",
+                "mov rdi, rsp",
+                "mov rsi, rax",
+                "call {handler}",
+
+                "pop r15",
+                "pop r14",
+                "pop r13",
+                "pop r12",
+                "pop r11",
+                "pop r10",
+                "pop r9",
+                "pop r8",
+                "pop rbp",
+                "pop rdi",
+                "pop rsi",
+                "pop rdx",
+                "pop rcx",
+                "pop rbx",
+                "pop rax",
+
+                "iretq",
+
+                handler = sym $handler,
+            )
+        }
+    };
 }
 
-extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
-    serial_println!("EXCEPTION: DEVICE NOT AVAILABLE\n{:#?}", stack_frame);
-    hlt_loop();
+interrupt_entry_stub!(timer_interrupt_entry, timer_interrupt_handler);
+interrupt_entry_stub!(yield_interrupt_entry, yield_interrupt_handler);
+interrupt_entry_stub!(divide_by_zero_entry, divide_by_zero_handler);
+interrupt_entry_stub!(overflow_entry, overflow_handler);
+interrupt_entry_stub!(bound_range_exceeded_entry, bound_range_exceeded_handler);
+interrupt_entry_stub!(invalid_opcode_entry, invalid_opcode_handler);
+interrupt_entry_stub!(device_not_available_entry, device_not_available_handler);
+interrupt_entry_stub!(simd_floating_point_entry, simd_floating_point_handler);
+error_code_entry_stub!(alignment_check_entry, alignment_check_handler);
+error_code_entry_stub!(invalid_tss_entry, invalid_tss_handler);
+error_code_entry_stub!(segment_not_present_entry, segment_not_present_handler);
+error_code_entry_stub!(stack_segment_fault_entry, stack_segment_fault_handler);
+error_code_entry_stub!(
+    general_protection_fault_entry,
+    general_protection_fault_handler
+);
+error_code_entry_stub!(pagefault_entry, pagefault_handler);
+
+fn entry_addr(entry: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(entry as *const () as u64)
 }
 
-extern "x86-interrupt" fn alignment_check_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
-    serial_println!(
-        "EXCEPTION: ALIGNMENT CHECK\nError Code: {}\n{:#?}",
-        error_code,
-        stack_frame
-    );
-    hlt_loop();
+extern "C" fn divide_by_zero_handler(frame: &mut InterruptFrame) {
+    cpu_exception(frame, Exception::DivideError);
 }
 
-extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
-    serial_println!(
-        "EXCEPTION: INVALID TSS\nError Code: {}\n{:#?}",
-        error_code,
-        stack_frame
-    );
-    hlt_loop();
+extern "C" fn overflow_handler(frame: &mut InterruptFrame) {
+    cpu_exception(frame, Exception::Overflow);
 }
 
-extern "x86-interrupt" fn segment_not_present_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
-    serial_println!(
-        "EXCEPTION: SEGMENT NOT PRESENT\nError Code: {}\n{:#?}",
-        error_code,
-        stack_frame
-    );
-    hlt_loop();
+extern "C" fn bound_range_exceeded_handler(frame: &mut InterruptFrame) {
+    cpu_exception(frame, Exception::BoundRangeExceeded);
 }
 
-extern "x86-interrupt" fn stack_segment_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
-    serial_println!(
-        "EXCEPTION: STACK SEGMENT FAULT\nError Code: {}\n{:#?}",
-        error_code,
-        stack_frame
-    );
-    hlt_loop();
+extern "C" fn invalid_opcode_handler(frame: &mut InterruptFrame) {
+    cpu_exception(frame, Exception::InvalidOpcode);
 }
 
-extern "x86-interrupt" fn general_protection_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
-    serial_println!(
-        "EXCEPTION: GENERAL PROTECTION FAULT\nError Code: {}\n{:#?}",
-        error_code,
-        stack_frame
-    );
-    hlt_loop();
+/// The FPU traps after a switch until its new owner claims it. From ring 3 the process
+/// manager is always free; from ring 0 it only has to be inside a `KernelFpu` guard.
+extern "C" fn device_not_available_handler(frame: &mut InterruptFrame) {
+    if (frame.is_user_mode() || fpu::in_kernel_fpu())
+        && let Some(mut pm) = PROCESS_MANAGER.try_lock()
+    {
+        pm.claim_fpu();
+        return;
+    }
+    cpu_exception(frame, Exception::DeviceNotAvailable);
 }
 
-extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
-    serial_println!("EXCEPTION: SIMD FLOATING POINT\n{:#?}", stack_frame);
-    hlt_loop();
+extern "C" fn simd_floating_point_handler(frame: &mut InterruptFrame) {
+    cpu_exception(frame, Exception::SimdFloatingPoint);
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+extern "C" fn alignment_check_handler(frame: &mut InterruptFrame, _error_code: u64) {
+    cpu_exception(frame, Exception::AlignmentCheck);
+}
+
+extern "C" fn invalid_tss_handler(frame: &mut InterruptFrame, error_code: u64) {
+    cpu_exception(frame, Exception::InvalidTss(error_code));
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "C" fn segment_not_present_handler(frame: &mut InterruptFrame, error_code: u64) {
+    cpu_exception(frame, Exception::SegmentNotPresent(error_code));
+}
+
+extern "C" fn stack_segment_fault_handler(frame: &mut InterruptFrame, error_code: u64) {
+    cpu_exception(frame, Exception::StackSegmentFault(error_code));
+}
+
+extern "C" fn general_protection_fault_handler(frame: &mut InterruptFrame, error_code: u64) {
+    cpu_exception(frame, Exception::GeneralProtection(error_code));
+}
+
+/// An exception in ring 0 is a kernel bug and stops the machine. One from ring 3 is the fault
+/// of the running process only, see `user_fault`.
+fn cpu_exception(frame: &mut InterruptFrame, exception: Exception) {
+    if frame.is_user_mode() {
+        user_fault(frame, exception);
+        return;
+    }
+
+    serial_println!("EXCEPTION: {}\n{:#?}", exception, frame);
+    hlt_loop();
+}
+
+extern "C" fn timer_interrupt_handler(frame: &mut InterruptFrame) {
     if TIMER_DEBUG_PRINT {
         serial_println!("*");
     };
 
     SYSTEM_TICKS.fetch_add(1, Ordering::Relaxed);
 
+    scheduler::on_interrupt(frame, ScheduleReason::Tick);
+
     unsafe {
         // re-arm the timer for the next tick
         let next_deadline = tsc_read() + TSC_CYCLES_PER_TICK;
@@ -590,6 +760,10 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
     }
 }
 
+extern "C" fn yield_interrupt_handler(frame: &mut InterruptFrame) {
+    scheduler::on_interrupt(frame, ScheduleReason::Yield);
+}
+
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
     use pc_keyboard::{
         DecodedKey, HandleControl, KeyState as PcKeyState, Keyboard, ScancodeSet1, layouts,
@@ -602,7 +776,7 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
             Mutex::new(Keyboard::new(
                 ScancodeSet1::new(),
                 layouts::Us104Key,
-                HandleControl::Ignore
+                HandleControl::MapLettersToUnicode
             ));
     }
     let mut keyboard = KEYBOARD.lock();
@@ -639,24 +813,115 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     }
 }
 
-extern "x86-interrupt" fn pagefault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
+extern "C" fn pagefault_handler(frame: &mut InterruptFrame, error_code: u64) {
     use x86_64::registers::control::Cr2;
 
-    serial_println!("EXCEPTION: PAGE FAULT");
-    serial_println!("Accessed Address: {:?}", Cr2::read());
-    serial_println!("Error Code: {:?}", error_code);
-    serial_println!("{:#?}", stack_frame);
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+    let addr = Cr2::read_raw();
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        user_pagefault_handler(frame, addr, error_code);
+        return;
+    }
+
+    report_kernel_stack_overflow(addr, frame.rsp);
+    serial_println!(
+        "EXCEPTION: {}\n{:#?}",
+        Exception::PageFault { addr, error_code },
+        frame
+    );
     hlt_loop();
 }
 
+/// Lazily allocated pages get backed here, any other fault is the process's own
+fn user_pagefault_handler(frame: &mut InterruptFrame, addr: u64, error_code: PageFaultErrorCode) {
+    let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+    let result = {
+        let mut pm = PROCESS_MANAGER.lock();
+        let Some(pid) = pm.scheduler.current_pid() else {
+            panic!("page fault from ring 3 without a current process");
+        };
+        // a task preempted in a syscall might hold the memory locks, the process runs
+        // the faulting instruction again once it is switched back in
+        let (Some(address_space_manager), Some(mut frame_allocator)) =
+            (try_get_user_mem_mgr(), try_get_frame_allocator())
+        else {
+            pm.schedule(frame, ScheduleReason::Yield);
+            return;
+        };
+        let result = match VirtAddr::try_new(addr) {
+            Ok(addr) => pm.handle_page_fault(
+                pid,
+                addr,
+                write,
+                &address_space_manager,
+                &mut frame_allocator,
+            ),
+            Err(_) => Err(PageFaultError::NotInRegion(VirtAddr::zero())),
+        };
+        if let Err(PageFaultError::StackOverflow(_)) = result
+            && let Ok(process) = pm.get_process(pid)
+        {
+            serial_println!("stack overflow in {} (PID {})", process.name, pid);
+        }
+        result
+    };
+
+    if let Err(e) = result {
+        serial_println!(
+            "Segmentation fault at {:#x} ({:?}): {:?}",
+            addr,
+            error_code,
+            e
+        );
+        user_fault(frame, Exception::PageFault { addr, error_code });
+    }
+}
+
+/// Raises the signal for an exception the running process caused in ring 3. The process
+/// either returns through `frame` into its handler, or only it is terminated and `frame` is
+/// switched over to the next task.
+fn user_fault(frame: &mut InterruptFrame, exception: Exception) {
+    let mut pm = PROCESS_MANAGER.lock();
+    let Some(pid) = pm.scheduler.current_pid() else {
+        panic!("exception from ring 3 without a current process");
+    };
+    if let Ok(process) = pm.get_process_mut(pid) {
+        process.signals.force(exception.signal());
+    }
+
+    let fatal = {
+        // with the memory locks held by a preempted task the signal stays pending, it is
+        // delivered once the process is switched back in
+        let (Some(address_space_manager), Some(mut frame_allocator)) =
+            (try_get_user_mem_mgr(), try_get_frame_allocator())
+        else {
+            pm.schedule(frame, ScheduleReason::Yield);
+            return;
+        };
+        pm.deliver_signal(frame, &address_space_manager, &mut frame_allocator)
+    };
+    let Some(signal) = fatal else {
+        return;
+    };
+
+    let fault = Fault {
+        exception,
+        rip: frame.rip,
+    };
+    serial_println!("PID {} killed by signal {}: {}", pid, signal, fault);
+    if let Err(e) = pm.terminate_for_fault(pid, fault, signal) {
+        serial_println!("user_fault: failed to terminate PID {}: {:?}", pid, e);
+    }
+    pm.schedule(frame, ScheduleReason::Yield);
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = 32,
     Keyboard,
+    // software interrupt raised by the kernel to give up the CPU
+    Yield = 0x81,
 }
 
 #[allow(non_camel_case_types)]
@@ -27,6 +27,7 @@ extern crate linkme;
 pub fn init_globals() {
     gdt::init();
     interrupts::init_idt();
+    process::fpu::init();
 }
 
 use core::sync::atomic::AtomicBool;
@@ -34,11 +34,12 @@ fn rust_panic(info: &core::panic::PanicInfo) -> ! {
     exit_qemu(QemuExitCode::Failed);
 }
 
+/// Arche redraws the screen at about 60 frames per second
+const FRAME_INTERVAL_NS: u64 = 1_000_000_000 / 60;
+
 fn main() -> ! {
     serial_println!("Welcome to BigOS!");
 
-    kernel::process::syscall::init_syscall_stack();
-
     let mut framebuffer_target = kernel::graphics::framebuffer::get_framebuffer();
     let fb = &mut *framebuffer_target;
 
@@ -83,5 +84,10 @@ fn main() -> ! {
             dynamic_renderer.update(&window2_buffer, dt as f32 / 1_000_000.0);
         }
         compositor.compose(&mut framebuffer_target);
+        kernel::process::process_manager::PROCESS_MANAGER
+            .lock()
+            .cleanup_dead();
+        // user processes run until the next frame is due
+        kernel::process::scheduler::sleep_until(time_start + FRAME_INTERVAL_NS);
     }
 }
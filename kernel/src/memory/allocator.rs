@@ -1,14 +1,26 @@
 extern crate alloc;
+use crate::memory::{
+    heap_debug::{
+        ALLOCATED_POISON, AllocationRecord, CALL_SITE_DEPTH, FREED_POISON, HeapDebug, REDZONE_BYTE,
+        REDZONE_BYTES, TRACKED_ALLOCATIONS_MAX, call_site,
+    },
+    paging::{MemoryMapFrameAllocator, PAGE_SIZE},
+    try_get_frame_allocator,
+    usermem::UserMemoryManager,
+};
 use crate::serial_println;
 use alloc::alloc::{GlobalAlloc, Layout};
+use alloc::vec::Vec;
 use core::{
     mem::{align_of, size_of},
     ptr::NonNull,
 };
 use x86_64::{
-    VirtAddr,
+    PhysAddr, VirtAddr,
+    instructions::interrupts::without_interrupts,
     structures::paging::{
-        FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, mapper::MapToError,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB, mapper::MapToError,
     },
 };
 
@@ -26,7 +38,18 @@ pub static ALLOCATOR: MutexWrapper<FixedSizeBlockAllocator> =
     MutexWrapper::new(FixedSizeBlockAllocator::new());
 
 pub const HEAP_POINTER: usize = 0xFFFF_8080_0000_0000;
+/// Mapped up front by `init_heap`, the heap grows past it on demand
 pub const HEAP_SIZE_BYTES: usize = 16 * 1024 * 1024; // 16 MB
+/// Ceiling the heap grows up to. It has to stay within the PML4 entry `init_heap` creates,
+/// user address spaces only copy the kernel's PML4 entries when they are created.
+pub const HEAP_MAX_SIZE_BYTES: usize = 1024 * 1024 * 1024; // 1 GB
+/// Least the heap grows by at once, so small allocations don't map a page each
+const HEAP_GROW_STEP_BYTES: usize = 1024 * 1024; // 1 MB
+/// Frames kept aside for growing: a step's worth and the page tables it may need
+const HEAP_RESERVED_FRAMES: usize = HEAP_GROW_STEP_BYTES / PAGE_SIZE + 2;
+
+const PML4_ENTRY_SPAN: usize = 512 * 1024 * 1024 * 1024;
+const _: () = assert!(HEAP_POINTER % PML4_ENTRY_SPAN + HEAP_MAX_SIZE_BYTES <= PML4_ENTRY_SPAN);
 
 // Wrapper around spin::Mutex to implement GlobalAlloc on a foreign type
 pub struct MutexWrapper<T> {
@@ -56,6 +79,43 @@ struct AllocatorListNode {
 pub struct FixedSizeBlockAllocator {
     lists: [Option<&'static mut AllocatorListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    /// Blocks of each size class handed out
+    allocated_blocks: [usize; BLOCK_SIZES.len()],
+    /// Blocks of each size class waiting in its list
+    free_blocks: [usize; BLOCK_SIZES.len()],
+    large_allocations: usize,
+    peak_heap_used: usize,
+    debug: HeapDebug,
+    /// Set by `init_heap_growth`, without it the heap keeps its initial size
+    growth: Option<HeapGrowth>,
+}
+
+/// Blocks of one size class
+#[derive(Debug, Clone, Copy)]
+pub struct SizeClassStats {
+    pub block_size: usize,
+    pub allocated: usize,
+    pub free: usize,
+}
+
+/// Snapshot of the kernel heap, see `heap_stats`
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub size_classes: [SizeClassStats; BLOCK_SIZES.len()],
+    /// Allocations too big for a size class, the fallback heap serves them directly
+    pub large_allocations: usize,
+    /// Bytes the fallback heap spans, it grows up to `HEAP_MAX_SIZE_BYTES`
+    pub heap_size: usize,
+    /// Bytes of the fallback heap in use, every block of the size classes included
+    pub heap_used: usize,
+    pub peak_heap_used: usize,
+    pub debug_enabled: bool,
+    /// Allocations made in debug mode which are still live
+    pub tracked_allocations: usize,
+    /// Allocations made in debug mode while the tracking table was full
+    pub untracked_allocations: usize,
+    /// Tracked allocations freed with an overwritten redzone or a wrong size
+    pub corruptions: usize,
 }
 
 impl Default for FixedSizeBlockAllocator {
@@ -114,6 +174,36 @@ impl FixedSizeBlockAllocator {
         Self {
             lists: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            allocated_blocks: [0; BLOCK_SIZES.len()],
+            free_blocks: [0; BLOCK_SIZES.len()],
+            large_allocations: 0,
+            peak_heap_used: 0,
+            debug: HeapDebug::new(),
+            growth: None,
+        }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let mut size_classes = [SizeClassStats {
+            block_size: 0,
+            allocated: 0,
+            free: 0,
+        }; BLOCK_SIZES.len()];
+        for (index, class) in size_classes.iter_mut().enumerate() {
+            class.block_size = BLOCK_SIZES[index];
+            class.allocated = self.allocated_blocks[index];
+            class.free = self.free_blocks[index];
+        }
+        HeapStats {
+            size_classes,
+            large_allocations: self.large_allocations,
+            heap_size: self.fallback_allocator.size(),
+            heap_used: self.fallback_allocator.used(),
+            peak_heap_used: self.peak_heap_used,
+            debug_enabled: self.debug.enabled(),
+            tracked_allocations: self.debug.live(),
+            untracked_allocations: self.debug.untracked,
+            corruptions: self.debug.corruptions,
         }
     }
 
@@ -133,9 +223,14 @@ impl FixedSizeBlockAllocator {
     }
 
     fn allocate_with_fallback_allocator(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
+        let mut result = self.fallback_allocator.allocate_first_fit(layout);
+        if result.is_err() && self.grow(layout) {
+            result = self.fallback_allocator.allocate_first_fit(layout);
+        }
+        match result {
             Ok(ptr) => {
                 alloc_debug!("[FALLBACK ALLOC] Success, ptr: {:p}", ptr.as_ptr());
+                self.peak_heap_used = self.peak_heap_used.max(self.fallback_allocator.used());
                 ptr.as_ptr()
             }
             Err(e) => {
@@ -144,6 +239,174 @@ impl FixedSizeBlockAllocator {
             }
         }
     }
+
+    /// Serves `layout` from the fallback heap with a redzone on each side, poisoned, and records
+    /// where it was allocated from
+    fn allocate_tracked(&mut self, layout: Layout, call_site: [u64; CALL_SITE_DEPTH]) -> *mut u8 {
+        let front = REDZONE_BYTES.max(layout.align());
+        let Ok(full_layout) = Layout::from_size_align(front + layout.size() + REDZONE_BYTES, front)
+        else {
+            return core::ptr::null_mut();
+        };
+        let base = self.allocate_with_fallback_allocator(full_layout);
+        if base.is_null() {
+            return base;
+        }
+        let ptr = unsafe {
+            base.write_bytes(REDZONE_BYTE, front);
+            let ptr = base.add(front);
+            ptr.write_bytes(ALLOCATED_POISON, layout.size());
+            ptr.add(layout.size())
+                .write_bytes(REDZONE_BYTE, REDZONE_BYTES);
+            ptr
+        };
+        self.debug
+            .track(ptr as usize, layout.size(), layout.align(), call_site);
+        ptr
+    }
+
+    /// Checks the redzones of a tracked allocation, reporting it over serial when they were
+    /// overwritten, then poisons and frees it
+    fn deallocate_tracked(&mut self, record: AllocationRecord, layout: Layout) {
+        let (front, back) = unsafe { record.check_redzones() };
+        if front || back || record.size != layout.size() {
+            self.debug.corruptions += 1;
+            serial_println!(
+                "heap: corrupted allocation {:#x} (#{}, {} bytes, freed as {}), front redzone {}, back redzone {}, allocated from {:x?}",
+                record.ptr,
+                record.sequence,
+                record.size,
+                layout.size(),
+                if front { "overwritten" } else { "intact" },
+                if back { "overwritten" } else { "intact" },
+                record.call_site
+            );
+        }
+        let full_layout =
+            Layout::from_size_align(record.full_size(), record.front_redzone()).unwrap();
+        unsafe {
+            (record.ptr as *mut u8).write_bytes(FREED_POISON, record.size);
+            let base = NonNull::new_unchecked(record.base() as *mut u8);
+            self.fallback_allocator.deallocate(base, full_layout);
+        }
+    }
+
+    /// Maps frames from the reserve right above the heap so `layout` fits, without going past
+    /// `HEAP_MAX_SIZE_BYTES`. This runs with the allocator locked and interrupts off, so the
+    /// frame allocator is only tried to top up the reserve: whoever holds it may be the one
+    /// allocating. Returns whether the heap grew.
+    fn grow(&mut self, layout: Layout) -> bool {
+        let top = self.fallback_allocator.top();
+        let Some(growth) = self.growth.as_mut() else {
+            return false;
+        };
+        let wanted = (layout.size() + layout.align())
+            .max(HEAP_GROW_STEP_BYTES)
+            .next_multiple_of(PAGE_SIZE);
+        let grow_by = wanted.min((HEAP_POINTER + HEAP_MAX_SIZE_BYTES).saturating_sub(top));
+        if grow_by == 0 {
+            return false;
+        }
+
+        let mut frame_allocator = try_get_frame_allocator();
+        if let Some(frame_allocator) = frame_allocator.as_mut() {
+            growth.refill(frame_allocator);
+        }
+        let mapped = growth.map(top, grow_by);
+        if let Some(frame_allocator) = frame_allocator.as_mut() {
+            growth.refill(frame_allocator);
+        }
+        if mapped == 0 {
+            return false;
+        }
+        unsafe { self.fallback_allocator.extend(mapped) };
+        alloc_debug!(
+            "[GROW] Heap grew by {:#x} bytes to {:#x}",
+            mapped,
+            self.fallback_allocator.size()
+        );
+        true
+    }
+}
+
+/// What the heap grows with: the kernel's page table, every address space shares the tables
+/// below its PML4 entry, and frames set aside so growing never waits for the frame allocator
+struct HeapGrowth {
+    kernel_page_table_phys: PhysAddr,
+    phys_offset: u64,
+    frames: [Option<PhysFrame>; HEAP_RESERVED_FRAMES],
+    reserved: usize,
+}
+
+impl HeapGrowth {
+    /// Tops the reserve up from `frame_allocator`
+    fn refill(&mut self, frame_allocator: &mut MemoryMapFrameAllocator) {
+        while self.reserved < HEAP_RESERVED_FRAMES {
+            let Some(frame) = frame_allocator.allocate_frame() else {
+                break;
+            };
+            self.frames[self.reserved] = Some(frame);
+            self.reserved += 1;
+        }
+    }
+
+    /// Maps reserved frames for up to `size_bytes` at `start`. Returns the bytes mapped before
+    /// the reserve ran out.
+    fn map(&mut self, start: usize, size_bytes: usize) -> usize {
+        let pml4_virt = self.kernel_page_table_phys.as_u64() + self.phys_offset;
+        let pml4_table = unsafe { &mut *(pml4_virt as *mut PageTable) };
+        let mut mapper =
+            unsafe { OffsetPageTable::new(pml4_table, VirtAddr::new(self.phys_offset)) };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+        let mut mapped = 0;
+        while mapped < size_bytes {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new((start + mapped) as u64));
+            let Some(frame) = self.allocate_frame() else {
+                break;
+            };
+            match unsafe { mapper.map_to(page, frame, flags, self) } {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    unsafe { self.deallocate_frame(frame) };
+                    break;
+                }
+            }
+            mapped += PAGE_SIZE;
+        }
+        mapped
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for HeapGrowth {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.reserved = self.reserved.checked_sub(1)?;
+        self.frames[self.reserved].take()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for HeapGrowth {
+    /// Only takes back frames the reserve handed out
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.frames[self.reserved] = Some(frame);
+        self.reserved += 1;
+    }
+}
+
+/// Lets the heap grow past `HEAP_SIZE_BYTES`, filling its frame reserve from `frame_allocator`.
+/// Called once the heap is set up, before the frame allocator becomes a memory global.
+pub fn init_heap_growth(
+    user_mem_mgr: &UserMemoryManager,
+    frame_allocator: &mut MemoryMapFrameAllocator,
+) {
+    let mut growth = HeapGrowth {
+        kernel_page_table_phys: user_mem_mgr.kernel_page_table_phys,
+        phys_offset: user_mem_mgr.phys_offset,
+        frames: [None; HEAP_RESERVED_FRAMES],
+        reserved: 0,
+    };
+    growth.refill(frame_allocator);
+    without_interrupts(|| ALLOCATOR.lock().growth = Some(growth));
 }
 
 fn get_block_index(layout: &Layout) -> Option<usize> {
@@ -151,8 +414,20 @@ fn get_block_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= size)
 }
 
+// The lock is taken with interrupts disabled: the kernel task can be preempted at any point and the
+// task that runs next may need to allocate too.
 unsafe impl GlobalAlloc for MutexWrapper<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| unsafe { self.alloc_locked(layout) })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| unsafe { self.dealloc_locked(ptr, layout) })
+    }
+}
+
+impl MutexWrapper<FixedSizeBlockAllocator> {
+    unsafe fn alloc_locked(&self, layout: Layout) -> *mut u8 {
         alloc_debug!(
             "[ALLOC] Request: size={}, align={}",
             layout.size(),
@@ -162,6 +437,13 @@ unsafe impl GlobalAlloc for MutexWrapper<FixedSizeBlockAllocator> {
         let mut allocator = self.lock();
         alloc_debug!("[ALLOC] Lock acquired");
 
+        if allocator.debug.enabled() {
+            if !allocator.debug.is_full() {
+                return allocator.allocate_tracked(layout, call_site());
+            }
+            allocator.debug.untracked += 1;
+        }
+
         if let Some(index) = get_block_index(&layout) {
             alloc_debug!(
                 "[ALLOC] Using block index={}, size={}",
@@ -173,6 +455,8 @@ unsafe impl GlobalAlloc for MutexWrapper<FixedSizeBlockAllocator> {
                     // reuse an existing block
                     alloc_debug!("[ALLOC] Reusing existing block");
                     allocator.lists[index] = node.next.take();
+                    allocator.free_blocks[index] -= 1;
+                    allocator.allocated_blocks[index] += 1;
 
                     let ptr = node as *mut AllocatorListNode as *mut u8;
                     alloc_debug!("[ALLOC] Returning ptr: {:p}", ptr);
@@ -201,6 +485,9 @@ unsafe impl GlobalAlloc for MutexWrapper<FixedSizeBlockAllocator> {
                         "[ALLOC] allocate_with_fallback_allocator returned: {:p}",
                         res
                     );
+                    if !res.is_null() {
+                        allocator.allocated_blocks[index] += 1;
+                    }
 
                     res
                 }
@@ -211,15 +498,30 @@ unsafe impl GlobalAlloc for MutexWrapper<FixedSizeBlockAllocator> {
             );
             let res = allocator.allocate_with_fallback_allocator(layout);
             alloc_debug!("[ALLOC] Fallback allocator returned: {:p}", res);
+            if !res.is_null() {
+                allocator.large_allocations += 1;
+            }
             res
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe fn dealloc_locked(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
 
+        // only look the block up while some allocation is tracked, freeing stays O(1) otherwise
+        if allocator.debug.live() > 0
+            && let Some(record) = allocator.debug.untrack(ptr as usize)
+        {
+            allocator.deallocate_tracked(record, layout);
+            return;
+        }
+        let poison = allocator.debug.enabled();
+
         match get_block_index(&layout) {
             Some(index) => {
+                if poison {
+                    unsafe { ptr.write_bytes(FREED_POISON, BLOCK_SIZES[index]) };
+                }
                 let new_node = AllocatorListNode {
                     next: allocator.lists[index].take(),
                 };
@@ -232,14 +534,68 @@ unsafe impl GlobalAlloc for MutexWrapper<FixedSizeBlockAllocator> {
                     new_node_ptr.write(new_node);
                     allocator.lists[index] = Some(&mut *new_node_ptr);
                 }
+                allocator.allocated_blocks[index] -= 1;
+                allocator.free_blocks[index] += 1;
             }
 
             None => {
+                if poison {
+                    unsafe { ptr.write_bytes(FREED_POISON, layout.size()) };
+                }
                 let ptr = NonNull::new(ptr).unwrap();
                 unsafe {
                     allocator.fallback_allocator.deallocate(ptr, layout);
                 }
+                allocator.large_allocations -= 1;
             }
         }
     }
 }
+
+pub fn heap_stats() -> HeapStats {
+    without_interrupts(|| ALLOCATOR.lock().stats())
+}
+
+/// Switches debug mode on or off. While it is on, every allocation gets redzones, freed memory
+/// is poisoned and live allocations are recorded with their call sites for `heap_leaks`.
+pub fn set_heap_debug(enabled: bool) {
+    without_interrupts(|| ALLOCATOR.lock().debug.set_enabled(enabled));
+    serial_println!(
+        "heap: debug mode {}",
+        if enabled { "enabled" } else { "disabled" }
+    );
+}
+
+/// Allocations made in debug mode which are still live, oldest first
+pub fn heap_leaks() -> Vec<AllocationRecord> {
+    // allocated up front, the allocator is locked while it is filled
+    let mut leaks = Vec::with_capacity(TRACKED_ALLOCATIONS_MAX);
+    let own_buffer = leaks.as_ptr() as usize;
+    without_interrupts(|| {
+        let allocator = ALLOCATOR.lock();
+        leaks.extend(
+            allocator
+                .debug
+                .records()
+                .filter(|record| record.ptr != own_buffer)
+                .copied(),
+        );
+    });
+    leaks.sort_unstable_by_key(|record| record.sequence);
+    leaks
+}
+
+/// Writes every live allocation made in debug mode to serial
+pub fn dump_heap_leaks() {
+    let leaks = heap_leaks();
+    serial_println!("heap: {} live tracked allocations", leaks.len());
+    for record in &leaks {
+        serial_println!(
+            "heap: #{} {:#x}, {} bytes, allocated from {:x?}",
+            record.sequence,
+            record.ptr,
+            record.size,
+            record.call_site
+        );
+    }
+}
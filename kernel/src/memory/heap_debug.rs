@@ -0,0 +1,176 @@
+use core::arch::asm;
+
+/// Allocations tracked at once in debug mode, the table lives in the allocator itself so
+/// tracking never allocates. Allocations made while it is full are served untracked.
+pub const TRACKED_ALLOCATIONS_MAX: usize = 1024;
+/// Return addresses recorded per allocation, innermost first. The first few are the
+/// allocator's own frames.
+pub const CALL_SITE_DEPTH: usize = 6;
+/// Bytes guarding each side of a tracked allocation, at least this is added in front so the
+/// allocation keeps its alignment
+pub const REDZONE_BYTES: usize = 16;
+pub const REDZONE_BYTE: u8 = 0xFD;
+/// Fills tracked allocations when they are handed out, so reads of uninitialized memory
+/// stand out
+pub const ALLOCATED_POISON: u8 = 0xCD;
+/// Fills memory once it is freed, so use after free stands out
+pub const FREED_POISON: u8 = 0xDD;
+
+const KERNEL_HALF_START: u64 = 0xFFFF_8000_0000_0000;
+
+/// Return addresses of the innermost frames, found by walking the frame pointer chain which
+/// the kernel is built to keep. The walk stops at the first frame outside the kernel half,
+/// like the user frame a syscall came from.
+#[inline(always)]
+pub fn call_site() -> [u64; CALL_SITE_DEPTH] {
+    let mut callers = [0; CALL_SITE_DEPTH];
+    let mut rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    for caller in callers.iter_mut() {
+        if rbp < KERNEL_HALF_START || !rbp.is_multiple_of(8) {
+            break;
+        }
+        let frame = rbp as *const u64;
+        let (next, return_address) = unsafe { (frame.read(), frame.add(1).read()) };
+        *caller = return_address;
+        // callers' frames lie above, anything else is not a frame pointer
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    callers
+}
+
+/// Allocation made in debug mode, still live
+#[derive(Debug, Clone, Copy)]
+pub struct AllocationRecord {
+    /// Address handed out, the front redzone lies right below
+    pub ptr: usize,
+    pub size: usize,
+    pub align: usize,
+    /// Order the allocations were made in, counted from when debug mode was first enabled
+    pub sequence: u64,
+    pub call_site: [u64; CALL_SITE_DEPTH],
+}
+
+impl AllocationRecord {
+    /// Bytes guarding the front, a multiple of the alignment
+    pub fn front_redzone(&self) -> usize {
+        REDZONE_BYTES.max(self.align)
+    }
+
+    /// Start of the block the fallback allocator handed out
+    pub fn base(&self) -> usize {
+        self.ptr - self.front_redzone()
+    }
+
+    pub fn full_size(&self) -> usize {
+        self.front_redzone() + self.size + REDZONE_BYTES
+    }
+
+    /// Checks both redzones, returns which of them were overwritten
+    ///
+    /// # Safety
+    ///
+    /// The allocation must still be live.
+    pub unsafe fn check_redzones(&self) -> (bool, bool) {
+        let intact = |start: usize, len: usize| {
+            let zone = unsafe { core::slice::from_raw_parts(start as *const u8, len) };
+            zone.iter().all(|byte| *byte == REDZONE_BYTE)
+        };
+        (
+            !intact(self.base(), self.front_redzone()),
+            !intact(self.ptr + self.size, REDZONE_BYTES),
+        )
+    }
+}
+
+/// Debug mode state of the kernel heap: whether it is on and the allocations made while it
+/// was. Tracked allocations are recognized on free even after debug mode is switched off.
+pub struct HeapDebug {
+    enabled: bool,
+    records: [Option<AllocationRecord>; TRACKED_ALLOCATIONS_MAX],
+    live: usize,
+    next_sequence: u64,
+    /// Tracked allocations freed with an overwritten redzone
+    pub corruptions: usize,
+    /// Allocations made in debug mode while the table was full
+    pub untracked: usize,
+}
+
+impl Default for HeapDebug {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeapDebug {
+    pub const fn new() -> Self {
+        Self {
+            enabled: false,
+            records: [None; TRACKED_ALLOCATIONS_MAX],
+            live: 0,
+            next_sequence: 0,
+            corruptions: 0,
+            untracked: 0,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Number of live tracked allocations
+    pub fn live(&self) -> usize {
+        self.live
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.live == TRACKED_ALLOCATIONS_MAX
+    }
+
+    /// Records an allocation, there has to be room for it
+    pub fn track(
+        &mut self,
+        ptr: usize,
+        size: usize,
+        align: usize,
+        call_site: [u64; CALL_SITE_DEPTH],
+    ) {
+        let slot = self
+            .records
+            .iter_mut()
+            .find(|record| record.is_none())
+            .expect("heap debug: tracking table is full");
+        *slot = Some(AllocationRecord {
+            ptr,
+            size,
+            align,
+            sequence: self.next_sequence,
+            call_site,
+        });
+        self.next_sequence += 1;
+        self.live += 1;
+    }
+
+    /// Takes out the record of the allocation at `ptr`, if it was tracked. Scans the table,
+    /// callers skip it while `live` is 0.
+    pub fn untrack(&mut self, ptr: usize) -> Option<AllocationRecord> {
+        let record = self
+            .records
+            .iter_mut()
+            .find(|record| record.is_some_and(|record| record.ptr == ptr))?
+            .take();
+        self.live -= 1;
+        record
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &AllocationRecord> {
+        self.records.iter().flatten()
+    }
+}
@@ -1,5 +1,7 @@
 pub mod allocator;
+pub mod heap_debug;
 pub mod paging;
+pub mod stack;
 pub mod usermem;
 
 use crate::memory::paging::MemoryMapFrameAllocator;
@@ -10,11 +12,14 @@ pub static FRAME_ALLOCATOR: Once<Mutex<MemoryMapFrameAllocator>> = Once::new();
 pub static USER_MEMORY_MANAGER: Once<Mutex<UserMemoryManager>> = Once::new();
 
 pub fn init_memory_globals(
-    frame_allocator: MemoryMapFrameAllocator,
+    mut frame_allocator: MemoryMapFrameAllocator,
     user_mem_manager: UserMemoryManager,
 ) {
+    allocator::init_heap_growth(&user_mem_manager, &mut frame_allocator);
     FRAME_ALLOCATOR.call_once(|| Mutex::new(frame_allocator));
     USER_MEMORY_MANAGER.call_once(|| Mutex::new(user_mem_manager));
+    // before any user address space exists, they only copy the kernel's PML4 entries once
+    crate::gdt::install_guarded_stacks();
 }
 
 pub fn get_frame_allocator() -> MutexGuard<'static, MemoryMapFrameAllocator> {
@@ -24,3 +29,13 @@ pub fn get_frame_allocator() -> MutexGuard<'static, MemoryMapFrameAllocator> {
 pub fn get_user_mem_mgr() -> MutexGuard<'static, UserMemoryManager> {
     USER_MEMORY_MANAGER.get().unwrap().lock()
 }
+
+/// For interrupt handlers, which can not wait for a task they interrupted to unlock it
+pub fn try_get_frame_allocator() -> Option<MutexGuard<'static, MemoryMapFrameAllocator>> {
+    FRAME_ALLOCATOR.get()?.try_lock()
+}
+
+/// For interrupt handlers, which can not wait for a task they interrupted to unlock it
+pub fn try_get_user_mem_mgr() -> Option<MutexGuard<'static, UserMemoryManager>> {
+    USER_MEMORY_MANAGER.get()?.try_lock()
+}
@@ -3,8 +3,13 @@ use acpi::{Handler, PhysicalMapping};
 use limine::memmap::{Entry, MEMMAP_USABLE};
 use x86_64::{
     PhysAddr, VirtAddr,
-    registers::control::Cr3,
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    registers::{
+        control::Cr3,
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PhysFrame, Size2MiB, Size4KiB,
+    },
 };
 
 pub const PAGE_SIZE: usize = 4096; // 4 KiB
@@ -37,11 +42,37 @@ pub unsafe fn init_offset_page_table(hhdm_offset: u64) -> OffsetPageTable<'stati
     }
 }
 
-/// iterates through USABLE memory regions and hands out 4KB physical frames on demand
+/// Lets pages be mapped NO_EXECUTE, without it the bit is reserved and faults
+///
+/// # Safety
+///
+/// The CPU must support NX, see `CpuFeatureFlags::NX`.
+pub unsafe fn enable_no_execute() {
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+    }
+}
+
+pub fn no_execute_enabled() -> bool {
+    Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE)
+}
+
+/// Frames of 2 MiB, used for huge pages
+pub const FRAMES_PER_HUGE_PAGE: usize = 512;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// Tracks every physical frame of the USABLE memory regions in a bitmap, a set bit means the
+/// frame is in use. The bitmap and the share counts live in the first usable region big enough
+/// to hold them.
 pub struct MemoryMapFrameAllocator {
-    memory_map: &'static [&'static Entry],
-    curr_region_index: usize,
-    frame_offset_in_region: u64,
+    bitmap: &'static mut [u64],
+    /// How many owners a frame has besides the one which allocated it, a shared frame is only
+    /// freed once every owner gave it back
+    share_counts: &'static mut [u32],
+    /// No free frame lives below this index
+    next_free_hint: usize,
+    total_frames: usize,
+    free_frames: usize,
 }
 
 pub const fn align_up(x: u64, align: u64) -> u64 {
@@ -52,64 +83,229 @@ pub const fn align_down(x: u64, align: u64) -> u64 {
     x & !(align - 1)
 }
 
+fn usable_frame_ranges(
+    memory_map: &'static [&'static Entry],
+) -> impl Iterator<Item = core::ops::Range<usize>> {
+    let page_size = PAGE_SIZE as u64;
+    memory_map
+        .iter()
+        .filter(|r| r.type_ == MEMMAP_USABLE)
+        .map(move |r| {
+            let start = align_up(r.base, page_size) / page_size;
+            let end = align_down(r.base + r.length, page_size) / page_size;
+            start as usize..end as usize
+        })
+        .filter(|frames| !frames.is_empty())
+}
+
 impl MemoryMapFrameAllocator {
     /// # Safety
     ///
     /// `memory_map` must be valid for the `'static` lifetime and accurately describe all usable
     /// physical memory regions. Calling this with an incorrect map may cause the allocator to hand
-    /// out frames that overlap with firmware or kernel data.
-    pub unsafe fn init(memory_map: &'static [&'static Entry]) -> Self {
+    /// out frames that overlap with firmware or kernel data. All physical memory must be mapped
+    /// at `phys_offset`.
+    pub unsafe fn init(memory_map: &'static [&'static Entry], phys_offset: u64) -> Self {
         serial_println!("Initializing frame allocator with memory map:");
 
         for entry in memory_map {
             serial_println!("Base: {:#x}, Length: {:#x}", entry.base, entry.length,);
         }
 
-        Self {
-            memory_map,
-            curr_region_index: 0,
-            frame_offset_in_region: 0,
+        let frame_count = usable_frame_ranges(memory_map)
+            .map(|frames| frames.end)
+            .max()
+            .unwrap_or(0);
+        let bitmap_words = frame_count.div_ceil(BITS_PER_WORD);
+        let bitmap_bytes = bitmap_words * size_of::<u64>();
+        let bitmap_frames = (bitmap_bytes + frame_count * size_of::<u32>()).div_ceil(PAGE_SIZE);
+
+        let bitmap_start = usable_frame_ranges(memory_map)
+            .find(|frames| frames.len() >= bitmap_frames)
+            .expect("no usable region can hold the frame bitmap")
+            .start;
+        let bitmap_virt = bitmap_start as u64 * PAGE_SIZE as u64 + phys_offset;
+        let bitmap =
+            unsafe { core::slice::from_raw_parts_mut(bitmap_virt as *mut u64, bitmap_words) };
+        bitmap.fill(u64::MAX);
+        let share_counts = unsafe {
+            core::slice::from_raw_parts_mut(
+                (bitmap_virt + bitmap_bytes as u64) as *mut u32,
+                frame_count,
+            )
+        };
+        share_counts.fill(0);
+
+        let mut allocator = Self {
+            bitmap,
+            share_counts,
+            next_free_hint: 0,
+            total_frames: 0,
+            free_frames: 0,
+        };
+        for frames in usable_frame_ranges(memory_map) {
+            allocator.total_frames += frames.len();
+            for frame in frames {
+                allocator.mark_free(frame);
+            }
+        }
+        for frame in bitmap_start..bitmap_start + bitmap_frames {
+            allocator.mark_used(frame);
         }
+        // physical address 0 doubles as "no frame"
+        if !allocator.is_used(0) {
+            allocator.mark_used(0);
+        }
+
+        serial_println!(
+            "Frame allocator: {} usable frames, bitmap takes {} frames at {:#x}",
+            allocator.total_frames,
+            bitmap_frames,
+            bitmap_start * PAGE_SIZE
+        );
+        allocator
     }
 
-    fn _usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.type_ == MEMMAP_USABLE);
-        let addr_ranges = usable_regions.map(|r| r.base..r.base + r.length);
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(PAGE_SIZE));
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
 
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
     }
-}
 
-unsafe impl FrameAllocator<Size4KiB> for MemoryMapFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        loop {
-            let region = self.memory_map.get(self.curr_region_index)?;
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    /// Hands out `count` physically contiguous frames, the first one aligned to `align_frames`
+    pub fn allocate_contiguous(&mut self, count: usize, align_frames: usize) -> Option<PhysFrame> {
+        assert!(count > 0, "allocate_contiguous: count must not be 0");
+        assert!(
+            align_frames.is_power_of_two(),
+            "allocate_contiguous: alignment must be a power of two"
+        );
 
-            if region.type_ != MEMMAP_USABLE {
-                self.curr_region_index += 1;
-                self.frame_offset_in_region = 0;
-                continue;
+        let frame_count = self.bitmap.len() * BITS_PER_WORD;
+        let mut start = self.next_free_hint.next_multiple_of(align_frames);
+        while start + count <= frame_count {
+            match (start..start + count).find(|frame| self.is_used(*frame)) {
+                Some(used) => start = (used + 1).next_multiple_of(align_frames),
+                None => {
+                    for frame in start..start + count {
+                        self.mark_used(frame);
+                    }
+                    return Some(Self::frame_at(start));
+                }
             }
+        }
+        None
+    }
+
+    /// Gives back `count` frames starting at `start`, which came from `allocate_contiguous`
+    ///
+    /// # Safety
+    ///
+    /// None of the frames may be mapped or otherwise in use anymore.
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let first = Self::index_of(start.start_address());
+        for frame in first..first + count {
+            self.release(frame);
+        }
+    }
 
-            let page_size: u64 = PAGE_SIZE as u64;
-            let start = align_up(region.base, page_size);
-            let end = region.base + region.length;
+    /// Adds an owner to an allocated frame, it then has to be deallocated once more before it
+    /// is actually freed
+    pub fn share_frame(&mut self, frame: PhysFrame) {
+        let index = Self::index_of(frame.start_address());
+        assert!(
+            self.is_used(index),
+            "frame allocator: sharing free frame {:#x}",
+            frame.start_address()
+        );
+        self.share_counts[index] += 1;
+    }
 
-            let addr = start + self.frame_offset_in_region;
+    /// Number of owners the frame has besides the first one
+    pub fn share_count(&self, frame: PhysFrame) -> u32 {
+        self.share_counts[Self::index_of(frame.start_address())]
+    }
 
-            if addr + page_size <= end {
-                self.frame_offset_in_region += page_size;
-                return Some(PhysFrame::containing_address(PhysAddr::new(addr)));
-            } else {
-                self.curr_region_index += 1;
-                self.frame_offset_in_region = 0;
-            }
+    /// 2 MiB aligned frame for a huge page
+    pub fn allocate_huge_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let start = self.allocate_contiguous(FRAMES_PER_HUGE_PAGE, FRAMES_PER_HUGE_PAGE)?;
+        Some(PhysFrame::containing_address(start.start_address()))
+    }
+
+    /// # Safety
+    ///
+    /// The frame must not be mapped or otherwise in use anymore.
+    pub unsafe fn deallocate_huge_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let start = PhysFrame::containing_address(frame.start_address());
+        unsafe { self.deallocate_contiguous(start, FRAMES_PER_HUGE_PAGE) };
+    }
+
+    fn frame_at(index: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new((index * PAGE_SIZE) as u64))
+    }
+
+    fn index_of(addr: PhysAddr) -> usize {
+        addr.as_u64() as usize / PAGE_SIZE
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn mark_used(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+        self.free_frames -= 1;
+    }
+
+    fn mark_free(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+        self.free_frames += 1;
+        self.next_free_hint = self.next_free_hint.min(index);
+    }
+
+    fn release(&mut self, index: usize) {
+        assert!(
+            index < self.bitmap.len() * BITS_PER_WORD && self.is_used(index),
+            "frame allocator: double free of frame {:#x}",
+            index * PAGE_SIZE
+        );
+        if self.share_counts[index] > 0 {
+            self.share_counts[index] -= 1;
+        } else {
+            self.mark_free(index);
         }
     }
 }
 
+unsafe impl FrameAllocator<Size4KiB> for MemoryMapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let first_word = self.next_free_hint / BITS_PER_WORD;
+        let word_index = first_word
+            + self.bitmap[first_word..]
+                .iter()
+                .position(|word| *word != u64::MAX)?;
+        let index = word_index * BITS_PER_WORD + self.bitmap[word_index].trailing_ones() as usize;
+
+        self.mark_used(index);
+        self.next_free_hint = index + 1;
+        Some(Self::frame_at(index))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for MemoryMapFrameAllocator {
+    /// # Safety
+    ///
+    /// The frame must not be mapped or otherwise in use anymore.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.release(Self::index_of(frame.start_address()));
+    }
+}
+
 impl Handler for IdendtityAcpiHandler {
     unsafe fn map_physical_region<T>(
         &self,
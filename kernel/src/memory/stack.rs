@@ -0,0 +1,115 @@
+use crate::memory::{
+    get_frame_allocator, get_user_mem_mgr,
+    paging::{PAGE_SIZE, no_execute_enabled},
+};
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::{
+    VirtAddr,
+    structures::paging::{PageTableFlags, Size4KiB, mapper::MapToError},
+};
+
+/// Virtual region kernel stacks are carved from, it takes a PML4 entry of its own
+pub const STACK_REGION_START: u64 = 0xFFFF_8100_0000_0000;
+const STACK_REGION_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+/// Every stack sits at the top of a slot of this size, the rest of the slot below it stays
+/// unmapped and is its guard
+pub const STACK_SLOT_SIZE: u64 = 8 * 1024 * 1024;
+/// At least a page of every slot is left as the guard
+pub const MAX_GUARDED_STACK_SIZE: usize = STACK_SLOT_SIZE as usize - PAGE_SIZE;
+const STACK_SLOTS: usize = (STACK_REGION_SIZE / STACK_SLOT_SIZE) as usize;
+
+struct SlotAllocator {
+    /// Slots above this one were never handed out
+    next: usize,
+    free: Vec<usize>,
+}
+
+static SLOTS: Mutex<SlotAllocator> = Mutex::new(SlotAllocator {
+    next: 0,
+    free: Vec::new(),
+});
+
+fn slot_start(slot: usize) -> VirtAddr {
+    VirtAddr::new(STACK_REGION_START + slot as u64 * STACK_SLOT_SIZE)
+}
+
+/// Slot of the stack region `addr` lies in
+pub fn slot_of(addr: u64) -> Option<usize> {
+    let offset = addr.checked_sub(STACK_REGION_START)?;
+    (offset < STACK_REGION_SIZE).then_some((offset / STACK_SLOT_SIZE) as usize)
+}
+
+/// Whether a fault on `addr` with the stack pointer at `rsp` comes from a stack running into
+/// its guard: both lie in the same slot. Only the stack is mapped in a slot, so a fault there
+/// has to be below it.
+pub fn is_stack_overflow(addr: u64, rsp: u64) -> bool {
+    matches!((slot_of(addr), slot_of(rsp)), (Some(fault), Some(stack)) if fault == stack)
+}
+
+/// Kernel stack with unmapped memory below it, running off its end faults instead of
+/// overwriting whatever lies there. Takes the user memory manager and the frame allocator,
+/// so it must not be created or dropped while holding them.
+pub struct GuardedStack {
+    slot: usize,
+    size: usize,
+}
+
+impl GuardedStack {
+    /// Maps `size` bytes, a multiple of the page size, at the top of a free slot
+    pub fn new(size: usize) -> Result<Self, MapToError<Size4KiB>> {
+        assert!(
+            size > 0 && size <= MAX_GUARDED_STACK_SIZE && size.is_multiple_of(PAGE_SIZE),
+            "GuardedStack: invalid stack size {:#x}",
+            size
+        );
+        let slot = {
+            let mut slots = SLOTS.lock();
+            match slots.free.pop() {
+                Some(slot) => slot,
+                None if slots.next < STACK_SLOTS => {
+                    slots.next += 1;
+                    slots.next - 1
+                }
+                None => return Err(MapToError::FrameAllocationFailed),
+            }
+        };
+
+        // dropped on failure, which unmaps whatever got mapped
+        let stack = Self { slot, size };
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        if no_execute_enabled() {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        get_user_mem_mgr().map_kernel_region(
+            stack.bottom(),
+            size as u64,
+            flags,
+            &mut get_frame_allocator(),
+        )?;
+        Ok(stack)
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        slot_start(self.slot + 1)
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.top() - self.size as u64
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for GuardedStack {
+    fn drop(&mut self) {
+        get_user_mem_mgr().unmap_kernel_region(
+            self.bottom(),
+            self.size as u64,
+            &mut *get_frame_allocator(),
+        );
+        SLOTS.lock().free.push(self.slot);
+    }
+}
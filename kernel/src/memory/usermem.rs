@@ -1,15 +1,42 @@
-use crate::{memory::paging::MemoryMapFrameAllocator, serial_println};
+use crate::{
+    memory::paging::{MemoryMapFrameAllocator, PAGE_SIZE},
+    serial_println,
+};
 use x86_64::{
     PhysAddr, VirtAddr,
+    instructions::tlb,
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, Size4KiB,
-        mapper::MapToError,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PageTableIndex, PhysFrame, Size4KiB, Translate,
+        mapper::{CleanUp, MapToError, MappedFrame, TranslateResult},
     },
 };
 
 const LEVEL_4_KERNEL_ENTRIES_START: usize = 256;
 const LEVEL_4_KERNEL_ENTRIES_END: usize = 512;
 pub const USER_STACK_TOP: u64 = 0x7FFF_FFFF_F000;
+/// First address of the kernel half, everything below belongs to userspace
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+/// Mappings made for userspace end at or below this. The last page stays unused, so the end
+/// of every area is a canonical address.
+pub const USER_MAPPABLE_END: u64 = USER_SPACE_END - PAGE_SIZE as u64;
+/// Marks a page shared with another address space, it gets copied on the first write
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+/// Intermediate tables allow everything, the leaf entries decide what a page may be used for
+const USER_TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserCopyError {
+    NotUserAddress(VirtAddr),
+    NotMapped(VirtAddr),
+    NotWritable(VirtAddr),
+}
+
+fn bytes_left_in_page(vaddr: VirtAddr) -> usize {
+    PAGE_SIZE - (vaddr.as_u64() as usize % PAGE_SIZE)
+}
 
 pub struct UserMemoryManager {
     pub kernel_page_table_phys: PhysAddr,
@@ -59,6 +86,31 @@ impl UserMemoryManager {
         user_page_table_phys: PhysAddr,
         user_vaddr: VirtAddr,
     ) -> Option<PhysAddr> {
+        self.walk_user_page_tables(user_page_table_phys, user_vaddr)
+            .map(|(phys_addr, _)| phys_addr)
+    }
+
+    pub fn is_page_mapped(&self, user_page_table_phys: PhysAddr, user_vaddr: VirtAddr) -> bool {
+        self.user_page_mapper(user_page_table_phys)
+            .translate_page(Page::<Size4KiB>::containing_address(user_vaddr))
+            .is_ok()
+    }
+
+    /// Returns the physical address `user_vaddr` maps to and the flags of the mapping.
+    /// Kernel-half addresses and pages not accessible from ring 3 at every level give `None`.
+    fn walk_user_page_tables(
+        &self,
+        user_page_table_phys: PhysAddr,
+        user_vaddr: VirtAddr,
+    ) -> Option<(PhysAddr, PageTableFlags)> {
+        if user_vaddr.as_u64() >= USER_SPACE_END {
+            serial_println!(
+                "translate_user_virt_to_phys: {:?} is not a user address",
+                user_vaddr
+            );
+            return None;
+        }
+
         let pml4_virt = VirtAddr::new(user_page_table_phys.as_u64() + self.phys_offset);
         let pml4 = unsafe { &*(pml4_virt.as_u64() as *const PageTable) };
 
@@ -68,28 +120,33 @@ impl UserMemoryManager {
         let pt_idx = ((user_vaddr.as_u64() >> 12) & 0x1FF) as usize;
         let page_offset = user_vaddr.as_u64() & 0xFFF;
 
+        let user_present = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
         let pml4_entry = &pml4[pml4_idx];
-        if !pml4_entry.flags().contains(PageTableFlags::PRESENT) {
+        if !pml4_entry.flags().contains(user_present) {
             serial_println!("translate_user_virt_to_phys: PML4 entry not present");
             return None;
         }
+        // a page is only writable if every level allows it
+        let mut flags = pml4_entry.flags();
 
         let pdpt_phys = PhysAddr::new(pml4_entry.addr().as_u64());
         let pdpt_virt = VirtAddr::new(pdpt_phys.as_u64() + self.phys_offset);
         let pdpt = unsafe { &*(pdpt_virt.as_u64() as *const PageTable) };
 
         let pdpt_entry = &pdpt[pdpt_idx];
-        if !pdpt_entry.flags().contains(PageTableFlags::PRESENT) {
+        if !pdpt_entry.flags().contains(user_present) {
             serial_println!("translate_user_virt_to_phys: PDPT entry not present");
             return None;
         }
+        flags &= pdpt_entry.flags();
 
         let pd_phys = PhysAddr::new(pdpt_entry.addr().as_u64());
         let pd_virt = VirtAddr::new(pd_phys.as_u64() + self.phys_offset);
         let pd = unsafe { &*(pd_virt.as_u64() as *const PageTable) };
 
         let pd_entry = &pd[pd_idx];
-        if !pd_entry.flags().contains(PageTableFlags::PRESENT) {
+        if !pd_entry.flags().contains(user_present) {
             serial_println!("translate_user_virt_to_phys: PD entry not present");
             return None;
         }
@@ -97,20 +154,137 @@ impl UserMemoryManager {
         if pd_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
             let phys_addr =
                 PhysAddr::new(pd_entry.addr().as_u64() + (user_vaddr.as_u64() & 0x1FFFFF));
-            return Some(phys_addr);
+            return Some((phys_addr, flags & pd_entry.flags()));
         }
+        flags &= pd_entry.flags();
 
         let pt_phys = PhysAddr::new(pd_entry.addr().as_u64());
         let pt_virt = VirtAddr::new(pt_phys.as_u64() + self.phys_offset);
         let pt = unsafe { &*(pt_virt.as_u64() as *const PageTable) };
 
         let pt_entry = &pt[pt_idx];
-        if !pt_entry.flags().contains(PageTableFlags::PRESENT) {
+        if !pt_entry.flags().contains(user_present) {
             serial_println!("translate_user_virt_to_phys: PT entry not present");
             return None;
         }
 
-        Some(PhysAddr::new(pt_entry.addr().as_u64() + page_offset))
+        Some((
+            PhysAddr::new(pt_entry.addr().as_u64() + page_offset),
+            flags & pt_entry.flags(),
+        ))
+    }
+
+    /// Copies `dst.len()` bytes starting at `src` in the given user address space into `dst`.
+    /// Nothing is copied unless the whole range is mapped and accessible from ring 3.
+    pub fn copy_from_user(
+        &self,
+        user_page_table_phys: PhysAddr,
+        src: VirtAddr,
+        dst: &mut [u8],
+    ) -> Result<(), UserCopyError> {
+        self.check_user_range(user_page_table_phys, src, dst.len(), false)?;
+
+        let mut copied = 0;
+        while copied < dst.len() {
+            let vaddr = src + copied as u64;
+            let chunk = (dst.len() - copied).min(bytes_left_in_page(vaddr));
+            let phys_addr = self
+                .translate_user_virt_to_phys(user_page_table_phys, vaddr)
+                .ok_or(UserCopyError::NotMapped(vaddr))?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    (phys_addr.as_u64() + self.phys_offset) as *const u8,
+                    dst[copied..].as_mut_ptr(),
+                    chunk,
+                );
+            }
+            copied += chunk;
+        }
+
+        Ok(())
+    }
+
+    /// Copies `src` to `dst` in the given user address space.
+    /// Nothing is copied unless the whole range is mapped writable and accessible from ring 3.
+    pub fn copy_to_user(
+        &self,
+        user_page_table_phys: PhysAddr,
+        dst: VirtAddr,
+        src: &[u8],
+    ) -> Result<(), UserCopyError> {
+        self.check_user_range(user_page_table_phys, dst, src.len(), true)?;
+        self.write_user_pages(user_page_table_phys, dst, src)
+    }
+
+    /// Like `copy_to_user`, but also writes to read-only pages. Used to fill in memory before
+    /// the process gets to run, like the segments of its program.
+    pub fn load_into_user(
+        &self,
+        user_page_table_phys: PhysAddr,
+        dst: VirtAddr,
+        src: &[u8],
+    ) -> Result<(), UserCopyError> {
+        self.check_user_range(user_page_table_phys, dst, src.len(), false)?;
+        self.write_user_pages(user_page_table_phys, dst, src)
+    }
+
+    fn write_user_pages(
+        &self,
+        user_page_table_phys: PhysAddr,
+        dst: VirtAddr,
+        src: &[u8],
+    ) -> Result<(), UserCopyError> {
+        let mut copied = 0;
+        while copied < src.len() {
+            let vaddr = dst + copied as u64;
+            let chunk = (src.len() - copied).min(bytes_left_in_page(vaddr));
+            let phys_addr = self
+                .translate_user_virt_to_phys(user_page_table_phys, vaddr)
+                .ok_or(UserCopyError::NotMapped(vaddr))?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    src[copied..].as_ptr(),
+                    (phys_addr.as_u64() + self.phys_offset) as *mut u8,
+                    chunk,
+                );
+            }
+            copied += chunk;
+        }
+
+        Ok(())
+    }
+
+    fn check_user_range(
+        &self,
+        user_page_table_phys: PhysAddr,
+        start: VirtAddr,
+        len: usize,
+        writable: bool,
+    ) -> Result<(), UserCopyError> {
+        if len == 0 {
+            return Ok(());
+        }
+
+        let end = start
+            .as_u64()
+            .checked_add(len as u64)
+            .filter(|end| *end <= USER_SPACE_END)
+            .ok_or(UserCopyError::NotUserAddress(start))?;
+
+        let mut page_start = start.align_down(PAGE_SIZE as u64);
+        while page_start.as_u64() < end {
+            // the first page might start before `start`
+            let vaddr = page_start.max(start);
+            let (_, flags) = self
+                .walk_user_page_tables(user_page_table_phys, vaddr)
+                .ok_or(UserCopyError::NotMapped(vaddr))?;
+            if writable && !flags.contains(PageTableFlags::WRITABLE) {
+                return Err(UserCopyError::NotWritable(vaddr));
+            }
+            page_start += PAGE_SIZE as u64;
+        }
+
+        Ok(())
     }
 
     pub fn map_virt_mem_region(
@@ -126,6 +300,20 @@ impl UserMemoryManager {
             virt_addr.as_u64(),
             size_bytes
         );
+        // the kernel half is shared by every address space and must never become user
+        // accessible, `MapToError` has nothing closer to report it with
+        if virt_addr
+            .as_u64()
+            .checked_add(size_bytes)
+            .is_none_or(|end| end > USER_SPACE_END)
+        {
+            serial_println!(
+                "UserMemoryManager: map_virt_mem_region: {:#x} + {:#x} is not a user range",
+                virt_addr.as_u64(),
+                size_bytes
+            );
+            return Err(MapToError::FrameAllocationFailed);
+        }
         let new_table_pml4_virt = VirtAddr::new(pml4_table_phys.as_u64() + self.phys_offset);
         let pml4_table = unsafe { &mut *(new_table_pml4_virt.as_u64() as *mut PageTable) };
 
@@ -140,35 +328,357 @@ impl UserMemoryManager {
             let phys_frame = frame_allocator
                 .allocate_frame()
                 .ok_or(MapToError::FrameAllocationFailed)?;
+            // don't leak whatever the frame held before to userspace
             unsafe {
-                user_page_mapper
-                    .map_to(page, phys_frame, user_flags, frame_allocator)?
-                    .flush();
+                core::ptr::write_bytes(
+                    (phys_frame.start_address().as_u64() + self.phys_offset) as *mut u8,
+                    0,
+                    PAGE_SIZE,
+                );
             }
+            let mapped = unsafe {
+                user_page_mapper.map_to_with_table_flags(
+                    page,
+                    phys_frame,
+                    user_flags,
+                    USER_TABLE_FLAGS,
+                    frame_allocator,
+                )
+            };
+            match mapped {
+                Ok(flush) => flush.flush(),
+                Err(e) => {
+                    unsafe { frame_allocator.deallocate_frame(phys_frame) };
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Unmaps every page of the region and gives its frames back, pages which are not mapped
+    /// are skipped
+    pub fn unmap_virt_mem_region(
+        &self,
+        pml4_table_phys: PhysAddr,
+        virt_addr: VirtAddr,
+        size_bytes: u64,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) {
+        if size_bytes == 0 {
+            return;
         }
+        let mut user_page_mapper = self.user_page_mapper(pml4_table_phys);
+
+        let start_page = Page::<Size4KiB>::containing_address(virt_addr);
+        let end_page = Page::containing_address(virt_addr + size_bytes - 1u64);
+        for page in Page::range_inclusive(start_page, end_page) {
+            if let Ok((frame, flush)) = user_page_mapper.unmap(page) {
+                flush.flush();
+                unsafe { frame_deallocator.deallocate_frame(frame) };
+            }
+        }
+    }
+
+    /// Maps fresh frames for a region of the kernel half into the kernel's page table. Every
+    /// address space sees it, they share the tables below the kernel's PML4 entries as long as
+    /// the entry already existed when the address space was created.
+    pub fn map_kernel_region(
+        &self,
+        virt_addr: VirtAddr,
+        size_bytes: u64,
+        flags: PageTableFlags,
+        frame_allocator: &mut MemoryMapFrameAllocator,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(
+            virt_addr.as_u64() >= USER_SPACE_END,
+            "map_kernel_region: {:#x} is not a kernel address",
+            virt_addr
+        );
+        let mut kernel_page_mapper = self.user_page_mapper(self.kernel_page_table_phys);
 
+        let start_page = Page::containing_address(virt_addr);
+        let end_page = Page::containing_address(virt_addr + size_bytes - 1u64);
+        for page in Page::range_inclusive(start_page, end_page) {
+            let phys_frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe {
+                kernel_page_mapper
+                    .map_to(page, phys_frame, flags, frame_allocator)?
+                    .flush();
+            }
+        }
         Ok(())
     }
 
-    pub fn create_main_stack(
+    /// Unmaps a region `map_kernel_region` mapped and gives its frames back
+    pub fn unmap_kernel_region(
+        &self,
+        virt_addr: VirtAddr,
+        size_bytes: u64,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) {
+        self.unmap_virt_mem_region(
+            self.kernel_page_table_phys,
+            virt_addr,
+            size_bytes,
+            frame_deallocator,
+        );
+    }
+
+    /// Gives every mapped page of the region the leaf flags `protection_flags`, pages which are
+    /// not mapped are skipped. Without `PRESENT` the pages stay mapped but out of reach of ring 3.
+    /// Pages sharing their frame stay copy-on-write instead of becoming writable.
+    pub fn protect_virt_mem_region(
         &self,
         pml4_table_phys: PhysAddr,
-        stack_size: u64,
+        virt_addr: VirtAddr,
+        size_bytes: u64,
+        protection_flags: PageTableFlags,
+        frame_allocator: &MemoryMapFrameAllocator,
+    ) {
+        if size_bytes == 0 {
+            return;
+        }
+        let mut user_page_mapper = self.user_page_mapper(pml4_table_phys);
+
+        let start_page = Page::<Size4KiB>::containing_address(virt_addr);
+        let end_page = Page::containing_address(virt_addr + size_bytes - 1u64);
+        for page in Page::range_inclusive(start_page, end_page) {
+            let TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags,
+                ..
+            } = user_page_mapper.translate(page.start_address())
+            else {
+                continue;
+            };
+
+            let mut new_flags = if protection_flags.contains(PageTableFlags::PRESENT) {
+                protection_flags | PageTableFlags::USER_ACCESSIBLE
+            } else {
+                PageTableFlags::PRESENT
+            };
+            let shared = flags.contains(COPY_ON_WRITE) || frame_allocator.share_count(frame) > 0;
+            if new_flags.contains(PageTableFlags::WRITABLE) && shared {
+                new_flags.remove(PageTableFlags::WRITABLE);
+                new_flags.insert(COPY_ON_WRITE);
+            }
+            if let Ok(flush) = unsafe { user_page_mapper.update_flags(page, new_flags) } {
+                flush.flush();
+            }
+        }
+    }
+
+    /// Frees the page tables of the user half and the top-level table itself.
+    /// Must not be called for the active address space, the kernel half is shared and stays.
+    pub fn free_address_space(
+        &self,
+        pml4_table_phys: PhysAddr,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) {
+        assert!(
+            pml4_table_phys != self.kernel_page_table_phys,
+            "free_address_space: refusing to free the kernel address space"
+        );
+        let mut user_page_mapper = self.user_page_mapper(pml4_table_phys);
+
+        let user_pages = Page::range_inclusive(
+            Page::<Size4KiB>::containing_address(VirtAddr::new(0)),
+            Page::containing_address(VirtAddr::new(USER_SPACE_END - 1)),
+        );
+        unsafe {
+            user_page_mapper.clean_up_addr_range(user_pages, frame_deallocator);
+            frame_deallocator.deallocate_frame(PhysFrame::containing_address(pml4_table_phys));
+        }
+    }
+
+    /// Creates an address space sharing every user page of `parent_pml4_phys`. Pages writable in
+    /// the parent become read-only copy-on-write pages in both, the parent's TLB is flushed.
+    pub fn clone_address_space_cow(
+        &self,
+        parent_pml4_phys: PhysAddr,
         frame_allocator: &mut MemoryMapFrameAllocator,
-    ) -> Result<VirtAddr, MapToError<Size4KiB>> {
-        let stack_top = VirtAddr::new(USER_STACK_TOP);
-        let stack_bottom = stack_top - stack_size;
+    ) -> Result<PhysAddr, MapToError<Size4KiB>> {
+        let child_pml4_phys = self.allocate_new_address_space(frame_allocator)?;
+        let mut child_mapper = self.user_page_mapper(child_pml4_phys);
+
+        let parent_pml4 = unsafe { self.table_at(parent_pml4_phys) };
+        for (pml4_idx, pml4_entry) in parent_pml4
+            .iter()
+            .enumerate()
+            .take(LEVEL_4_KERNEL_ENTRIES_START)
+        {
+            if pml4_entry.is_unused() {
+                continue;
+            }
+            let pdpt = unsafe { self.table_at(pml4_entry.addr()) };
+            for (pdpt_idx, pdpt_entry) in pdpt.iter().enumerate() {
+                if pdpt_entry.is_unused() {
+                    continue;
+                }
+                let pd = unsafe { self.table_at(pdpt_entry.addr()) };
+                for (pd_idx, pd_entry) in pd.iter().enumerate() {
+                    if pd_entry.is_unused() {
+                        continue;
+                    }
+                    let pt = unsafe { self.table_at(pd_entry.addr()) };
+                    for (pt_idx, pt_entry) in pt.iter_mut().enumerate() {
+                        let Ok(frame) = pt_entry.frame() else {
+                            continue;
+                        };
+                        let mut flags = pt_entry.flags();
+                        if flags.contains(PageTableFlags::WRITABLE) {
+                            flags.remove(PageTableFlags::WRITABLE);
+                            flags.insert(COPY_ON_WRITE);
+                            pt_entry.set_flags(flags);
+                        }
 
-        let stack_protection_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+                        let page = Page::from_page_table_indices(
+                            PageTableIndex::new(pml4_idx as u16),
+                            PageTableIndex::new(pdpt_idx as u16),
+                            PageTableIndex::new(pd_idx as u16),
+                            PageTableIndex::new(pt_idx as u16),
+                        );
+                        frame_allocator.share_frame(frame);
+                        let mapped = unsafe {
+                            child_mapper.map_to_with_table_flags(
+                                page,
+                                frame,
+                                flags,
+                                USER_TABLE_FLAGS,
+                                frame_allocator,
+                            )
+                        };
+                        match mapped {
+                            Ok(flush) => flush.ignore(),
+                            Err(e) => {
+                                // the child gives back its share of every frame, the parent
+                                // keeps its pages copy-on-write
+                                unsafe { frame_allocator.deallocate_frame(frame) };
+                                self.release_user_frames(child_pml4_phys, frame_allocator);
+                                self.free_address_space(child_pml4_phys, frame_allocator);
+                                tlb::flush_all();
+                                return Err(e);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        tlb::flush_all();
+        Ok(child_pml4_phys)
+    }
+
+    /// Unmaps every user page of an inactive address space and gives its frame back, the
+    /// tables are left for `free_address_space`
+    fn release_user_frames(
+        &self,
+        pml4_table_phys: PhysAddr,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) {
+        let pml4 = unsafe { self.table_at(pml4_table_phys) };
+        for pml4_entry in pml4.iter().take(LEVEL_4_KERNEL_ENTRIES_START) {
+            if pml4_entry.is_unused() {
+                continue;
+            }
+            let pdpt = unsafe { self.table_at(pml4_entry.addr()) };
+            for pdpt_entry in pdpt.iter() {
+                if pdpt_entry.is_unused() {
+                    continue;
+                }
+                let pd = unsafe { self.table_at(pdpt_entry.addr()) };
+                for pd_entry in pd.iter() {
+                    if pd_entry.is_unused() {
+                        continue;
+                    }
+                    let pt = unsafe { self.table_at(pd_entry.addr()) };
+                    for pt_entry in pt.iter_mut() {
+                        if let Ok(frame) = pt_entry.frame() {
+                            pt_entry.set_unused();
+                            unsafe { frame_deallocator.deallocate_frame(frame) };
+                        }
+                    }
+                }
+            }
+        }
+    }
 
-        self.map_virt_mem_region(
-            pml4_table_phys,
-            stack_bottom,
-            stack_size,
-            stack_protection_flags,
-            frame_allocator,
-        )?;
+    /// Gives the page at `addr` its own writable frame if it is a copy-on-write page, the last
+    /// owner of a shared frame just gets write access back. Returns false for any other page.
+    pub fn resolve_copy_on_write(
+        &self,
+        pml4_table_phys: PhysAddr,
+        addr: VirtAddr,
+        frame_allocator: &mut MemoryMapFrameAllocator,
+    ) -> Result<bool, MapToError<Size4KiB>> {
+        let mut user_page_mapper = self.user_page_mapper(pml4_table_phys);
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } = user_page_mapper.translate(addr)
+        else {
+            return Ok(false);
+        };
+        if !flags.contains(COPY_ON_WRITE) {
+            return Ok(false);
+        }
+        let writable_flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+        if frame_allocator.share_count(frame) == 0 {
+            unsafe {
+                user_page_mapper
+                    .update_flags(page, writable_flags)
+                    .map_err(|_| MapToError::PageAlreadyMapped(frame))?
+                    .flush();
+            }
+            return Ok(true);
+        }
+
+        let copy = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                (frame.start_address().as_u64() + self.phys_offset) as *const u8,
+                (copy.start_address().as_u64() + self.phys_offset) as *mut u8,
+                PAGE_SIZE,
+            );
+        }
+        let (shared, flush) = user_page_mapper
+            .unmap(page)
+            .map_err(|_| MapToError::PageAlreadyMapped(frame))?;
+        flush.flush();
+        unsafe {
+            frame_allocator.deallocate_frame(shared);
+            user_page_mapper
+                .map_to_with_table_flags(
+                    page,
+                    copy,
+                    writable_flags,
+                    USER_TABLE_FLAGS,
+                    frame_allocator,
+                )?
+                .flush();
+        }
+        Ok(true)
+    }
+
+    /// # Safety
+    ///
+    /// `table_phys` must hold a page table.
+    unsafe fn table_at(&self, table_phys: PhysAddr) -> &'static mut PageTable {
+        unsafe { &mut *((table_phys.as_u64() + self.phys_offset) as *mut PageTable) }
+    }
 
-        Ok(stack_top)
+    fn user_page_mapper(&self, pml4_table_phys: PhysAddr) -> OffsetPageTable<'static> {
+        let pml4_virt = VirtAddr::new(pml4_table_phys.as_u64() + self.phys_offset);
+        let pml4_table = unsafe { &mut *(pml4_virt.as_u64() as *mut PageTable) };
+        unsafe { OffsetPageTable::new(pml4_table, VirtAddr::new(self.phys_offset)) }
     }
 }
@@ -1,6 +1,14 @@
+use crate::memory::paging::PAGE_SIZE;
+use crate::process::task::USER_STACK_AREA_START;
 use crate::serial_println;
 use alloc::vec::Vec;
 use elf::ElfBytes;
+use x86_64::instructions::random::RdRand;
+
+/// Where position independent executables are loaded without randomization
+pub const PIE_LOAD_BASE: u64 = 0x0000_5555_0000_0000;
+/// The randomized load base stays within this many pages above `PIE_LOAD_BASE`
+const PIE_MAX_SLIDE_PAGES: u64 = 1 << 18;
 
 #[derive(Debug)]
 pub struct ElfLoadInfo {
@@ -8,6 +16,10 @@ pub struct ElfLoadInfo {
     pub min_vaddr: u64,
     pub max_vaddr: u64,
     pub segments: Vec<LoadSegment>,
+    /// Where the program headers end up in memory, 0 when no segment loads them
+    pub program_headers_vaddr: u64,
+    pub program_header_size: u64,
+    pub program_header_count: u64,
 }
 
 #[derive(Debug)]
@@ -15,9 +27,69 @@ pub struct LoadSegment {
     pub vaddr: u64,
     pub in_file_size: u64,
     pub in_memory_size: u64, // includes the bss section
+    /// `p_flags` of the program header, `PF_R`, `PF_W` and `PF_X`
+    pub flags: u32,
     pub data: Vec<u8>,
 }
 
+impl LoadSegment {
+    pub fn is_writable(&self) -> bool {
+        self.flags & elf::abi::PF_W != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & elf::abi::PF_X != 0
+    }
+
+    /// Cuts the segment in two at `addr`, which has to lie inside it
+    fn split_at(self, addr: u64) -> (LoadSegment, LoadSegment) {
+        let head_size = addr - self.vaddr;
+        let head_file_size = self.in_file_size.min(head_size);
+        let mut head_data = self.data;
+        let tail_data = head_data.split_off((head_file_size as usize).min(head_data.len()));
+
+        let head = LoadSegment {
+            vaddr: self.vaddr,
+            in_file_size: head_file_size,
+            in_memory_size: head_size,
+            flags: self.flags,
+            data: head_data,
+        };
+        let tail = LoadSegment {
+            vaddr: addr,
+            in_file_size: self.in_file_size - head_file_size,
+            in_memory_size: self.in_memory_size - head_size,
+            flags: self.flags,
+            data: tail_data,
+        };
+        (head, tail)
+    }
+
+    /// Adds `next`, which starts inside or right after this segment, the result allows
+    /// everything either of them did
+    fn absorb(&mut self, next: LoadSegment) {
+        let curr_end = self.vaddr + self.in_memory_size;
+        let next_end = next.vaddr + next.in_memory_size;
+        let new_end = core::cmp::max(next_end, curr_end);
+        self.in_memory_size = new_end - self.vaddr;
+        self.flags |= next.flags;
+
+        if next.in_file_size > 0 {
+            let offset_in_curr = (next.vaddr - self.vaddr) as usize;
+            let merged_data_size = offset_in_curr + next.data.len();
+            if self.data.len() < merged_data_size {
+                self.data.resize(merged_data_size, 0)
+            }
+            self.data[offset_in_curr..merged_data_size].copy_from_slice(&next.data);
+        }
+
+        let file_end = next.vaddr + next.in_file_size;
+        if file_end > self.vaddr + self.in_file_size {
+            self.in_file_size = file_end - self.vaddr;
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ElfLoadError {
     InvalidMagic,
@@ -26,6 +98,19 @@ pub enum ElfLoadError {
     InvalidType,
     NoLoadableSegments,
     ReadError,
+    /// Dynamically linked, there is no dynamic linker to hand the program to
+    NeedsInterpreter,
+    UnsupportedRelocation(u32),
+    /// A relocation writes outside the loaded segments
+    InvalidRelocation(u64),
+    /// A segment overflows, holds more file data than memory, or does not lie in user space
+    /// below the stack
+    InvalidSegment(u64),
+    /// The entry point does not lie in an executable segment
+    InvalidEntryPoint(u64),
+    /// Segments with different flags share the page at this address, together it would be
+    /// writable and executable
+    WritableAndExecutable(u64),
     ParseError(elf::ParseError),
 }
 
@@ -35,7 +120,11 @@ impl From<elf::ParseError> for ElfLoadError {
     }
 }
 
-fn merge_segments(segments: Vec<LoadSegment>) -> Vec<LoadSegment> {
+/// Makes sure no page is covered by two segments. Segments with the same flags sharing a page
+/// are merged, otherwise only the shared pages are split off and get the flags of both. A page
+/// which would end up writable and executable that way is rejected.
+fn merge_segments(segments: Vec<LoadSegment>) -> Result<Vec<LoadSegment>, ElfLoadError> {
+    let page_size = PAGE_SIZE as u64;
     let mut merged = Vec::<LoadSegment>::with_capacity(segments.len());
     let mut sorted = segments;
     sorted.sort_by_key(|s| s.vaddr);
@@ -45,37 +134,103 @@ fn merge_segments(segments: Vec<LoadSegment>) -> Vec<LoadSegment> {
 
     for next in sorted_iter {
         let curr_end = curr_seg.vaddr + curr_seg.in_memory_size;
-        if next.vaddr <= curr_end {
-            let next_end = next.vaddr + next.in_memory_size;
-            let new_end = core::cmp::max(next_end, curr_end);
-            curr_seg.in_memory_size = new_end - curr_end;
-
-            if next.in_file_size > 0 {
-                let offset_in_curr = (next.vaddr - curr_seg.vaddr) as usize;
-                let merged_data_size = offset_in_curr + next.data.len();
-                if curr_seg.data.len() < merged_data_size {
-                    curr_seg.data.resize(merged_data_size, 0)
-                }
-                curr_seg.data[offset_in_curr..merged_data_size].copy_from_slice(&next.data);
-            }
-
-            let file_end = next.vaddr + next.in_file_size;
-            if file_end > curr_seg.vaddr + curr_seg.in_file_size {
-                curr_seg.in_file_size = file_end - curr_seg.vaddr;
-            }
-        } else {
+        let curr_page_end = curr_end.next_multiple_of(page_size);
+        if next.vaddr >= curr_page_end {
             merged.push(curr_seg);
             curr_seg = next;
+            continue;
+        }
+
+        // segments sharing a page have to be mapped together
+        if next.flags == curr_seg.flags {
+            curr_seg.absorb(next);
+            continue;
+        }
+
+        let shared_start = (next.vaddr / page_size * page_size).max(curr_seg.vaddr);
+        let shared_end = curr_page_end.min(next.vaddr + next.in_memory_size);
+        let shared_flags = curr_seg.flags | next.flags;
+        if shared_flags & elf::abi::PF_W != 0 && shared_flags & elf::abi::PF_X != 0 {
+            serial_println!(
+                "ELF Error: segments at {:#x} and {:#x} share a page, it would be writable and executable",
+                curr_seg.vaddr,
+                next.vaddr
+            );
+            return Err(ElfLoadError::WritableAndExecutable(shared_start));
+        }
+        let mut shared = if shared_start > curr_seg.vaddr {
+            let (head, tail) = curr_seg.split_at(shared_start);
+            merged.push(head);
+            tail
+        } else {
+            curr_seg
+        };
+
+        if shared_end < next.vaddr + next.in_memory_size {
+            let (next_head, next_tail) = next.split_at(shared_end);
+            shared.absorb(next_head);
+            merged.push(shared);
+            curr_seg = next_tail;
+        } else {
+            shared.absorb(next);
+            curr_seg = shared;
         }
     }
 
     merged.push(curr_seg);
 
-    merged
+    Ok(merged)
+}
+
+/// Writes `value` to `vaddr` in whichever segment covers it, before the segments are merged
+fn apply_relocation(
+    segments: &mut [LoadSegment],
+    vaddr: u64,
+    value: u64,
+) -> Result<(), ElfLoadError> {
+    let size = size_of::<u64>() as u64;
+    let value_end = vaddr
+        .checked_add(size)
+        .ok_or(ElfLoadError::InvalidRelocation(vaddr))?;
+    let segment = segments
+        .iter_mut()
+        .find(|s| {
+            vaddr >= s.vaddr
+                && s.vaddr
+                    .checked_add(s.in_memory_size)
+                    .is_some_and(|end| value_end <= end)
+        })
+        .ok_or(ElfLoadError::InvalidRelocation(vaddr))?;
+
+    let offset = (vaddr - segment.vaddr) as usize;
+    let end = offset + size as usize;
+    // a relocation in the bss turns that part into file data
+    if segment.data.len() < end {
+        segment.data.resize(end, 0);
+        segment.in_file_size = end as u64;
+    }
+    segment.data[offset..end].copy_from_slice(&value.to_ne_bytes());
+    Ok(())
+}
+
+/// Load base for a position independent executable, a random page aligned slide is added
+/// when the CPU can provide randomness
+fn pie_load_base() -> u64 {
+    let slide_pages = RdRand::new()
+        .and_then(|rdrand| rdrand.get_u64())
+        .map_or(0, |random| random % PIE_MAX_SLIDE_PAGES);
+    PIE_LOAD_BASE + slide_pages * PAGE_SIZE as u64
 }
 
 impl ElfLoadInfo {
+    /// Parses `elf_data`, a position independent executable is placed at a randomized base
     pub fn from_elf_data(elf_data: &[u8]) -> Result<Self, ElfLoadError> {
+        Self::from_elf_data_at(elf_data, pie_load_base())
+    }
+
+    /// Parses `elf_data`, a position independent executable is relocated to `pie_base`,
+    /// which has to be page aligned. Other executables ignore it.
+    pub fn from_elf_data_at(elf_data: &[u8], pie_base: u64) -> Result<Self, ElfLoadError> {
         serial_println!("ElfLoadInfo: from_elf_data(): loading elf");
         let elf = ElfBytes::<elf::endian::AnyEndian>::minimal_parse(elf_data)?;
         serial_println!("ElfLoadInfo: from_elf_data(): elf parsed");
@@ -92,42 +247,73 @@ impl ElfLoadInfo {
             }
         }
 
-        let entry_point = elf.ehdr.e_entry;
+        let base = match elf.ehdr.e_type {
+            elf::abi::ET_EXEC => 0,
+            elf::abi::ET_DYN => pie_base,
+            _ => return Err(ElfLoadError::InvalidType),
+        };
+
+        let entry_point = base
+            .checked_add(elf.ehdr.e_entry)
+            .ok_or(ElfLoadError::InvalidEntryPoint(elf.ehdr.e_entry))?;
         serial_println!("ELF: Entry point: {:#x}", entry_point);
 
         // Parse program headers and find loadable segments
-        let mut min_vaddr = u64::MAX;
-        let mut max_vaddr = u64::MIN;
         let mut segments = Vec::new();
+        let mut program_headers_vaddr = 0;
+        let program_headers_offset = elf.ehdr.e_phoff;
 
         let segments_iter = elf.segments().ok_or(ElfLoadError::InvalidHeader)?;
 
         for segment in segments_iter {
-            if segment.p_type == elf::abi::PT_LOAD {
+            if segment.p_type == elf::abi::PT_INTERP {
+                return Err(ElfLoadError::NeedsInterpreter);
+            }
+            if segment.p_type == elf::abi::PT_PHDR {
+                program_headers_vaddr = segment.p_vaddr;
+            } else if segment.p_type == elf::abi::PT_LOAD
+                && program_headers_vaddr == 0
+                && segment
+                    .p_offset
+                    .checked_add(segment.p_filesz)
+                    .is_some_and(|end| (segment.p_offset..end).contains(&program_headers_offset))
+            {
+                program_headers_vaddr = segment
+                    .p_vaddr
+                    .checked_add(program_headers_offset - segment.p_offset)
+                    .ok_or(ElfLoadError::InvalidHeader)?;
+            }
+
+            if segment.p_type == elf::abi::PT_LOAD && segment.p_memsz > 0 {
                 let vaddr = segment.p_vaddr;
                 let in_file_size = segment.p_filesz;
                 let in_memory_size = segment.p_memsz;
                 let offset = segment.p_offset;
+                let flags = segment.p_flags;
 
                 serial_println!(
-                    "ELF: Found LOAD segment: vaddr={:#x}, in_file_size={}, in_memory_size={}",
+                    "ELF: Found LOAD segment: vaddr={:#x}, in_file_size={}, in_memory_size={}, flags={:#x}",
                     vaddr,
                     in_file_size,
-                    in_memory_size
+                    in_memory_size,
+                    flags
                 );
 
-                min_vaddr = core::cmp::min(vaddr, min_vaddr);
-                max_vaddr = core::cmp::max(vaddr + in_memory_size, max_vaddr);
+                // checked again once relocated, this keeps the merging below from overflowing
+                if in_file_size > in_memory_size
+                    || vaddr
+                        .checked_add(in_memory_size)
+                        .is_none_or(|end| end > USER_STACK_AREA_START)
+                {
+                    return Err(ElfLoadError::InvalidSegment(vaddr));
+                }
 
                 let segment_data = if in_file_size > 0 {
-                    let offset_usize = offset as usize;
-                    let in_file_size_usize = in_file_size as usize;
-
-                    if offset_usize + in_file_size_usize > elf_data.len() {
-                        return Err(ElfLoadError::ReadError);
-                    }
-
-                    elf_data[offset_usize..offset_usize + in_file_size_usize].to_vec()
+                    let file_end = offset
+                        .checked_add(in_file_size)
+                        .filter(|end| *end <= elf_data.len() as u64)
+                        .ok_or(ElfLoadError::ReadError)?;
+                    elf_data[offset as usize..file_end as usize].to_vec()
                 } else {
                     Vec::new()
                 };
@@ -136,6 +322,7 @@ impl ElfLoadInfo {
                     vaddr,
                     in_file_size,
                     in_memory_size,
+                    flags,
                     data: segment_data,
                 });
             }
@@ -144,8 +331,52 @@ impl ElfLoadInfo {
         if segments.is_empty() {
             return Err(ElfLoadError::NoLoadableSegments);
         }
-        let segments = merge_segments(segments);
 
+        // only a position independent executable has a load bias to relocate by
+        if elf.ehdr.e_type == elf::abi::ET_DYN
+            && let Some(rela_dyn) = elf.section_header_by_name(".rela.dyn")?
+        {
+            for rela in elf.section_data_as_relas(&rela_dyn)? {
+                match rela.r_type {
+                    elf::abi::R_X86_64_NONE => {}
+                    elf::abi::R_X86_64_RELATIVE => {
+                        let value = base.wrapping_add_signed(rela.r_addend);
+                        apply_relocation(&mut segments, rela.r_offset, value)?;
+                    }
+                    other => return Err(ElfLoadError::UnsupportedRelocation(other)),
+                }
+            }
+        }
+
+        let mut segments = merge_segments(segments)?;
+        // every segment has to end up in user space below the stack, which is mapped later
+        for segment in &mut segments {
+            segment.vaddr = segment
+                .vaddr
+                .checked_add(base)
+                .filter(|vaddr| {
+                    vaddr
+                        .checked_add(segment.in_memory_size)
+                        .is_some_and(|end| end <= USER_STACK_AREA_START)
+                })
+                .ok_or(ElfLoadError::InvalidSegment(segment.vaddr))?;
+        }
+        if program_headers_vaddr != 0 {
+            program_headers_vaddr = program_headers_vaddr.wrapping_add(base);
+        }
+        if !segments.iter().any(|s| {
+            s.is_executable() && (s.vaddr..s.vaddr + s.in_memory_size).contains(&entry_point)
+        }) {
+            return Err(ElfLoadError::InvalidEntryPoint(entry_point));
+        }
+
+        // merged segments are sorted
+        let min_vaddr = segments[0].vaddr;
+        let max_vaddr = segments
+            .iter()
+            .map(|s| s.vaddr + s.in_memory_size)
+            .max()
+            .unwrap_or(min_vaddr);
         serial_println!(
             "ELF: Memory range: {:#x} - {:#x} (size: {:#x} bytes)",
             min_vaddr,
@@ -158,6 +389,9 @@ impl ElfLoadInfo {
             min_vaddr,
             max_vaddr,
             segments,
+            program_headers_vaddr,
+            program_header_size: elf.ehdr.e_phentsize as u64,
+            program_header_count: elf.ehdr.e_phnum as u64,
         })
     }
 }
@@ -1,11 +1,114 @@
-use crate::{process::Process, serial_println};
+use crate::{
+    process::{Process, task::ExecutionContext},
+    serial_println,
+};
 use core::arch::asm;
 use x86_64::{
-    PhysAddr,
+    PhysAddr, VirtAddr,
     registers::control::{Cr3, Cr3Flags},
     structures::paging::PhysFrame,
 };
 
+/// Register state pushed by an interrupt entry stub, followed by the frame pushed by the CPU.
+/// Returning from the interrupt restores everything in here, so rewriting it switches tasks.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InterruptFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+
+    // pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl InterruptFrame {
+    pub fn is_user_mode(&self) -> bool {
+        self.cs & 0b11 == 3
+    }
+}
+
+impl ExecutionContext {
+    pub fn save_from(&mut self, frame: &InterruptFrame) {
+        self.rax = frame.rax;
+        self.rbx = frame.rbx;
+        self.rcx = frame.rcx;
+        self.rdx = frame.rdx;
+        self.rsi = frame.rsi;
+        self.rdi = frame.rdi;
+        self.rbp = frame.rbp;
+        self.rsp = frame.rsp;
+        self.r8 = frame.r8;
+        self.r9 = frame.r9;
+        self.r10 = frame.r10;
+        self.r11 = frame.r11;
+        self.r12 = frame.r12;
+        self.r13 = frame.r13;
+        self.r14 = frame.r14;
+        self.r15 = frame.r15;
+        self.rip = frame.rip;
+        self.rflags = frame.rflags;
+        self.cs = frame.cs;
+        self.ss = frame.ss;
+    }
+
+    pub fn restore_into(&self, frame: &mut InterruptFrame) {
+        frame.rax = self.rax;
+        frame.rbx = self.rbx;
+        frame.rcx = self.rcx;
+        frame.rdx = self.rdx;
+        frame.rsi = self.rsi;
+        frame.rdi = self.rdi;
+        frame.rbp = self.rbp;
+        frame.rsp = self.rsp;
+        frame.r8 = self.r8;
+        frame.r9 = self.r9;
+        frame.r10 = self.r10;
+        frame.r11 = self.r11;
+        frame.r12 = self.r12;
+        frame.r13 = self.r13;
+        frame.r14 = self.r14;
+        frame.r15 = self.r15;
+        frame.rip = self.rip;
+        frame.rflags = self.rflags;
+        frame.cs = self.cs;
+        frame.ss = self.ss;
+    }
+}
+
+/// Switches to the given address space unless it is already active
+pub fn switch_address_space(page_table_base_phys: u64) {
+    let page_table_frame = PhysFrame::containing_address(PhysAddr::new(page_table_base_phys));
+    let (current_frame, _) = Cr3::read();
+    if current_frame != page_table_frame {
+        unsafe {
+            Cr3::write(page_table_frame, Cr3Flags::empty());
+        }
+    }
+}
+
+/// Makes interrupts from ring 3 and syscalls land on the given kernel stack
+pub fn switch_kernel_stack(stack_top: VirtAddr) {
+    crate::gdt::set_privilege_stack(stack_top);
+    crate::process::syscall::set_syscall_stack(stack_top);
+}
+
 pub fn execute_process_direct(process: &Process) -> ! {
     serial_println!("execute_process_direct");
     serial_println!("PID: {}", process.pid);
@@ -26,6 +129,10 @@ pub fn execute_process_direct(process: &Process) -> ! {
 
     serial_println!("Switched to user page table");
 
+    if let Some(kernel_stack) = &process.kernel_stack {
+        switch_kernel_stack(kernel_stack.top());
+    }
+
     unsafe {
         jump_to_userspace(process.execution_context.rip, process.execution_context.rsp);
     }
@@ -70,3 +177,40 @@ unsafe fn jump_to_userspace(entry_point: u64, stack_pointer: u64) -> ! {
         );
     }
 }
+
+/// Returns to ring 3 with every register taken from `frame`, unlike `sysret` which can not
+/// restore rcx and r11. Whatever is left on the current kernel stack is abandoned.
+///
+/// # Safety
+///
+/// `frame` must describe a valid ring 3 context of the running process and nothing on the
+/// kernel stack may need to be dropped or unlocked anymore.
+pub unsafe fn return_to_user(frame: &InterruptFrame) -> ! {
+    unsafe {
+        asm!(
+            "cli",
+            "mov rsp, {frame}",
+
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop r11",
+            "pop r10",
+            "pop r9",
+            "pop r8",
+            "pop rbp",
+            "pop rdi",
+            "pop rsi",
+            "pop rdx",
+            "pop rcx",
+            "pop rbx",
+            "pop rax",
+
+            "iretq",
+
+            frame = in(reg) frame as *const InterruptFrame,
+            options(noreturn)
+        );
+    }
+}
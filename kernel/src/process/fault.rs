@@ -0,0 +1,107 @@
+use crate::process::signal::{SIGBUS, SIGFPE, SIGILL, SIGSEGV, Signal};
+use core::fmt;
+use x86_64::structures::idt::PageFaultErrorCode;
+
+/// CPU exception a process can cause, with what the CPU reported about it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    DivideError,
+    Overflow,
+    BoundRangeExceeded,
+    InvalidOpcode,
+    DeviceNotAvailable,
+    AlignmentCheck,
+    InvalidTss(u64),
+    SegmentNotPresent(u64),
+    StackSegmentFault(u64),
+    GeneralProtection(u64),
+    PageFault {
+        addr: u64,
+        error_code: PageFaultErrorCode,
+    },
+    SimdFloatingPoint,
+}
+
+impl Exception {
+    /// Signal the exception raises in the process which caused it, the same as on Linux
+    pub fn signal(&self) -> Signal {
+        match self {
+            Exception::DivideError
+            | Exception::DeviceNotAvailable
+            | Exception::SimdFloatingPoint => SIGFPE,
+            Exception::InvalidOpcode => SIGILL,
+            Exception::AlignmentCheck
+            | Exception::SegmentNotPresent(_)
+            | Exception::StackSegmentFault(_) => SIGBUS,
+            Exception::Overflow
+            | Exception::BoundRangeExceeded
+            | Exception::InvalidTss(_)
+            | Exception::GeneralProtection(_)
+            | Exception::PageFault { .. } => SIGSEGV,
+        }
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exception::DivideError => write!(f, "DIVIDE BY ZERO"),
+            Exception::Overflow => write!(f, "OVERFLOW"),
+            Exception::BoundRangeExceeded => write!(f, "BOUND RANGE EXCEEDED"),
+            Exception::InvalidOpcode => write!(f, "INVALID OPCODE"),
+            Exception::DeviceNotAvailable => write!(f, "DEVICE NOT AVAILABLE"),
+            Exception::AlignmentCheck => write!(f, "ALIGNMENT CHECK"),
+            Exception::InvalidTss(error_code) => {
+                write!(f, "INVALID TSS\nError Code: {}", error_code)
+            }
+            Exception::SegmentNotPresent(error_code) => {
+                write!(f, "SEGMENT NOT PRESENT\nError Code: {}", error_code)
+            }
+            Exception::StackSegmentFault(error_code) => {
+                write!(f, "STACK SEGMENT FAULT\nError Code: {}", error_code)
+            }
+            Exception::GeneralProtection(error_code) => {
+                write!(f, "GENERAL PROTECTION FAULT\nError Code: {}", error_code)
+            }
+            Exception::PageFault { addr, error_code } => {
+                write!(
+                    f,
+                    "PAGE FAULT\nAccessed Address: {:#x}\nError Code: {:?}",
+                    addr, error_code
+                )
+            }
+            Exception::SimdFloatingPoint => write!(f, "SIMD FLOATING POINT"),
+        }
+    }
+}
+
+/// Exception a process was terminated for, kept until its parent reaps it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub exception: Exception,
+    /// Instruction which caused it
+    pub rip: u64,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // one line, unlike the kernel's own exception reports
+        let name = match self.exception {
+            Exception::DivideError => "divide by zero",
+            Exception::Overflow => "overflow",
+            Exception::BoundRangeExceeded => "bound range exceeded",
+            Exception::InvalidOpcode => "invalid opcode",
+            Exception::DeviceNotAvailable => "device not available",
+            Exception::AlignmentCheck => "alignment check",
+            Exception::InvalidTss(_) => "invalid TSS",
+            Exception::SegmentNotPresent(_) => "segment not present",
+            Exception::StackSegmentFault(_) => "stack segment fault",
+            Exception::GeneralProtection(_) => "general protection fault",
+            Exception::PageFault { addr, .. } => {
+                return write!(f, "page fault on {:#x} at {:#x}", addr, self.rip);
+            }
+            Exception::SimdFloatingPoint => "SIMD floating point exception",
+        };
+        write!(f, "{} at {:#x}", name, self.rip)
+    }
+}
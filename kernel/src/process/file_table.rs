@@ -0,0 +1,196 @@
+use crate::filesystem::sirius::{FileNode, get_sirius};
+use crate::process::{
+    pipe::{PipeReader, PipeWriter},
+    syscall::SyscallError,
+};
+use alloc::{sync::Arc, vec::Vec};
+use bitflags::bitflags;
+use spin::Mutex;
+
+/// Most descriptors a process can have open at once
+pub const MAX_FILE_DESCRIPTORS: usize = 256;
+
+bitflags! {
+    /// How a file is opened, `Open` takes them as its flags argument
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct OpenFlags: usize {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        /// Create the file if it does not exist yet
+        const CREATE = 1 << 2;
+        /// Every write goes to the current end of the file
+        const APPEND = 1 << 3;
+    }
+}
+
+/// What an open file reads from and writes to
+pub enum FileObject {
+    /// Writes go to the serial console, there is no input yet
+    Console,
+    File(FileNode),
+    PipeReader(PipeReader),
+    PipeWriter(PipeWriter),
+}
+
+/// An open file with its own cursor, shared by every descriptor it was duplicated into
+pub struct OpenFile {
+    pub object: FileObject,
+    pub flags: OpenFlags,
+    pub offset: usize,
+}
+
+pub type SharedOpenFile = Arc<Mutex<OpenFile>>;
+
+impl OpenFile {
+    pub fn console() -> Self {
+        Self {
+            object: FileObject::Console,
+            flags: OpenFlags::READ | OpenFlags::WRITE,
+            offset: 0,
+        }
+    }
+
+    pub fn file(node: FileNode, flags: OpenFlags) -> Self {
+        Self {
+            object: FileObject::File(node),
+            flags,
+            offset: 0,
+        }
+    }
+
+    pub fn pipe_reader(reader: PipeReader) -> Self {
+        Self {
+            object: FileObject::PipeReader(reader),
+            flags: OpenFlags::READ,
+            offset: 0,
+        }
+    }
+
+    pub fn pipe_writer(writer: PipeWriter) -> Self {
+        Self {
+            object: FileObject::PipeWriter(writer),
+            flags: OpenFlags::WRITE,
+            offset: 0,
+        }
+    }
+
+    /// Reads at the cursor and moves it past the data, an empty pipe blocks. With
+    /// `until_newline` it stops after the first newline, which is included.
+    pub fn read(&mut self, buffer: &mut [u8], until_newline: bool) -> Result<usize, SyscallError> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(SyscallError::PermissionDenied);
+        }
+        match &self.object {
+            // TODO: keyboard input is consumed by theophe, there is no stdin for processes yet
+            FileObject::Console => Err(SyscallError::NotSupported),
+            FileObject::PipeReader(reader) => Ok(reader.read(buffer, until_newline)),
+            FileObject::PipeWriter(_) => Err(SyscallError::PermissionDenied),
+            FileObject::File(node) => {
+                let mut read = get_sirius().read_node(node, self.offset, buffer)?;
+                if until_newline
+                    && let Some(newline) = buffer[..read].iter().position(|&b| b == b'\n')
+                {
+                    read = newline + 1;
+                }
+                self.offset += read;
+                Ok(read)
+            }
+        }
+    }
+
+    /// Writes at the cursor, or at the end of the file when opened with `APPEND`,
+    /// and moves the cursor past the data. A full pipe blocks.
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, SyscallError> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(SyscallError::PermissionDenied);
+        }
+        match &self.object {
+            FileObject::Console => {
+                crate::serial_print!("{}", alloc::string::String::from_utf8_lossy(data));
+                Ok(data.len())
+            }
+            FileObject::PipeWriter(writer) => {
+                writer.write(data).map_err(|_| SyscallError::BrokenPipe)
+            }
+            FileObject::PipeReader(_) => Err(SyscallError::PermissionDenied),
+            FileObject::File(node) => {
+                let mut sirius = get_sirius();
+                if self.flags.contains(OpenFlags::APPEND) {
+                    self.offset = sirius.refresh_node(node)?.size;
+                }
+                let written = sirius.write_node(node, self.offset, data)?;
+                self.offset += written;
+                Ok(written)
+            }
+        }
+    }
+
+    /// Current size of the file, the console and pipes have none
+    pub fn size(&self) -> Result<usize, SyscallError> {
+        match &self.object {
+            FileObject::File(node) => Ok(get_sirius().refresh_node(node)?.size),
+            _ => Err(SyscallError::NotSupported),
+        }
+    }
+}
+
+/// Maps a process's descriptors to open files. Cloning the table, like fork does,
+/// shares the open files and with them their cursors.
+#[derive(Clone, Default)]
+pub struct FileDescriptorTable {
+    files: Vec<Option<SharedOpenFile>>,
+}
+
+impl FileDescriptorTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Table of a new user process, stdin, stdout and stderr all refer to the console
+    pub fn with_console() -> Self {
+        let console = Arc::new(Mutex::new(OpenFile::console()));
+        Self {
+            files: alloc::vec![Some(console.clone()), Some(console.clone()), Some(console)],
+        }
+    }
+
+    pub fn get(&self, fd: usize) -> Option<SharedOpenFile> {
+        self.files.get(fd)?.clone()
+    }
+
+    /// Puts `file` at the lowest free descriptor and returns it, `None` when the table is full
+    pub fn insert(&mut self, file: SharedOpenFile) -> Option<usize> {
+        let fd = match self.files.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if self.files.len() < MAX_FILE_DESCRIPTORS => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return None,
+        };
+        self.files[fd] = Some(file);
+        Some(fd)
+    }
+
+    /// Points `fd` at `file`, closing whatever it referred to before.
+    /// Returns false when `fd` is out of range.
+    pub fn set(&mut self, fd: usize, file: SharedOpenFile) -> bool {
+        if fd >= MAX_FILE_DESCRIPTORS {
+            return false;
+        }
+        if self.files.len() <= fd {
+            self.files.resize(fd + 1, None);
+        }
+        self.files[fd] = Some(file);
+        true
+    }
+
+    /// Frees `fd`, the file itself is closed once no descriptor refers to it anymore
+    pub fn remove(&mut self, fd: usize) -> Option<SharedOpenFile> {
+        self.files.get_mut(fd)?.take()
+    }
+
+    pub fn open_count(&self) -> usize {
+        self.files.iter().flatten().count()
+    }
+}
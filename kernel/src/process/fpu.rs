@@ -0,0 +1,191 @@
+use crate::process::process_manager::PROCESS_MANAGER;
+use crate::serial_println;
+use alloc::{boxed::Box, vec};
+use core::arch::{asm, x86_64::__cpuid_count};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::registers::{
+    control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+    xcontrol::{XCr0, XCr0Flags},
+};
+
+/// Size of the area `fxsave` writes, also the legacy part at the start of an XSAVE area
+const FXSAVE_AREA_SIZE: usize = 512;
+/// x87 control word after `fninit`, all exceptions masked
+const DEFAULT_FCW: u16 = 0x37F;
+/// MXCSR after reset, all exceptions masked and round to nearest
+const DEFAULT_MXCSR: u32 = 0x1F80;
+const MXCSR_OFFSET: usize = 24;
+
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_AREA_SIZE);
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+/// Nesting depth of `KernelFpu` guards, the kernel touching the FPU outside of them is a bug
+static KERNEL_FPU_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Enables SSE, and AVX with XSAVE where the CPU has them, and sizes the save areas from
+/// CPUID leaf 0xD. The FPU starts out owned by nobody, so its first use traps and loads
+/// the state of whoever uses it.
+pub fn init() {
+    let features = __cpuid_count(1, 0);
+    let has_xsave = features.ecx & (1 << 26) != 0;
+    let has_avx = features.ecx & (1 << 28) != 0;
+
+    unsafe {
+        Cr0::update(|cr0| {
+            cr0.remove(Cr0Flags::EMULATE_COPROCESSOR);
+            cr0.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+        });
+        Cr4::update(|cr4| cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+
+    if !has_xsave {
+        serial_println!(
+            "FPU: no XSAVE, using {} byte FXSAVE areas",
+            FXSAVE_AREA_SIZE
+        );
+        return;
+    }
+
+    let mut components = XCr0Flags::X87 | XCr0Flags::SSE;
+    if has_avx {
+        components |= XCr0Flags::AVX;
+    }
+    unsafe {
+        Cr4::update(|cr4| cr4.insert(Cr4Flags::OSXSAVE));
+        XCr0::write(components);
+    }
+
+    // with XCR0 set, EBX is the size needed for the enabled components
+    let area_size = __cpuid_count(0xD, 0).ebx as usize;
+    AREA_SIZE.store(area_size.max(FXSAVE_AREA_SIZE), Ordering::Relaxed);
+    USE_XSAVE.store(true, Ordering::Relaxed);
+    serial_println!(
+        "FPU: XSAVE with components {:?}, {} byte areas",
+        components,
+        area_size
+    );
+}
+
+/// Bytes of FPU, SSE and AVX state saved per process
+pub fn area_size() -> usize {
+    AREA_SIZE.load(Ordering::Relaxed)
+}
+
+/// Lets the next FPU instruction run without trapping
+pub fn enable() {
+    unsafe { asm!("clts", options(nomem, nostack, preserves_flags)) };
+}
+
+/// Makes the next FPU instruction trap with #NM, so its state can be switched lazily
+pub fn disable() {
+    unsafe { Cr0::update(|cr0| cr0.insert(Cr0Flags::TASK_SWITCHED)) };
+}
+
+/// Whether an #NM from ring 0 happened inside a `KernelFpu` guard
+pub fn in_kernel_fpu() -> bool {
+    KERNEL_FPU_DEPTH.load(Ordering::Relaxed) > 0
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
+struct AreaChunk([u8; 64]);
+
+/// FPU, SSE and AVX registers of a process while it does not own the FPU
+#[derive(Clone)]
+pub struct FpuState {
+    area: Box<[AreaChunk]>,
+}
+
+impl FpuState {
+    /// State a new program starts with, everything zeroed and all exceptions masked.
+    /// The XSAVE header is zeroed too, which `xrstor` takes as the init state.
+    pub fn new() -> Self {
+        let chunks = area_size().div_ceil(size_of::<AreaChunk>());
+        let mut state = Self {
+            area: vec![AreaChunk([0; 64]); chunks].into_boxed_slice(),
+        };
+        let bytes = state.bytes_mut();
+        bytes[..2].copy_from_slice(&DEFAULT_FCW.to_le_bytes());
+        bytes[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+        state
+    }
+
+    pub fn control_word(&self) -> u16 {
+        u16::from_le_bytes([self.bytes()[0], self.bytes()[1]])
+    }
+
+    pub fn mxcsr(&self) -> u32 {
+        let bytes = &self.bytes()[MXCSR_OFFSET..MXCSR_OFFSET + 4];
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    pub fn size(&self) -> usize {
+        self.area.len() * size_of::<AreaChunk>()
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.area.as_ptr().cast(), self.size()) }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.area.as_mut_ptr().cast(), self.size()) }
+    }
+
+    /// Copies the live FPU registers into this area
+    ///
+    /// # Safety
+    ///
+    /// The FPU must be enabled and `init` must have run before this area was allocated.
+    pub unsafe fn save(&mut self) {
+        let area = self.area.as_mut_ptr();
+        unsafe {
+            if USE_XSAVE.load(Ordering::Relaxed) {
+                asm!("xsave64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX,
+                    options(nostack, preserves_flags));
+            } else {
+                asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags));
+            }
+        }
+    }
+
+    /// Loads this area into the FPU registers
+    ///
+    /// # Safety
+    ///
+    /// The FPU must be enabled and `init` must have run before this area was allocated.
+    pub unsafe fn restore(&self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            if USE_XSAVE.load(Ordering::Relaxed) {
+                asm!("xrstor64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX,
+                    options(nostack, preserves_flags, readonly));
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags, readonly));
+            }
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Brackets kernel code using SSE registers. The running task takes over the FPU, the
+/// previous owner's state is saved first. Preemption inside stays fine, the task gets the
+/// FPU back through #NM like any process. Must not be taken while holding `PROCESS_MANAGER`.
+pub struct KernelFpu(());
+
+impl KernelFpu {
+    pub fn begin() -> Self {
+        KERNEL_FPU_DEPTH.fetch_add(1, Ordering::Relaxed);
+        PROCESS_MANAGER.lock().claim_fpu();
+        Self(())
+    }
+}
+
+impl Drop for KernelFpu {
+    fn drop(&mut self) {
+        KERNEL_FPU_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+}
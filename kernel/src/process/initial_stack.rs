@@ -0,0 +1,86 @@
+use alloc::vec::Vec;
+use x86_64::{VirtAddr, instructions::random::RdRand};
+
+// auxiliary vector entry types, see the System V x86_64 ABI
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_RANDOM: u64 = 25;
+
+/// Most bytes of arguments and environment a program can be started with
+pub const MAX_ARGS_SIZE: usize = 64 * 1024;
+
+const WORD_SIZE: usize = size_of::<u64>();
+const RANDOM_SIZE: usize = 16;
+
+/// Contents of the top of a new program's stack, `bytes` go to `rsp` and end at the stack top
+pub struct InitialStack {
+    pub rsp: VirtAddr,
+    pub bytes: Vec<u8>,
+}
+
+/// Lays out the stack a program expects at its entry point, from `rsp` up: argc, the argv
+/// pointers, the envp pointers, the auxiliary vector and finally the strings and the
+/// `AT_RANDOM` bytes. `auxv` gets `AT_RANDOM` and the terminating `AT_NULL` appended.
+pub fn build_initial_stack(
+    stack_top: VirtAddr,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: &[(u64, u64)],
+) -> InitialStack {
+    let strings_size: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    let strings_start = (stack_top - (RANDOM_SIZE + strings_size) as u64).align_down(16u64);
+
+    let words = 1 + (argv.len() + 1) + (envp.len() + 1) + 2 * (auxv.len() + 2);
+    // rsp has to be 16 byte aligned at the entry point
+    let rsp = (strings_start - (words * WORD_SIZE) as u64).align_down(16u64);
+
+    let mut bytes = Vec::with_capacity((stack_top - rsp) as usize);
+    push_word(&mut bytes, argv.len() as u64);
+    let mut string_addr = strings_start + RANDOM_SIZE as u64;
+    for strings in [argv, envp] {
+        for string in strings {
+            push_word(&mut bytes, string_addr.as_u64());
+            string_addr += string.len() as u64 + 1;
+        }
+        push_word(&mut bytes, 0);
+    }
+    for &(key, value) in auxv {
+        push_word(&mut bytes, key);
+        push_word(&mut bytes, value);
+    }
+    push_word(&mut bytes, AT_RANDOM);
+    push_word(&mut bytes, strings_start.as_u64());
+    push_word(&mut bytes, AT_NULL);
+    push_word(&mut bytes, 0);
+
+    bytes.resize((strings_start - rsp) as usize, 0);
+    bytes.extend_from_slice(&random_bytes());
+    for string in argv.iter().chain(envp) {
+        bytes.extend_from_slice(string.as_bytes());
+        bytes.push(0);
+    }
+    bytes.resize((stack_top - rsp) as usize, 0);
+
+    InitialStack { rsp, bytes }
+}
+
+fn push_word(bytes: &mut Vec<u8>, word: u64) {
+    bytes.extend_from_slice(&word.to_ne_bytes());
+}
+
+/// Seed for the program's stack protector and allocator, the TSC stands in without RDRAND
+fn random_bytes() -> [u8; RANDOM_SIZE] {
+    let mut random = [0u8; RANDOM_SIZE];
+    let rdrand = RdRand::new();
+    for chunk in random.as_chunks_mut::<WORD_SIZE>().0 {
+        let value = rdrand
+            .and_then(|rdrand| rdrand.get_u64())
+            .unwrap_or_else(|| unsafe { core::arch::x86_64::_rdtsc() }.rotate_left(17));
+        chunk.copy_from_slice(&value.to_ne_bytes());
+    }
+    random
+}
@@ -1,10 +1,18 @@
 pub mod elf_loader;
 pub mod execution;
+pub mod fault;
+pub mod file_table;
+pub mod fpu;
+pub mod initial_stack;
+pub mod pipe;
 pub mod process_manager;
 pub mod process_mem;
 pub mod scheduler;
+pub mod signal;
 pub mod syscall;
 pub mod task;
+pub mod timer;
+pub mod vma;
 
 pub use process_manager::ProcessManager;
 pub use syscall::SystemCall;
@@ -0,0 +1,103 @@
+use crate::process::scheduler;
+use alloc::{collections::VecDeque, sync::Arc};
+use spin::Mutex;
+
+/// Bytes a pipe holds before writers have to wait for the reader
+pub const PIPE_CAPACITY: usize = 4096;
+
+/// Byte stream between processes, each end marks itself closed when dropped
+struct Pipe {
+    buffer: VecDeque<u8>,
+    reader_open: bool,
+    writer_open: bool,
+}
+
+/// Writing to a pipe nobody can read from anymore
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BrokenPipe;
+
+pub struct PipeReader {
+    pipe: Arc<Mutex<Pipe>>,
+}
+
+pub struct PipeWriter {
+    pipe: Arc<Mutex<Pipe>>,
+}
+
+/// Creates a pipe, it goes away once both ends are dropped
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Mutex::new(Pipe {
+        buffer: VecDeque::with_capacity(PIPE_CAPACITY),
+        reader_open: true,
+        writer_open: true,
+    }));
+    (PipeReader { pipe: pipe.clone() }, PipeWriter { pipe })
+}
+
+impl PipeReader {
+    /// Blocks until there is data, returns 0 once it is drained and the writer is gone.
+    /// With `until_newline` it stops after the first newline, which is included.
+    pub fn read(&self, buffer: &mut [u8], until_newline: bool) -> usize {
+        if buffer.is_empty() {
+            return 0;
+        }
+        loop {
+            {
+                let mut pipe = self.pipe.lock();
+                if !pipe.buffer.is_empty() {
+                    let mut read = 0;
+                    while read < buffer.len()
+                        && let Some(byte) = pipe.buffer.pop_front()
+                    {
+                        buffer[read] = byte;
+                        read += 1;
+                        if until_newline && byte == b'\n' {
+                            break;
+                        }
+                    }
+                    return read;
+                }
+                if !pipe.writer_open {
+                    return 0;
+                }
+            }
+            scheduler::yield_now();
+        }
+    }
+}
+
+impl PipeWriter {
+    /// Blocks until all of `data` is in the pipe. Fails once the reader is gone,
+    /// whatever was written before that stays in the pipe.
+    pub fn write(&self, data: &[u8]) -> Result<usize, BrokenPipe> {
+        let mut written = 0;
+        loop {
+            {
+                let mut pipe = self.pipe.lock();
+                if !pipe.reader_open {
+                    return Err(BrokenPipe);
+                }
+                let room = PIPE_CAPACITY - pipe.buffer.len();
+                let chunk = &data[written..data.len().min(written + room)];
+                pipe.buffer.extend(chunk);
+                written += chunk.len();
+                if written == data.len() {
+                    return Ok(written);
+                }
+            }
+            scheduler::yield_now();
+        }
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.pipe.lock().reader_open = false;
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.pipe.lock().writer_open = false;
+    }
+}
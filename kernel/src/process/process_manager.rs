@@ -1,8 +1,27 @@
 use crate::data_structures::vector::Vec;
-use crate::process::elf_loader::ElfLoadError;
-use crate::process::task::{INVALID_PID, Process, ProcessState};
+use crate::filesystem::sirius::{FileSystemError, get_sirius};
+use crate::memory::{
+    get_frame_allocator, get_user_mem_mgr,
+    paging::MemoryMapFrameAllocator,
+    usermem::{USER_SPACE_END, UserMemoryManager},
+};
+use crate::process::elf_loader::{ElfLoadError, ElfLoadInfo};
+use crate::process::execution::{InterruptFrame, switch_address_space, switch_kernel_stack};
+use crate::process::fault::Fault;
+use crate::process::fpu;
+use crate::process::process_mem::PageFaultError;
+use crate::process::scheduler::{ScheduleReason, Scheduler};
+use crate::process::signal::{self, Delivery, Disposition, Signal, SignalContext};
+use crate::process::task::{
+    ExecutionContext, INVALID_PID, KernelStack, MAX_PRIORITY, PID, Process, ProcessResources,
+    ProcessState,
+};
+use crate::process::timer::TimerQueue;
 use crate::serial_println;
 use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{Size4KiB, mapper::MapToError};
 
 pub const ARCHE_PID: usize = 0;
 
@@ -19,12 +38,35 @@ pub enum ProcessError {
     ProcessNotFound,
     ParentNotFound,
     DoubleDelete,
+    OutOfMemory,
+    /// The arguments and environment do not fit on the new program's stack
+    ArgumentsTooLong,
     ElfLoadError(ElfLoadError),
+    FileSystemError(FileSystemError),
+}
+
+impl From<MapToError<Size4KiB>> for ProcessError {
+    fn from(_: MapToError<Size4KiB>) -> Self {
+        ProcessError::OutOfMemory
+    }
+}
+
+/// What a parent learns about a child it reaped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitStatus {
+    pub pid: PID,
+    pub exit_code: i32,
+    /// Exception the child was terminated for, if any
+    pub fault: Option<Fault>,
 }
 
 pub struct ProcessManager {
     processes: Vec<Process>,
     new_pid: usize,
+    pub scheduler: Scheduler,
+    timers: TimerQueue,
+    /// Task whose state is in the FPU registers, the FPU traps for everyone else
+    fpu_owner: Option<PID>,
 }
 
 unsafe impl Send for ProcessManager {}
@@ -40,75 +82,394 @@ impl ProcessManager {
         Self {
             processes: Vec::with_capacity(16),
             new_pid: 1,
+            scheduler: Scheduler::new(),
+            timers: TimerQueue::new(),
+            fpu_owner: None,
         }
     }
 
+    /// Arche is the kernel's own main thread, it is the root of the process tree
     pub fn init_arche(&mut self) -> usize {
-        // let arche = Process::new(
-        //     ARCHE_PID,
-        //     ARCHE_PID,
-        //     MAX_PRIORITY,
-        //     String::from("arche"),
-        //     true,
-        //     ProcessResources {
-        //         memory_limit: usize::MAX,
-        //         memory_used: 0,
-        //         cpu_time_slice: 0,
-        //     },
-        //     0,
-        //     0,
-        //     0,
-        // );
+        let (kernel_page_table, _) = Cr3::read();
+        let arche = Process::new_kernel_task(
+            ARCHE_PID,
+            "arche",
+            MAX_PRIORITY,
+            ProcessResources {
+                memory_limit: usize::MAX,
+                memory_used: 0,
+                cpu_time_slice: 0,
+            },
+            kernel_page_table.start_address(),
+        );
+        self.processes.push(arche);
+        self.scheduler.reserve(self.processes.len());
         serial_println!("Initialized arche process with PID 0");
-        // self.processes.push(arche);
-        0
+        ARCHE_PID
+    }
+
+    pub fn allocate_pid(&mut self) -> PID {
+        let pid = self.new_pid;
+        self.new_pid += 1;
+        pid
+    }
+
+    /// Adds an already created process and puts it on the ready queue
+    pub fn spawn(&mut self, mut process: Process) -> PID {
+        let pid = process.pid;
+        process.state = ProcessState::Ready;
+
+        if let Ok(parent) = self.get_process_mut(process.parent_pid) {
+            parent.children.push(pid);
+        }
+
+        let priority = process.dynamic_priority;
+        self.processes.push(process);
+        // every process is queued at most once, so `schedule` never has to grow the queue
+        self.scheduler.reserve(self.processes.len());
+        self.scheduler.enqueue(pid, priority);
+        serial_println!("Spawned process with PID: {}", pid);
+        pid
+    }
+
+    /// Saves the interrupted task into its process and loads the next ready one into `frame`.
+    /// Runs in interrupt context, so it must not allocate. The ready queue already has room for
+    /// every process, `spawn` reserves it.
+    pub fn schedule(&mut self, frame: &mut InterruptFrame, reason: ScheduleReason) {
+        if reason == ScheduleReason::Tick {
+            self.wake_expired(crate::interrupts::system_uptime_ns());
+            if self.scheduler.boost_due() {
+                self.boost_priorities();
+            }
+        }
+
+        let current_pid = self.scheduler.current_pid();
+        let mut still_running = false;
+
+        if let Some(pid) = current_pid {
+            let Ok(current) = self.get_process(pid) else {
+                return;
+            };
+
+            still_running = current.state == ProcessState::Running;
+            let priority = current.dynamic_priority;
+
+            if current.state == ProcessState::Waiting || current.state == ProcessState::Ready {
+                // blocked, or already woken up before it got to switch away, either way it
+                // continues from here once picked
+                self.save_current(frame);
+            }
+
+            if still_running && reason == ScheduleReason::Tick {
+                let quantum_over = self.scheduler.tick();
+                let higher_ready = self
+                    .scheduler
+                    .highest_ready_priority()
+                    .is_some_and(|p| p > priority);
+
+                if quantum_over {
+                    // used the whole quantum, most likely CPU-bound
+                    if let Ok(current) = self.get_process_mut(pid) {
+                        current.dynamic_priority = current.dynamic_priority.saturating_sub(1);
+                    }
+                } else if !higher_ready {
+                    return;
+                }
+            }
+        }
+
+        // on a tick the current task competes with the others at its new priority,
+        // a yielding task only runs again when nothing else is ready
+        if still_running && reason == ScheduleReason::Tick {
+            self.park_current(frame);
+        }
+        let mut next_pid = self.pick_ready();
+        if still_running && reason == ScheduleReason::Yield {
+            self.park_current(frame);
+            if next_pid.is_none() {
+                next_pid = self.pick_ready();
+            }
+        }
+
+        let Some(next_pid) = next_pid else {
+            return;
+        };
+        let Ok(next) = self.get_process_mut(next_pid) else {
+            return;
+        };
+        next.state = ProcessState::Running;
+        next.execution_context.restore_into(frame);
+        switch_address_space(next.execution_context.page_table_base_phys);
+        if let Some(kernel_stack) = &next.kernel_stack {
+            switch_kernel_stack(kernel_stack.top());
+        }
+        let time_slice = next.resources.cpu_time_slice as u64;
+        // the FPU state is only switched once the next task actually uses it, see `claim_fpu`
+        if self.fpu_owner == Some(next_pid) {
+            fpu::enable();
+        } else {
+            fpu::disable();
+        }
+        self.scheduler.set_current(next_pid, time_slice);
+    }
+
+    /// Hands the FPU to the running task, saving the state of its previous owner first.
+    /// Runs on #NM in interrupt context, so it must not allocate.
+    pub fn claim_fpu(&mut self) {
+        fpu::enable();
+        let current_pid = self.scheduler.current_pid();
+        if self.fpu_owner == current_pid {
+            return;
+        }
+        if let Some(owner) = self.fpu_owner
+            && let Ok(owner) = self.get_process_mut(owner)
+        {
+            unsafe { owner.fpu_state.save() };
+        }
+        if let Some(pid) = current_pid
+            && let Ok(current) = self.get_process(pid)
+        {
+            unsafe { current.fpu_state.restore() };
+        }
+        self.fpu_owner = current_pid;
+    }
+
+    /// Blocks `pid` until the uptime reaches `deadline_ns`, the caller still has to yield
+    pub fn sleep_until(&mut self, pid: PID, deadline_ns: u64) -> Result<(), ProcessError> {
+        let process = self.get_process_mut(pid)?;
+        process.state = ProcessState::Waiting;
+        process.wake_at = Some(deadline_ns);
+        self.timers.add(deadline_ns, pid);
+        Ok(())
+    }
+
+    /// Blocks `pid` until one of its children exits, the caller still has to yield
+    pub fn wait_for_child(&mut self, pid: PID) -> Result<(), ProcessError> {
+        let process = self.get_process_mut(pid)?;
+        process.state = ProcessState::Waiting;
+        process.waits_for_child = true;
+        Ok(())
+    }
+
+    /// Marks `pid`, which is running again, as no longer sleeping
+    pub fn cancel_sleep(&mut self, pid: PID) {
+        if let Ok(process) = self.get_process_mut(pid)
+            && process.wake_at.take().is_some()
+            && process.state == ProcessState::Waiting
+        {
+            process.state = ProcessState::Running;
+        }
+    }
+
+    /// Readies the sleepers whose deadline is not after `now_ns`. Runs on the timer
+    /// interrupt, so it must not allocate.
+    pub fn wake_expired(&mut self, now_ns: u64) {
+        while let Some((deadline_ns, pid)) = self.timers.pop_expired(now_ns) {
+            let Ok(process) = self.get_process_mut(pid) else {
+                continue;
+            };
+            // a deadline the process no longer sleeps for, it was woken or slept again since
+            if process.wake_at != Some(deadline_ns) {
+                continue;
+            }
+            process.wake_at = None;
+            if process.state == ProcessState::Waiting {
+                process.state = ProcessState::Ready;
+                let priority = process.dynamic_priority;
+                self.scheduler.enqueue(pid, priority);
+            }
+        }
+    }
+
+    fn park_current(&mut self, frame: &InterruptFrame) {
+        let Some(pid) = self.scheduler.current_pid() else {
+            return;
+        };
+        if let Ok(current) = self.get_process_mut(pid) {
+            current.execution_context.save_from(frame);
+            current.state = ProcessState::Ready;
+            let priority = current.dynamic_priority;
+            self.scheduler.enqueue(pid, priority);
+        }
+    }
+
+    fn save_current(&mut self, frame: &InterruptFrame) {
+        let Some(pid) = self.scheduler.current_pid() else {
+            return;
+        };
+        if let Ok(current) = self.get_process_mut(pid) {
+            current.execution_context.save_from(frame);
+        }
+    }
+
+    fn pick_ready(&mut self) -> Option<PID> {
+        loop {
+            let pid = self.scheduler.pick_next()?;
+            if matches!(self.get_process(pid), Ok(p) if p.state == ProcessState::Ready) {
+                return Some(pid);
+            }
+        }
+    }
+
+    fn boost_priorities(&mut self) {
+        for process in self.processes.iter_mut() {
+            process.dynamic_priority = process.priority;
+        }
+
+        let processes = &self.processes;
+        self.scheduler.boost(|pid| {
+            processes
+                .iter()
+                .find(|p| p.pid == pid)
+                .map_or(0, |p| p.priority)
+        });
     }
 
+    /// Loads the ELF program at `path` from the filesystem and puts it on the ready queue
+    /// as a child of `parent_pid`, which it inherits its resource limits from.
+    /// `argv` ends up on its initial stack.
     pub fn create_process(
         &mut self,
-        parent_pid: usize,
-        _priority: u8,
-        _name_ptr: *const u8,
-        _name_len: u8,
-        _is_out: bool,
-    ) -> Result<usize, ProcessError> {
+        parent_pid: PID,
+        priority: u8,
+        path: &str,
+        is_out: bool,
+        argv: &[&str],
+    ) -> Result<PID, ProcessError> {
         assert!(
             parent_pid != INVALID_PID,
             "Parent PID cannot be INVALID_PID"
         );
 
-        let new_pid = self.new_pid;
-        self.new_pid += 1;
+        let parent = self.get_process(parent_pid)?;
+        let resources = ProcessResources {
+            memory_limit: parent.resources.memory_limit,
+            memory_used: 0,
+            cpu_time_slice: parent.resources.cpu_time_slice,
+        };
+
+        let elf_data = get_sirius()
+            .read_whole_file(path)
+            .map_err(ProcessError::FileSystemError)?;
+        let elf_info = ElfLoadInfo::from_elf_data(&elf_data).map_err(ProcessError::ElfLoadError)?;
+        // the syscall returns there through sysret, which faults in ring 0 on an address
+        // that is not canonical
+        if elf_info.entry_point >= USER_SPACE_END {
+            return Err(ProcessError::ElfLoadError(ElfLoadError::InvalidEntryPoint(
+                elf_info.entry_point,
+            )));
+        }
+
+        let name = path.rsplit('/').next().unwrap_or(path);
+        let new_pid = self.allocate_pid();
+        let mut process =
+            Process::create_with_elf(&elf_info, name, new_pid, parent_pid, argv, &[])?;
+        process.priority = priority.min(MAX_PRIORITY);
+        process.dynamic_priority = process.priority;
+        process.resources = resources;
+        process.account_memory();
+        process.is_out = is_out;
+
+        Ok(self.spawn(process))
+    }
+
+    /// Creates a child of `parent_pid` sharing its memory copy-on-write, it resumes at `context`
+    pub fn fork_process(
+        &mut self,
+        parent_pid: PID,
+        context: ExecutionContext,
+    ) -> Result<PID, ProcessError> {
+        self.get_process(parent_pid)?;
+        // taken before the address space is copied, it simply goes again if that fails
+        let kernel_stack = KernelStack::new().map_err(|_| ProcessError::OutOfMemory)?;
+        let pid = self.allocate_pid();
+        let parent = self.get_process(parent_pid)?;
+        let memory_layout = parent
+            .memory_layout
+            .fork(&get_user_mem_mgr(), &mut get_frame_allocator())
+            .map_err(|_| ProcessError::OutOfMemory)?;
 
-        // let parent = self.get_process_mut(parent_pid)?;
+        let mut fpu_state = parent.fpu_state.clone();
+        if self.fpu_owner == Some(parent_pid) {
+            // the parent's saved state is stale while it owns the FPU
+            unsafe { fpu_state.save() };
+        }
 
-        // //TODO:
-        // //let child_resources = if is_out {
-        // let resources = ProcessResources {
-        //     memory_limit: parent.resources.memory_limit / 4,
-        //     memory_used: 0,
-        //     cpu_time_slice: parent.resources.cpu_time_slice / 2,
-        // };
+        let process = Process {
+            pid,
+            parent_pid,
+            priority: parent.priority,
+            dynamic_priority: parent.dynamic_priority,
+            state: ProcessState::Ready,
+            name: parent.name.clone(),
+            children: Vec::new(),
+            file_descriptors: parent.file_descriptors.clone(),
+            signals: parent.signals.fork(),
+            resources: parent.resources,
+            exit_code: None,
+            wake_at: None,
+            waits_for_child: false,
+            fault: None,
+            is_out: parent.is_out,
+            execution_context: ExecutionContext {
+                page_table_base_phys: memory_layout.top_page_table_phys.as_u64(),
+                ..context
+            },
+            fpu_state,
+            memory_layout,
+            kernel_stack: Some(kernel_stack),
+        };
+
+        Ok(self.spawn(process))
+    }
 
-        // let name_str = unsafe {
-        //     let slice = core::slice::from_raw_parts(name_ptr, name_len as usize);
-        //     String::from_utf8_lossy(slice).into_owned()
-        // };
+    /// Loads the program at `path` into the running process `pid` in place of its current one.
+    /// Nothing changes if the program can not be loaded. Returns the context the new program
+    /// starts at, the caller has to return to it.
+    pub fn exec_process(
+        &mut self,
+        pid: PID,
+        path: &str,
+        argv: &[&str],
+        envp: &[&str],
+    ) -> Result<ExecutionContext, ProcessError> {
+        assert_eq!(
+            self.scheduler.current_pid(),
+            Some(pid),
+            "exec_process: only the running process can be replaced"
+        );
+
+        let elf_data = get_sirius()
+            .read_whole_file(path)
+            .map_err(ProcessError::FileSystemError)?;
+        let elf_info = ElfLoadInfo::from_elf_data(&elf_data).map_err(ProcessError::ElfLoadError)?;
+        // the syscall returns there through sysret, which faults in ring 0 on an address
+        // that is not canonical
+        if elf_info.entry_point >= USER_SPACE_END {
+            return Err(ProcessError::ElfLoadError(ElfLoadError::InvalidEntryPoint(
+                elf_info.entry_point,
+            )));
+        }
 
-        // let mut new_process = Process::new(
-        //     new_pid, parent_pid, priority, name_str, is_out, resources,
-        //     0, // TODO: will be set when loading program
-        //     0, // TODO: will be set when allocating process memory
-        //     0, //TODO: will be set when creating process page tables
-        // );
-        // new_process.state = ProcessState::Ready;
+        let name = path.rsplit('/').next().unwrap_or(path);
+        let process = self.get_process_mut(pid)?;
+        let mut old_layout = process.exec(&elf_info, name, argv, envp)?;
+        let context = process.execution_context;
+        if self.fpu_owner == Some(pid) {
+            // the live registers belong to the old program, the new one loads its own on first use
+            self.fpu_owner = None;
+            fpu::disable();
+        }
 
-        // parent.children.push(new_pid);
+        // the old address space can only go once we no longer run in it
+        switch_address_space(context.page_table_base_phys);
+        old_layout.release(&get_user_mem_mgr(), &mut *get_frame_allocator());
 
-        // self.processes.push(new_process);
-        Ok(new_pid)
+        Ok(context)
     }
 
+    /// Marks the process as terminated, it stays around as a zombie holding its exit code
+    /// until the parent reaps it. A waiting parent is woken up.
     pub fn terminate_process(
         &mut self,
         pid: usize,
@@ -119,19 +480,26 @@ impl ProcessManager {
             return Err(ProcessError::DoubleDelete);
         }
 
-        let children = {
+        let (parent_pid, children) = {
             let process = self.get_process_mut(pid)?;
+            if process.state == ProcessState::Terminated {
+                return Err(ProcessError::DoubleDelete);
+            }
             process.state = ProcessState::Terminated;
             process.exit_code = Some(exit_code);
-            process.children.clone()
+            (process.parent_pid, process.children.clone())
         };
+        self.scheduler.remove(pid);
 
-        if let Ok(parent) = self.get_process_mut(pid) {
-            for child_pid in parent.children.iter_mut() {
-                if *child_pid == pid {
-                    *child_pid = INVALID_PID;
-                }
-            }
+        if parent_pid != pid
+            && let Ok(parent) = self.get_process_mut(parent_pid)
+            && parent.state == ProcessState::Waiting
+            && parent.waits_for_child
+        {
+            parent.state = ProcessState::Ready;
+            parent.waits_for_child = false;
+            let priority = parent.dynamic_priority;
+            self.scheduler.enqueue(parent_pid, priority);
         }
 
         if cascade {
@@ -148,12 +516,182 @@ impl ProcessManager {
                     arche.children.push(child_pid);
                 }
             }
+            if let Ok(process) = self.get_process_mut(pid) {
+                process.children.clear();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends `signal` to `pid`. One whose default action kills the process does so right away,
+    /// unless it is blocked or the process is the running one. Otherwise it waits until the
+    /// process returns to ring 3.
+    pub fn send_signal(&mut self, pid: PID, signal: Signal) -> Result<(), ProcessError> {
+        let running = self.scheduler.current_pid() == Some(pid);
+        let process = self.get_process_mut(pid)?;
+        if process.state == ProcessState::Terminated {
+            return Ok(());
         }
 
+        let signals = &mut process.signals;
+        if !running
+            && !signals.is_blocked(signal)
+            && signals.disposition(signal) == Disposition::Terminate
+        {
+            serial_println!("PID {} killed by signal {}", pid, signal);
+            return self.terminate_process(pid, signal::exit_code(signal), false);
+        }
+        signals.raise(signal);
         Ok(())
     }
 
-    pub fn cleanup_dead(&mut self) {}
+    /// Points `frame`, on its way back to ring 3, at the handler of the next pending signal of
+    /// the running process. Returns the signal which has to terminate the process instead.
+    pub fn deliver_signal(
+        &mut self,
+        frame: &mut InterruptFrame,
+        address_space_manager: &UserMemoryManager,
+        frame_allocator: &mut MemoryMapFrameAllocator,
+    ) -> Option<Signal> {
+        let pid = self.scheduler.current_pid()?;
+        let process = self.get_process_mut(pid).ok()?;
+        if !process.signals.has_deliverable() {
+            return None;
+        }
+
+        let mut context = SignalContext::from_interrupt_frame(frame);
+        match signal::deliver(
+            process,
+            &mut context,
+            address_space_manager,
+            frame_allocator,
+        ) {
+            Delivery::Nothing => None,
+            Delivery::Handler(_) => {
+                context.restore_into(frame);
+                None
+            }
+            Delivery::Fatal(signal) => Some(signal),
+        }
+    }
+
+    /// Terminates `pid` by `signal`, which a CPU exception it caused raised. The `fault` is
+    /// kept along with the exit code.
+    pub fn terminate_for_fault(
+        &mut self,
+        pid: PID,
+        fault: Fault,
+        signal: Signal,
+    ) -> Result<(), ProcessError> {
+        self.get_process_mut(pid)?.fault = Some(fault);
+        self.terminate_process(pid, signal::exit_code(signal), false)
+    }
+
+    /// Removes a terminated child of `parent_pid` and returns how it exited.
+    /// `child_pid` selects a specific child, `None` takes any of them.
+    /// Gives `Ok(None)` when the matching children are all still alive.
+    pub fn reap_child(
+        &mut self,
+        parent_pid: PID,
+        child_pid: Option<PID>,
+    ) -> Result<Option<ExitStatus>, ProcessError> {
+        let parent = self.get_process(parent_pid)?;
+        let mut candidates = parent
+            .children
+            .iter()
+            .copied()
+            .filter(|pid| child_pid.is_none_or(|wanted| wanted == *pid))
+            .peekable();
+        if candidates.peek().is_none() {
+            return Err(ProcessError::ProcessNotFound);
+        }
+
+        let current_pid = self.scheduler.current_pid();
+        let zombie = candidates.find(|pid| {
+            Some(*pid) != current_pid
+                && matches!(self.get_process(*pid), Ok(p) if p.state == ProcessState::Terminated)
+        });
+        let Some(zombie) = zombie else {
+            return Ok(None);
+        };
+
+        let process = self.reap(zombie)?;
+        Ok(Some(ExitStatus {
+            pid: zombie,
+            exit_code: process.exit_code.unwrap_or(0),
+            fault: process.fault,
+        }))
+    }
+
+    /// Frees the memory of terminated processes and drops the zombies nobody can wait for
+    /// anymore. The running task is left alone, it may still be on its way out.
+    pub fn cleanup_dead(&mut self) {
+        let current_pid = self.scheduler.current_pid();
+
+        let mut orphaned_zombies = Vec::new();
+        for process in self.processes.iter() {
+            if process.state != ProcessState::Terminated || Some(process.pid) == current_pid {
+                continue;
+            }
+            let parent_alive = self
+                .processes
+                .iter()
+                .any(|p| p.pid == process.parent_pid && p.state != ProcessState::Terminated);
+            if !parent_alive {
+                orphaned_zombies.push(process.pid);
+            }
+        }
+
+        for process in self.processes.iter_mut() {
+            if process.state == ProcessState::Terminated && Some(process.pid) != current_pid {
+                Self::release_memory(process);
+            }
+        }
+        for pid in orphaned_zombies.iter().copied() {
+            let _ = self.reap(pid);
+        }
+    }
+
+    /// Takes a terminated process out of the process list, freeing everything it owned
+    fn reap(&mut self, pid: PID) -> Result<Process, ProcessError> {
+        assert!(
+            Some(pid) != self.scheduler.current_pid(),
+            "reap: PID {} is still running",
+            pid
+        );
+        let index = self
+            .processes
+            .iter()
+            .position(|p| p.pid == pid)
+            .ok_or(ProcessError::ProcessNotFound)?;
+        let mut process = self.processes.remove(index);
+        Self::release_memory(&mut process);
+        if self.fpu_owner == Some(pid) {
+            self.fpu_owner = None;
+        }
+
+        if let Ok(parent) = self.get_process_mut(process.parent_pid)
+            && let Some(index) = parent.children.iter().position(|child| *child == pid)
+        {
+            parent.children.remove(index);
+        }
+
+        serial_println!("Reaped process with PID: {}", pid);
+        Ok(process)
+    }
+
+    fn release_memory(process: &mut Process) {
+        if process.is_kernel_task() {
+            return;
+        }
+        let address_space_manager = get_user_mem_mgr();
+        let mut frame_allocator = get_frame_allocator();
+        process
+            .memory_layout
+            .release(&address_space_manager, &mut *frame_allocator);
+        process.resources.memory_used = 0;
+    }
 
     pub fn get_process(&self, pid: usize) -> Result<&Process, ProcessError> {
         assert!(pid != INVALID_PID, "get_process: PID cannot be INVALID_PID");
@@ -174,6 +712,24 @@ impl ProcessManager {
             .ok_or(ProcessError::ProcessNotFound)
     }
 
+    /// Backs a lazily allocated or copy-on-write page of `pid` after it faulted on `addr`.
+    /// Takes the memory locks from the caller, a fault handler can not wait for them.
+    pub fn handle_page_fault(
+        &mut self,
+        pid: PID,
+        addr: VirtAddr,
+        write: bool,
+        address_space_manager: &UserMemoryManager,
+        frame_allocator: &mut MemoryMapFrameAllocator,
+    ) -> Result<(), PageFaultError> {
+        let process = self
+            .get_process_mut(pid)
+            .map_err(|_| PageFaultError::NotInRegion(addr))?;
+        process
+            .memory_layout
+            .handle_page_fault(addr, write, address_space_manager, frame_allocator)
+    }
+
     pub fn get_process_list(&self) -> &Vec<Process> {
         &self.processes
     }
@@ -1,10 +1,9 @@
-use crate::memory::paging::{MemoryMapFrameAllocator, PAGE_SIZE};
-use crate::memory::usermem::UserMemoryManager;
+use crate::memory::paging::{MemoryMapFrameAllocator, PAGE_SIZE, no_execute_enabled};
+use crate::memory::usermem::{USER_STACK_TOP, UserMemoryManager};
+use crate::process::vma::{Vma, VmaKind, VmaTree};
 use crate::serial_println;
-use alloc::vec::Vec;
-use spin::MutexGuard;
-use x86_64::structures::paging::Size4KiB;
 use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameDeallocator, Size4KiB};
 use x86_64::{PhysAddr, VirtAddr, structures::paging::PageTableFlags};
 
 #[derive(Debug, Clone)]
@@ -14,14 +13,31 @@ pub struct ProcessMemoryLayout {
     pub stack_size: u64,
     pub heap_start: VirtAddr,
     pub heap_end: VirtAddr,
-    pub mapped_regions: Vec<MappedMemoryRegion>,
+    pub vmas: VmaTree,
 }
 
-#[derive(Debug, Clone)]
-pub struct MappedMemoryRegion {
-    pub start_virt: VirtAddr,
-    pub size_bytes: u64,
-    pub page_flags: PageTableFlags,
+/// `Mmap` places mappings without a usable address hint at the lowest free range above this
+pub const MMAP_BASE: u64 = 0x0000_1000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultError {
+    /// The address is not part of any area
+    NotInRegion(VirtAddr),
+    /// The region does not allow the access
+    AccessViolation(VirtAddr),
+    /// The access hit the guard below the stack
+    StackOverflow(VirtAddr),
+    OutOfMemory,
+}
+
+/// Flags of the stack and heap, readable and writable but never executable
+pub fn data_page_flags() -> PageTableFlags {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    if no_execute_enabled() {
+        flags | PageTableFlags::NO_EXECUTE
+    } else {
+        flags
+    }
 }
 
 const fn align_to_page_size(size: u64) -> u64 {
@@ -38,7 +54,7 @@ impl ProcessMemoryLayout {
 
         Ok(Self {
             top_page_table_phys,
-            mapped_regions: Vec::new(),
+            vmas: VmaTree::new(),
             stack_top: VirtAddr::new(0),
             stack_size: 0u64,
             heap_start: VirtAddr::new(0x0000_0000_6000_0000),
@@ -46,57 +62,352 @@ impl ProcessMemoryLayout {
         })
     }
 
-    pub fn grow_heap(
+    /// Layout of a task running in an already existing address space, it owns no user memory
+    pub fn existing(top_page_table_phys: PhysAddr) -> Self {
+        Self {
+            top_page_table_phys,
+            vmas: VmaTree::new(),
+            stack_top: VirtAddr::new(0),
+            stack_size: 0u64,
+            heap_start: VirtAddr::new(0),
+            heap_end: VirtAddr::new(0),
+        }
+    }
+
+    /// Reserves `size_bytes` at `start_virt`, the pages are only backed once touched
+    pub fn reserve_lazy(
         &mut self,
-        new_heap_end: VirtAddr,
-        address_space_manager: MutexGuard<'_, UserMemoryManager>,
+        start_virt: VirtAddr,
+        size_bytes: u64,
+        flags: PageTableFlags,
+        kind: VmaKind,
+    ) {
+        self.vmas.insert(Vma {
+            start_virt,
+            size_bytes,
+            page_flags: flags,
+            lazy: true,
+            kind,
+        });
+    }
+
+    pub fn vma_containing(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.vmas.find(addr)
+    }
+
+    /// Where a new mapping of `size_bytes` can go: at `hint` if that range is free, otherwise
+    /// in the lowest free range above `MMAP_BASE`
+    pub fn find_free_range(&self, hint: Option<VirtAddr>, size_bytes: u64) -> Option<VirtAddr> {
+        let highest = VirtAddr::new(USER_STACK_TOP);
+        if let Some(hint) = hint
+            && hint.as_u64().saturating_add(size_bytes) <= highest.as_u64()
+            && !self.vmas.overlaps(hint, size_bytes)
+        {
+            return Some(hint);
+        }
+        self.vmas
+            .find_free(size_bytes, VirtAddr::new(MMAP_BASE), highest)
+    }
+
+    /// Backs `vma`, which has to cover a page-aligned free range, right away with `data`
+    /// followed by zeroes. Its flags only apply once the pages are filled in.
+    pub fn map_filled(
+        &mut self,
+        vma: Vma,
+        data: &[u8],
+        address_space_manager: &UserMemoryManager,
         frame_allocator: &mut MemoryMapFrameAllocator,
-    ) -> Result<VirtAddr, MapToError<Size4KiB>> {
-        if new_heap_end < self.heap_start {
-            serial_println!(
-                "ProcessMemoryLayout: grow_heap: new_heap_end ({}) < current heap_start ({})",
-                new_heap_end.as_u64(),
-                self.heap_start.as_u64()
+    ) -> Result<(), MapToError<Size4KiB>> {
+        debug_assert!(data.len() as u64 <= vma.size_bytes);
+        if let Err(e) = address_space_manager.map_virt_mem_region(
+            self.top_page_table_phys,
+            vma.start_virt,
+            vma.size_bytes,
+            PageTableFlags::PRESENT,
+            frame_allocator,
+        ) {
+            // pages mapped before the failure have to go again
+            address_space_manager.unmap_virt_mem_region(
+                self.top_page_table_phys,
+                vma.start_virt,
+                vma.size_bytes,
+                frame_allocator,
             );
-            return Ok(self.heap_end);
-        }
-
-        if new_heap_end > self.heap_end {
-            let grow_by = align_to_page_size(new_heap_end - self.heap_end);
-            if grow_by > 0 {
-                let protection_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-
-                address_space_manager.map_virt_mem_region(
-                    self.top_page_table_phys,
-                    self.heap_end,
-                    grow_by,
-                    protection_flags,
-                    frame_allocator,
-                )?;
-                self.mapped_regions.push(MappedMemoryRegion {
-                    start_virt: self.heap_end,
-                    size_bytes: grow_by,
-                    page_flags: protection_flags,
-                });
-                self.heap_end += grow_by;
-
-                serial_println!(
-                    "ProcessMemoryLayout: grow_heap: Grew heap by {} bytes (new heap_end: {})",
-                    grow_by,
-                    self.heap_end.as_u64()
-                );
+            return Err(e);
+        }
+        address_space_manager
+            .load_into_user(self.top_page_table_phys, vma.start_virt, data)
+            .expect("pages were just mapped");
+        address_space_manager.protect_virt_mem_region(
+            self.top_page_table_phys,
+            vma.start_virt,
+            vma.size_bytes,
+            vma.page_flags,
+            frame_allocator,
+        );
+        self.vmas.insert(Vma { lazy: false, ..vma });
+        Ok(())
+    }
+
+    /// Removes every area in the range, splitting those sticking out of it, and frees the frames
+    /// of their pages
+    pub fn unmap(
+        &mut self,
+        start_virt: VirtAddr,
+        size_bytes: u64,
+        address_space_manager: &UserMemoryManager,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) {
+        for vma in self.vmas.remove_range(start_virt, size_bytes) {
+            address_space_manager.unmap_virt_mem_region(
+                self.top_page_table_phys,
+                vma.start_virt,
+                vma.size_bytes,
+                frame_deallocator,
+            );
+        }
+    }
+
+    /// Changes the protection of every page in the range, which has to be covered by areas and
+    /// must not take in a stack guard. Returns false, changing nothing, when it does not hold.
+    pub fn protect(
+        &mut self,
+        start_virt: VirtAddr,
+        size_bytes: u64,
+        flags: PageTableFlags,
+        address_space_manager: &UserMemoryManager,
+        frame_allocator: &MemoryMapFrameAllocator,
+    ) -> bool {
+        if !self.vmas.covers(start_virt, size_bytes)
+            || self
+                .vmas
+                .overlaps_kind(start_virt, size_bytes, VmaKind::Guard)
+        {
+            return false;
+        }
+        self.vmas.protect(start_virt, size_bytes, flags);
+        address_space_manager.protect_virt_mem_region(
+            self.top_page_table_phys,
+            start_virt,
+            size_bytes,
+            flags,
+            frame_allocator,
+        );
+        true
+    }
+
+    /// Creates a copy of this layout in a new address space, both share their pages
+    /// copy-on-write until one of them writes to a page
+    pub fn fork(
+        &self,
+        address_space_manager: &UserMemoryManager,
+        frame_allocator: &mut MemoryMapFrameAllocator,
+    ) -> Result<Self, MapToError<Size4KiB>> {
+        let top_page_table_phys = address_space_manager
+            .clone_address_space_cow(self.top_page_table_phys, frame_allocator)?;
+
+        Ok(Self {
+            top_page_table_phys,
+            ..self.clone()
+        })
+    }
+
+    /// Backs the page containing `addr` if it lies in a lazy area which allows the access,
+    /// or gives a written copy-on-write page its own frame. Called on page faults from ring 3,
+    /// any other fault on an already mapped page is a violation.
+    pub fn handle_page_fault(
+        &mut self,
+        addr: VirtAddr,
+        write: bool,
+        address_space_manager: &UserMemoryManager,
+        frame_allocator: &mut MemoryMapFrameAllocator,
+    ) -> Result<(), PageFaultError> {
+        let Some(vma) = self.vma_containing(addr) else {
+            // a written copy-on-write page is the process's own even outside every area
+            if write && address_space_manager.is_page_mapped(self.top_page_table_phys, addr) {
+                match self.copy_on_write(addr, address_space_manager, frame_allocator) {
+                    Err(PageFaultError::AccessViolation(_)) => {}
+                    result => return result,
+                }
             }
-        } else if new_heap_end < self.heap_end {
-            let shrink_by = self.heap_end - new_heap_end;
-            self.heap_end = new_heap_end;
+            return Err(PageFaultError::NotInRegion(addr));
+        };
+        let lazy = vma.lazy;
+        if vma.kind == VmaKind::Guard {
+            return Err(PageFaultError::StackOverflow(addr));
+        }
+        if !vma.page_flags.contains(PageTableFlags::PRESENT)
+            || (write && !vma.page_flags.contains(PageTableFlags::WRITABLE))
+        {
+            return Err(PageFaultError::AccessViolation(addr));
+        }
+
+        if address_space_manager.is_page_mapped(self.top_page_table_phys, addr) {
+            return match write {
+                true => self.copy_on_write(addr, address_space_manager, frame_allocator),
+                false => Err(PageFaultError::AccessViolation(addr)),
+            };
+        }
+        if !lazy {
+            return Err(PageFaultError::AccessViolation(addr));
+        }
+
+        self.back_page(addr, address_space_manager, frame_allocator)
+    }
+
+    fn copy_on_write(
+        &self,
+        addr: VirtAddr,
+        address_space_manager: &UserMemoryManager,
+        frame_allocator: &mut MemoryMapFrameAllocator,
+    ) -> Result<(), PageFaultError> {
+        match address_space_manager.resolve_copy_on_write(
+            self.top_page_table_phys,
+            addr,
+            frame_allocator,
+        ) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(PageFaultError::AccessViolation(addr)),
+            Err(_) => Err(PageFaultError::OutOfMemory),
+        }
+    }
+
+    /// Backs every not yet touched lazy page in the range, so the kernel can copy to and from it.
+    /// With `write` copy-on-write pages in the range get their own frames as well.
+    /// Parts of the range outside any area are left alone.
+    pub fn populate(
+        &mut self,
+        start: VirtAddr,
+        len: u64,
+        write: bool,
+        address_space_manager: &UserMemoryManager,
+        frame_allocator: &mut MemoryMapFrameAllocator,
+    ) -> Result<(), PageFaultError> {
+        if len == 0 {
+            return Ok(());
+        }
+        let end = start.as_u64().saturating_add(len);
+        let mut page = start.align_down(PAGE_SIZE as u64);
+        while page.as_u64() < end {
+            let lazy = self
+                .vma_containing(page)
+                .is_some_and(|vma| vma.lazy && vma.page_flags.contains(PageTableFlags::PRESENT));
+            if address_space_manager.is_page_mapped(self.top_page_table_phys, page) {
+                // a mapped page which is not copy-on-write is left for the copy to check
+                if write
+                    && self.copy_on_write(page, address_space_manager, frame_allocator)
+                        == Err(PageFaultError::OutOfMemory)
+                {
+                    return Err(PageFaultError::OutOfMemory);
+                }
+            } else if lazy {
+                self.back_page(page, address_space_manager, frame_allocator)?;
+            }
+            page += PAGE_SIZE as u64;
+        }
+        Ok(())
+    }
+
+    fn back_page(
+        &mut self,
+        addr: VirtAddr,
+        address_space_manager: &UserMemoryManager,
+        frame_allocator: &mut MemoryMapFrameAllocator,
+    ) -> Result<(), PageFaultError> {
+        let flags = self
+            .vma_containing(addr)
+            .ok_or(PageFaultError::NotInRegion(addr))?
+            .page_flags;
+        address_space_manager
+            .map_virt_mem_region(
+                self.top_page_table_phys,
+                addr.align_down(PAGE_SIZE as u64),
+                PAGE_SIZE as u64,
+                flags,
+                frame_allocator,
+            )
+            .map_err(|_| PageFaultError::OutOfMemory)
+    }
+
+    /// Bytes of address space the heap would take up with its end at `heap_end`
+    pub fn heap_size_at(&self, heap_end: VirtAddr) -> u64 {
+        align_to_page_size(heap_end.as_u64().saturating_sub(self.heap_start.as_u64()))
+    }
+
+    /// Moves the end of the heap. Growing only reserves the memory, shrinking unmaps the pages
+    /// past the new end and frees their frames. Returns false, changing nothing, when the end
+    /// would go below the start of the heap or the heap would run into another area.
+    pub fn set_heap_end(
+        &mut self,
+        new_heap_end: VirtAddr,
+        address_space_manager: &UserMemoryManager,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) -> bool {
+        if new_heap_end < self.heap_start {
+            return false;
+        }
+        let mapped_end = self.heap_start + self.heap_size_at(self.heap_end);
+        let new_mapped_end = self.heap_start + self.heap_size_at(new_heap_end);
 
-            serial_println!(
-                "ProcessMemoryLayout: grow_heap: Shrunk heap by {} bytes (new heap_end: {})",
+        if new_mapped_end > mapped_end {
+            let grow_by = new_mapped_end - mapped_end;
+            if self.vmas.overlaps(mapped_end, grow_by) {
+                return false;
+            }
+            self.reserve_lazy(mapped_end, grow_by, data_page_flags(), VmaKind::Heap);
+        } else if new_mapped_end < mapped_end {
+            let shrink_by = mapped_end - new_mapped_end;
+            self.unmap(
+                new_mapped_end,
                 shrink_by,
-                self.heap_end.as_u64()
+                address_space_manager,
+                frame_deallocator,
+            );
+        }
+
+        serial_println!(
+            "ProcessMemoryLayout: set_heap_end: heap end moved from {:#x} to {:#x}",
+            self.heap_end.as_u64(),
+            new_heap_end.as_u64()
+        );
+        self.heap_end = new_heap_end;
+        true
+    }
+
+    /// Bytes of address space taken up by all areas but guards, whether their pages are
+    /// backed yet or not
+    pub fn mapped_bytes(&self) -> u64 {
+        self.vmas
+            .iter()
+            .filter(|vma| vma.kind != VmaKind::Guard)
+            .map(|vma| vma.size_bytes)
+            .sum()
+    }
+
+    /// Unmaps every area and frees the address space, the layout is left empty.
+    /// The address space must not be active. Releasing twice does nothing.
+    pub fn release(
+        &mut self,
+        address_space_manager: &UserMemoryManager,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) {
+        if self.top_page_table_phys.is_null() {
+            return;
+        }
+
+        for vma in self.vmas.take_all() {
+            address_space_manager.unmap_virt_mem_region(
+                self.top_page_table_phys,
+                vma.start_virt,
+                vma.size_bytes,
+                frame_deallocator,
             );
         }
+        address_space_manager.free_address_space(self.top_page_table_phys, frame_deallocator);
 
-        Ok(self.heap_end)
+        self.top_page_table_phys = PhysAddr::zero();
+        self.stack_top = VirtAddr::new(0);
+        self.stack_size = 0;
+        self.heap_end = self.heap_start;
     }
 }
@@ -1 +1,260 @@
-//TODO: Priority-based, preemptive; TSC-Deadline timer
+use crate::memory::{try_get_frame_allocator, try_get_user_mem_mgr};
+use crate::process::{
+    ProcessManager,
+    execution::InterruptFrame,
+    process_manager::{ARCHE_PID, PROCESS_MANAGER},
+    signal,
+    task::PID,
+};
+use crate::serial_println;
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const SCHEDULER_DEBUG_PRINT: bool = false;
+
+/// Number of timer ticks a task may run before it gets preempted, unless its
+/// `ProcessResources::cpu_time_slice` says otherwise
+pub const SCHEDULER_QUANTUM_TICKS: u64 = 10;
+
+/// Every this many ticks all processes go back to their base priority, so demoted tasks don't starve
+pub const SCHEDULER_BOOST_TICKS: u64 = 200;
+
+static SCHEDULER_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Why the scheduler got invoked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScheduleReason {
+    /// Timer tick, the current task is switched out once its quantum is over or a higher priority
+    /// task is ready
+    Tick,
+    /// The current task gives up the rest of its quantum
+    Yield,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ReadyTask {
+    pid: PID,
+    priority: u8,
+}
+
+/// Multi-level feedback queue. Higher priorities run first, tasks with the same priority are
+/// served round-robin. Tasks which use up their whole quantum get demoted by one level.
+/// Only PIDs are stored here, the process state itself lives in the `ProcessManager`.
+pub struct Scheduler {
+    ready_queue: VecDeque<ReadyTask>,
+    current_pid: Option<PID>,
+    ticks_left: u64,
+    ticks_until_boost: u64,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            ready_queue: VecDeque::with_capacity(16),
+            current_pid: None,
+            ticks_left: SCHEDULER_QUANTUM_TICKS,
+            ticks_until_boost: SCHEDULER_BOOST_TICKS,
+        }
+    }
+
+    pub fn current_pid(&self) -> Option<PID> {
+        self.current_pid
+    }
+
+    /// `time_slice` is in timer ticks, 0 selects the default quantum
+    pub fn set_current(&mut self, pid: PID, time_slice: u64) {
+        self.current_pid = Some(pid);
+        self.ticks_left = if time_slice == 0 {
+            SCHEDULER_QUANTUM_TICKS
+        } else {
+            time_slice
+        };
+    }
+
+    pub fn enqueue(&mut self, pid: PID, priority: u8) {
+        match self.ready_queue.iter_mut().find(|t| t.pid == pid) {
+            Some(task) => task.priority = priority,
+            None => self.ready_queue.push_back(ReadyTask { pid, priority }),
+        }
+    }
+
+    /// Makes room for `tasks` queued tasks, `enqueue` does not allocate until there are more
+    pub fn reserve(&mut self, tasks: usize) {
+        self.ready_queue
+            .reserve(tasks.saturating_sub(self.ready_queue.len()));
+    }
+
+    pub fn remove(&mut self, pid: PID) {
+        self.ready_queue.retain(|t| t.pid != pid);
+    }
+
+    /// Takes the oldest task out of the highest non-empty priority level
+    pub fn pick_next(&mut self) -> Option<PID> {
+        let priority = self.highest_ready_priority()?;
+        let idx = self
+            .ready_queue
+            .iter()
+            .position(|t| t.priority == priority)?;
+        self.ready_queue.remove(idx).map(|t| t.pid)
+    }
+
+    pub fn highest_ready_priority(&self) -> Option<u8> {
+        self.ready_queue.iter().map(|t| t.priority).max()
+    }
+
+    pub fn ready_count(&self) -> usize {
+        self.ready_queue.len()
+    }
+
+    /// Accounts one timer tick to the current task, returns true when its quantum is used up
+    pub fn tick(&mut self) -> bool {
+        self.ticks_left = self.ticks_left.saturating_sub(1);
+        self.ticks_left == 0
+    }
+
+    /// Returns true when it is time to reset every task to its base priority
+    pub fn boost_due(&mut self) -> bool {
+        self.ticks_until_boost = self.ticks_until_boost.saturating_sub(1);
+        if self.ticks_until_boost == 0 {
+            self.ticks_until_boost = SCHEDULER_BOOST_TICKS;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Resets the priority of every queued task, `base_priority` maps a PID to its base priority
+    pub fn boost(&mut self, base_priority: impl Fn(PID) -> u8) {
+        for task in self.ready_queue.iter_mut() {
+            task.priority = base_priority(task.pid);
+        }
+    }
+}
+
+/// Marks the arche (kernel) task as running and starts preempting on timer ticks.
+/// Must be called after the memory globals are initialized.
+pub fn init() {
+    {
+        let mut pm = PROCESS_MANAGER.lock();
+        pm.scheduler.set_current(ARCHE_PID, 0);
+    }
+    SCHEDULER_ENABLED.store(true, Ordering::SeqCst);
+    serial_println!("Scheduler enabled");
+}
+
+pub fn is_enabled() -> bool {
+    SCHEDULER_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Called from an interrupt with the interrupted task's full register state.
+/// May rewrite `frame` (and CR3) so that the interrupt returns into a different process.
+pub fn on_interrupt(frame: &mut InterruptFrame, reason: ScheduleReason) {
+    if !is_enabled() {
+        return;
+    }
+
+    // the interrupted code might hold the lock, we will try again on the next tick
+    let Some(mut pm) = PROCESS_MANAGER.try_lock() else {
+        if SCHEDULER_DEBUG_PRINT {
+            serial_println!("scheduler: process manager locked, skipping {:?}", reason);
+        }
+        return;
+    };
+
+    pm.schedule(frame, reason);
+
+    if frame.is_user_mode() {
+        deliver_signal(&mut pm, frame);
+    }
+}
+
+/// Delivers a pending signal to the task the interrupt returns to in ring 3. The memory locks
+/// might be held by a task preempted in a syscall, then the signal waits for the next interrupt.
+fn deliver_signal(pm: &mut ProcessManager, frame: &mut InterruptFrame) {
+    let has_deliverable = pm
+        .scheduler
+        .current_pid()
+        .and_then(|pid| pm.get_process(pid).ok())
+        .is_some_and(|process| process.signals.has_deliverable());
+    if !has_deliverable {
+        return;
+    }
+
+    let fatal = {
+        let (Some(address_space_manager), Some(mut frame_allocator)) =
+            (try_get_user_mem_mgr(), try_get_frame_allocator())
+        else {
+            return;
+        };
+        pm.deliver_signal(frame, &address_space_manager, &mut frame_allocator)
+    };
+
+    if let Some(signal) = fatal
+        && let Some(pid) = pm.scheduler.current_pid()
+    {
+        serial_println!("PID {} killed by signal {}", pid, signal);
+        let _ = pm.terminate_process(pid, signal::exit_code(signal), false);
+        pm.schedule(frame, ScheduleReason::Yield);
+    }
+}
+
+/// Gives the rest of the current quantum to other ready tasks
+pub fn yield_now() {
+    if !is_enabled() {
+        return;
+    }
+
+    unsafe {
+        core::arch::asm!("int {vector}", vector = const crate::interrupts::InterruptIndex::Yield as u8);
+    }
+}
+
+/// Blocks the current task until the uptime reaches `deadline_ns`. When nothing else is ready
+/// the CPU halts until the next tick instead of spinning.
+pub fn sleep_until(deadline_ns: u64) {
+    while crate::interrupts::system_uptime_ns() < deadline_ns {
+        if is_enabled() {
+            {
+                let mut pm = PROCESS_MANAGER.lock();
+                if let Some(pid) = pm.scheduler.current_pid() {
+                    let _ = pm.sleep_until(pid, deadline_ns);
+                }
+            }
+            yield_now();
+        }
+        if crate::interrupts::system_uptime_ns() < deadline_ns {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    // the deadline may have passed on a tick which found the process manager locked
+    let mut pm = PROCESS_MANAGER.lock();
+    if let Some(pid) = pm.scheduler.current_pid() {
+        pm.cancel_sleep(pid);
+    }
+}
+
+pub fn current_pid() -> Option<PID> {
+    PROCESS_MANAGER.lock().scheduler.current_pid()
+}
+
+/// Terminates the current process and waits for the scheduler to switch away from it.
+pub fn exit_current(exit_code: i32, kill_children: bool) -> ! {
+    {
+        let mut pm = PROCESS_MANAGER.lock();
+        if let Some(pid) = pm.scheduler.current_pid()
+            && let Err(e) = pm.terminate_process(pid, exit_code, kill_children)
+        {
+            serial_println!("exit_current: failed to terminate PID {}: {:?}", pid, e);
+        }
+    }
+
+    x86_64::instructions::interrupts::enable();
+    crate::hlt_loop()
+}
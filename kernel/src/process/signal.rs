@@ -0,0 +1,412 @@
+use crate::memory::{
+    paging::MemoryMapFrameAllocator,
+    usermem::{USER_SPACE_END, UserMemoryManager},
+};
+use crate::process::{
+    execution::InterruptFrame,
+    syscall::SyscallFrame,
+    task::{Process, RFLAGS_DEFAULT},
+};
+use x86_64::VirtAddr;
+
+pub type Signal = usize;
+
+// signal numbers, the same as on Linux
+pub const SIGINT: Signal = 2;
+pub const SIGILL: Signal = 4;
+pub const SIGBUS: Signal = 7;
+pub const SIGFPE: Signal = 8;
+pub const SIGKILL: Signal = 9;
+pub const SIGSEGV: Signal = 11;
+pub const SIGPIPE: Signal = 13;
+pub const SIGTERM: Signal = 15;
+pub const SIGCHLD: Signal = 17;
+/// Signals are numbered from 1 up to, but not including, this
+pub const SIGNAL_COUNT: usize = 32;
+
+// `SignalAction::handler` values which are not addresses
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+// `SigProcMask` operations
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+/// Signals which can neither be caught, ignored nor blocked
+const UNCATCHABLE: u64 = 1 << SIGKILL;
+/// Every valid signal, bit 0 stands for no signal
+const ALL_SIGNALS: u64 = (1 << SIGNAL_COUNT) - 2;
+
+/// Bytes below the stack pointer the ABI lets functions use without moving it
+const RED_ZONE_SIZE: u64 = 128;
+/// Flags a `SignalContext` may set, the rest comes from `RFLAGS_DEFAULT`:
+/// CF, PF, AF, ZF, SF, DF and OF
+const USER_RFLAGS: u64 = 0xCD5;
+const DIRECTION_FLAG: u64 = 1 << 10;
+
+pub fn signal_bit(signal: Signal) -> u64 {
+    1 << signal
+}
+
+pub fn is_valid(signal: Signal) -> bool {
+    (1..SIGNAL_COUNT).contains(&signal)
+}
+
+/// Exit code of a process killed by `signal`, the way shells report it
+pub fn exit_code(signal: Signal) -> i32 {
+    128 + signal as i32
+}
+
+/// What a process does when it gets a signal, mirrored by `struct sigaction` in
+/// `user/libc/syscall.h`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SignalAction {
+    /// `SIG_DFL`, `SIG_IGN` or the address of the handler, which gets the signal as its argument
+    pub handler: u64,
+    /// Signals blocked on top of the handled one while the handler runs
+    pub mask: u64,
+    /// The handler returns into this, it has to call `SigReturn`
+    pub restorer: u64,
+}
+
+/// What happens to a signal once it is delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    Ignore,
+    Terminate,
+    Handle(SignalAction),
+}
+
+/// What is done with a signal nobody installed an action for
+fn default_disposition(signal: Signal) -> Disposition {
+    match signal {
+        SIGCHLD => Disposition::Ignore,
+        _ => Disposition::Terminate,
+    }
+}
+
+/// Signals of a process: which are waiting to be delivered, which are held back and what each
+/// of them does
+#[derive(Debug, Clone)]
+pub struct SignalState {
+    pending: u64,
+    blocked: u64,
+    actions: [SignalAction; SIGNAL_COUNT],
+}
+
+impl Default for SignalState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SignalState {
+    pub fn new() -> Self {
+        Self {
+            pending: 0,
+            blocked: 0,
+            actions: [SignalAction::default(); SIGNAL_COUNT],
+        }
+    }
+
+    pub fn pending(&self) -> u64 {
+        self.pending
+    }
+
+    pub fn blocked(&self) -> u64 {
+        self.blocked
+    }
+
+    /// Replaces the blocked signals and returns the old mask, `SIGKILL` is never blocked
+    pub fn set_blocked(&mut self, mask: u64) -> u64 {
+        core::mem::replace(&mut self.blocked, mask & ALL_SIGNALS & !UNCATCHABLE)
+    }
+
+    pub fn action(&self, signal: Signal) -> SignalAction {
+        self.actions[signal]
+    }
+
+    /// Installs `action` for `signal` and returns the previous one. Ignoring a signal drops it
+    /// if it is pending. Gives `None` for signals whose action can not be changed.
+    pub fn set_action(&mut self, signal: Signal, action: SignalAction) -> Option<SignalAction> {
+        if !is_valid(signal) || signal_bit(signal) & UNCATCHABLE != 0 {
+            return None;
+        }
+        let old = core::mem::replace(&mut self.actions[signal], action);
+        if self.disposition(signal) == Disposition::Ignore {
+            self.pending &= !signal_bit(signal);
+        }
+        Some(old)
+    }
+
+    pub fn disposition(&self, signal: Signal) -> Disposition {
+        match self.actions[signal].handler {
+            SIG_DFL => default_disposition(signal),
+            SIG_IGN => Disposition::Ignore,
+            _ => Disposition::Handle(self.actions[signal]),
+        }
+    }
+
+    /// Marks `signal` pending, ignored signals are dropped right away
+    pub fn raise(&mut self, signal: Signal) {
+        if self.disposition(signal) != Disposition::Ignore {
+            self.pending |= signal_bit(signal);
+        }
+    }
+
+    /// Raises `signal` for a fault of the process itself. Returning to the faulting instruction
+    /// would only fault again, so a blocked or ignored `signal` gets its default action back.
+    pub fn force(&mut self, signal: Signal) {
+        let bit = signal_bit(signal);
+        if self.blocked & bit != 0 || self.disposition(signal) == Disposition::Ignore {
+            self.blocked &= !bit;
+            self.actions[signal] = SignalAction::default();
+        }
+        self.pending |= bit;
+    }
+
+    /// Whether `signal` waits until it is unblocked instead of being delivered
+    pub fn is_blocked(&self, signal: Signal) -> bool {
+        self.blocked & signal_bit(signal) != 0
+    }
+
+    pub fn has_deliverable(&self) -> bool {
+        self.pending & !self.blocked != 0
+    }
+
+    /// Takes the lowest pending signal which is not blocked
+    pub fn take_deliverable(&mut self) -> Option<Signal> {
+        let deliverable = self.pending & !self.blocked;
+        if deliverable == 0 {
+            return None;
+        }
+        let signal = deliverable.trailing_zeros() as Signal;
+        self.pending &= !signal_bit(signal);
+        Some(signal)
+    }
+
+    /// State of a forked child, it keeps the actions and the mask but nothing is pending
+    pub fn fork(&self) -> Self {
+        Self {
+            pending: 0,
+            ..self.clone()
+        }
+    }
+
+    /// Handlers are gone with the old program after exec, they go back to the default action.
+    /// Ignored signals stay ignored.
+    pub fn reset_handlers(&mut self) {
+        for action in self.actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SignalAction::default();
+            }
+        }
+    }
+}
+
+/// User registers of the interrupted code, saved on its stack while a handler runs and put back
+/// by `SigReturn`. Mirrored by `struct sigcontext` in `user/libc/syscall.h`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SignalContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl SignalContext {
+    pub fn from_interrupt_frame(frame: &InterruptFrame) -> Self {
+        Self {
+            r15: frame.r15,
+            r14: frame.r14,
+            r13: frame.r13,
+            r12: frame.r12,
+            r11: frame.r11,
+            r10: frame.r10,
+            r9: frame.r9,
+            r8: frame.r8,
+            rbp: frame.rbp,
+            rdi: frame.rdi,
+            rsi: frame.rsi,
+            rdx: frame.rdx,
+            rcx: frame.rcx,
+            rbx: frame.rbx,
+            rax: frame.rax,
+            rip: frame.rip,
+            rflags: frame.rflags,
+            rsp: frame.rsp,
+        }
+    }
+
+    /// Context of the caller of the syscall described by `frame`, which returns `result`.
+    /// The `syscall` instruction left the return address in rcx and the flags in r11.
+    pub fn from_syscall_frame(frame: &SyscallFrame, result: u64) -> Self {
+        Self {
+            r15: frame.r15,
+            r14: frame.r14,
+            r13: frame.r13,
+            r12: frame.r12,
+            r11: frame.rflags,
+            r10: frame.arg4,
+            r9: frame.arg6,
+            r8: frame.arg5,
+            rbp: frame.rbp,
+            rdi: frame.arg1,
+            rsi: frame.arg2,
+            rdx: frame.arg3,
+            rcx: frame.user_rip,
+            rbx: frame.rbx,
+            rax: result,
+            rip: frame.user_rip,
+            rflags: frame.rflags,
+            rsp: frame.user_rsp,
+        }
+    }
+
+    /// Makes `frame` return to this context in ring 3. Only the arithmetic flags are taken from
+    /// the context, it may have been written by the process itself.
+    pub fn restore_into(&self, frame: &mut InterruptFrame) {
+        frame.r15 = self.r15;
+        frame.r14 = self.r14;
+        frame.r13 = self.r13;
+        frame.r12 = self.r12;
+        frame.r11 = self.r11;
+        frame.r10 = self.r10;
+        frame.r9 = self.r9;
+        frame.r8 = self.r8;
+        frame.rbp = self.rbp;
+        frame.rdi = self.rdi;
+        frame.rsi = self.rsi;
+        frame.rdx = self.rdx;
+        frame.rcx = self.rcx;
+        frame.rbx = self.rbx;
+        frame.rax = self.rax;
+        frame.rip = self.rip;
+        frame.rflags = (self.rflags & USER_RFLAGS) | RFLAGS_DEFAULT;
+        frame.rsp = self.rsp;
+        frame.cs = crate::gdt::get_user_code_selector().0 as u64;
+        frame.ss = crate::gdt::get_user_data_selector().0 as u64;
+    }
+
+    /// Whether the instruction and stack pointer are in userspace, returning to a kernel or
+    /// non-canonical address would fault in ring 0
+    pub fn is_user(&self) -> bool {
+        self.rip < USER_SPACE_END && self.rsp < USER_SPACE_END
+    }
+}
+
+/// Pushed on the user stack below the interrupted code's red zone. The handler is entered as if
+/// called from `restorer`, whose `SigReturn` finds the frame right above its stack pointer.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SignalFrame {
+    pub restorer: u64,
+    pub signal: u64,
+    /// Signals blocked before the handler ran, put back along with the registers
+    pub blocked: u64,
+    pub context: SignalContext,
+}
+
+impl SignalFrame {
+    /// Where the frame is found once the handler returned and the restorer made its syscall
+    pub fn address_at_sigreturn(user_rsp: u64) -> u64 {
+        user_rsp.wrapping_sub(size_of::<u64>() as u64)
+    }
+}
+
+/// What came of delivering the pending signals
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Nothing to deliver, or only ignored signals
+    Nothing,
+    /// The context now enters the handler of this signal
+    Handler(Signal),
+    /// The process has to be terminated by this signal
+    Fatal(Signal),
+}
+
+/// Delivers the next pending signal of `process` on its way back to ring 3 with `context`.
+/// A caught signal gets a `SignalFrame` on the user stack and `context` is pointed at its
+/// handler, one which can not be pushed kills the process with `SIGSEGV`.
+pub fn deliver(
+    process: &mut Process,
+    context: &mut SignalContext,
+    address_space_manager: &UserMemoryManager,
+    frame_allocator: &mut MemoryMapFrameAllocator,
+) -> Delivery {
+    while let Some(signal) = process.signals.take_deliverable() {
+        let action = match process.signals.disposition(signal) {
+            Disposition::Ignore => continue,
+            Disposition::Terminate => return Delivery::Fatal(signal),
+            Disposition::Handle(action) => action,
+        };
+
+        let blocked = process.signals.blocked();
+        let frame = SignalFrame {
+            restorer: action.restorer,
+            signal: signal as u64,
+            blocked,
+            context: *context,
+        };
+        let Some(frame_addr) = push_frame(process, &frame, address_space_manager, frame_allocator)
+        else {
+            return Delivery::Fatal(SIGSEGV);
+        };
+
+        process
+            .signals
+            .set_blocked(blocked | action.mask | signal_bit(signal));
+        context.rip = action.handler;
+        context.rsp = frame_addr;
+        context.rdi = signal as u64;
+        // functions are entered with the direction flag cleared
+        context.rflags &= !DIRECTION_FLAG;
+        return Delivery::Handler(signal);
+    }
+    Delivery::Nothing
+}
+
+/// Writes `frame` below the red zone of its context's stack, so that the stack pointer ends up
+/// 8 bytes off a 16 byte boundary, like right after a call. Returns the frame's address.
+fn push_frame(
+    process: &mut Process,
+    frame: &SignalFrame,
+    address_space_manager: &UserMemoryManager,
+    frame_allocator: &mut MemoryMapFrameAllocator,
+) -> Option<u64> {
+    let size = size_of::<SignalFrame>() as u64;
+    let below_red_zone = frame.context.rsp.checked_sub(RED_ZONE_SIZE + size)?;
+    let addr = VirtAddr::try_new((below_red_zone & !0xF).checked_sub(8)?).ok()?;
+
+    let layout = &mut process.memory_layout;
+    layout
+        .populate(addr, size, true, address_space_manager, frame_allocator)
+        .ok()?;
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            (frame as *const SignalFrame).cast::<u8>(),
+            size_of::<SignalFrame>(),
+        )
+    };
+    address_space_manager
+        .copy_to_user(layout.top_page_table_phys, addr, bytes)
+        .ok()?;
+    Some(addr.as_u64())
+}
@@ -1,16 +1,46 @@
+use crate::filesystem::sirius::{FileSystemError, SIRIUS, Sirius};
+use crate::interrupts::system_uptime_ns;
+use crate::memory::{
+    get_frame_allocator, get_user_mem_mgr,
+    paging::PAGE_SIZE,
+    usermem::{USER_MAPPABLE_END, USER_SPACE_END, USER_STACK_TOP, UserCopyError},
+};
 use crate::process::{
-    process_manager::{ARCHE_PID, PROCESS_MANAGER},
-    task::INVALID_PID,
+    execution::{InterruptFrame, return_to_user},
+    file_table::{FileObject, OpenFile, OpenFlags, SharedOpenFile},
+    initial_stack::MAX_ARGS_SIZE,
+    pipe,
+    process_manager::{ARCHE_PID, PROCESS_MANAGER, ProcessError},
+    process_mem::PageFaultError,
+    scheduler,
+    signal::{
+        self, Delivery, SIG_BLOCK, SIG_DFL, SIG_IGN, SIG_SETMASK, SIG_UNBLOCK, SignalAction,
+        SignalContext, SignalFrame,
+    },
+    task::{DEFAULT_USER_PRIORITY, ExecutionContext, INVALID_PID, PID, Process},
+    vma::{MapFlags, Protection, Vma, VmaKind},
 };
 use crate::serial_println;
 use crate::util::msr::msr_write;
+use alloc::{string::String, sync::Arc, vec::Vec};
 use core::arch::naked_asm;
+use core::mem::offset_of;
 use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, MutexGuard};
 use x86_64::{
-    VirtAddr,
-    registers::model_specific::{Efer, EferFlags},
+    PhysAddr, VirtAddr,
+    registers::model_specific::{Efer, EferFlags, KernelGsBase},
 };
 
+/// `WaitPid` flag, return 0 instead of blocking when no child has exited yet
+pub const WAIT_NO_HANG: usize = 1;
+
+// `Seek` origins
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+/// Errors reach userspace negated in RAX, any other value is the syscall's result
 #[repr(u32)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SyscallError {
@@ -20,9 +50,77 @@ pub enum SyscallError {
     OutOfMemory = 3,
     ProcessNotFound = 4,
     InvalidFd = 5,
+    InvalidArgument = 6,
+    FileNotFound = 7,
+    FileExists = 8,
+    NoSpace = 9,
+    IoError = 10,
+    NotSupported = 11,
+    /// Writing to a pipe whose read end is closed
+    BrokenPipe = 12,
+    /// The arguments and environment of `Exec` do not fit on the new program's stack
+    ArgumentListTooLong = 13,
     SyscallNotFound = 999,
 }
 
+impl From<ProcessError> for SyscallError {
+    fn from(value: ProcessError) -> Self {
+        match value {
+            ProcessError::ProcessNotFound
+            | ProcessError::ParentNotFound
+            | ProcessError::DoubleDelete => SyscallError::ProcessNotFound,
+            ProcessError::OutOfMemory => SyscallError::OutOfMemory,
+            ProcessError::ArgumentsTooLong => SyscallError::ArgumentListTooLong,
+            ProcessError::ElfLoadError(_) => SyscallError::InvalidArgument,
+            ProcessError::FileSystemError(e) => e.into(),
+        }
+    }
+}
+
+impl From<FileSystemError> for SyscallError {
+    fn from(value: FileSystemError) -> Self {
+        match value {
+            FileSystemError::NotFound => SyscallError::FileNotFound,
+            FileSystemError::FileExists => SyscallError::FileExists,
+            FileSystemError::PermissionDenied => SyscallError::PermissionDenied,
+            FileSystemError::NoSpace
+            | FileSystemError::DirectoryFull
+            | FileSystemError::FileSizeExceeded => SyscallError::NoSpace,
+            FileSystemError::InvalidPath
+            | FileSystemError::InvalidFilename
+            | FileSystemError::IsDirectory
+            | FileSystemError::NotDirectory
+            | FileSystemError::DirectoryNotEmpty => SyscallError::InvalidArgument,
+            FileSystemError::NotSupported => SyscallError::NotSupported,
+            FileSystemError::DiskOpError | FileSystemError::IoError => SyscallError::IoError,
+        }
+    }
+}
+
+pub type SyscallResult = Result<u64, SyscallError>;
+
+/// Value returned to userspace in RAX
+pub fn encode_syscall_result(result: SyscallResult) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(e) => (-(e as i64)) as u64,
+    }
+}
+
+/// Filled in by `GetProcessInfo`, mirrored by `struct process_info` in `user/libc/syscall.h`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessInfo {
+    pub pid: u64,
+    pub parent_pid: u64,
+    pub priority: u64,
+    pub state: u64,
+    pub memory_used: u64,
+    pub memory_limit: u64,
+    /// Only meaningful once the process is terminated
+    pub exit_code: i64,
+}
+
 pub enum SystemCall {
     CreateProcess {
         parent_pid: usize,
@@ -50,6 +148,7 @@ pub enum SystemCall {
         buffer_ptr: usize,
         n_bytes: usize,
     },
+    /// Grows the heap by `size` bytes, returns the start of the new memory
     Allocate {
         size: usize,
     },
@@ -73,6 +172,99 @@ pub enum SystemCall {
     },
     GetProcessInfo {
         pid: usize,
+        info_ptr: usize,
+    },
+    /// `pid` set to `INVALID_PID` waits for any child, a null `status_ptr` drops the exit code
+    WaitPid {
+        pid: usize,
+        status_ptr: usize,
+        flags: usize,
+    },
+    /// Returns the child's PID to the parent and 0 to the child
+    Fork,
+    /// Only returns on failure, `argv` and `envp` are null terminated arrays of C strings
+    Exec {
+        path_ptr: usize,
+        path_len: usize,
+        argv: usize,
+        envp: usize,
+    },
+    /// `flags` are `OpenFlags`, returns the new descriptor
+    Open {
+        path_ptr: usize,
+        path_len: usize,
+        flags: usize,
+    },
+    Close {
+        fd: usize,
+    },
+    /// Moves the cursor by `offset` relative to `whence`, returns the new position
+    Seek {
+        fd: usize,
+        offset: i64,
+        whence: usize,
+    },
+    /// Stores the read end and then the write end of a new pipe as two `i32`s at `fds_ptr`
+    Pipe {
+        fds_ptr: usize,
+    },
+    /// Makes `new_fd` refer to the same open file as `old_fd`, closing it first if needed
+    Dup2 {
+        old_fd: usize,
+        new_fd: usize,
+    },
+    /// Installs the `SignalAction` at `action_ptr` for `signal`, a null `action_ptr` leaves it
+    /// alone. The previous action is stored at `old_action_ptr` unless it is null.
+    SigAction {
+        signal: usize,
+        action_ptr: usize,
+        old_action_ptr: usize,
+    },
+    /// Made by a signal handler's restorer, resumes the code the signal interrupted
+    SigReturn,
+    /// Blocks, unblocks or replaces the blocked signals with `mask` depending on `how`,
+    /// returns the previous mask
+    SigProcMask {
+        how: usize,
+        mask: u64,
+    },
+    /// Sends `signal` to `pid`, signal 0 only checks that it could be sent
+    Kill {
+        pid: usize,
+        signal: usize,
+    },
+    /// Blocks for at least `nanoseconds`, handled signals run once it wakes up
+    Sleep {
+        nanoseconds: u64,
+    },
+    /// Returns the nanoseconds since boot
+    GetTime,
+    /// Maps `len` bytes with protection `prot` and returns where. Anonymous mappings are
+    /// zeroed, otherwise the file `fd` is read in starting at the page-aligned `offset`.
+    /// `addr` is only a hint unless `MapFlags::FIXED` is set.
+    Mmap {
+        addr: usize,
+        len: usize,
+        prot: usize,
+        flags: usize,
+        fd: usize,
+        offset: usize,
+    },
+    /// Unmaps every page in the range, pages which are not mapped are skipped
+    Munmap {
+        addr: usize,
+        len: usize,
+    },
+    /// Changes the protection of every page in the range, which has to be mapped
+    Mprotect {
+        addr: usize,
+        len: usize,
+        prot: usize,
+    },
+    /// Moves the end of the heap to `addr`, pages past it are freed. Returns the end of the
+    /// heap afterwards, with `addr` 0 only that.
+    Brk {
+        addr: usize,
     },
     Exit {
         return_code: u32,
@@ -80,6 +272,7 @@ pub enum SystemCall {
 }
 
 #[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SyscallNumber {
     CreateProcess = 0,
     TerminateProcess = 1,
@@ -93,9 +286,68 @@ pub enum SyscallNumber {
     UnloadFile = 9,
     CreateWindow = 10,
     GetProcessInfo = 11,
+    WaitPid = 12,
+    Fork = 13,
+    Exec = 14,
+    Open = 15,
+    Close = 16,
+    Seek = 17,
+    Pipe = 18,
+    Dup2 = 19,
+    SigAction = 20,
+    SigReturn = 21,
+    SigProcMask = 22,
+    Kill = 23,
+    Sleep = 24,
+    GetTime = 25,
+    Mmap = 26,
+    Munmap = 27,
+    Mprotect = 28,
+    Brk = 29,
     Exit = 999,
 }
 
+impl TryFrom<usize> for SyscallNumber {
+    type Error = SyscallError;
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(SyscallNumber::CreateProcess),
+            1 => Ok(SyscallNumber::TerminateProcess),
+            2 => Ok(SyscallNumber::Write),
+            3 => Ok(SyscallNumber::Read),
+            4 => Ok(SyscallNumber::GetLine),
+            5 => Ok(SyscallNumber::Allocate),
+            6 => Ok(SyscallNumber::CreateFile),
+            7 => Ok(SyscallNumber::RemoveFile),
+            8 => Ok(SyscallNumber::LoadFile),
+            9 => Ok(SyscallNumber::UnloadFile),
+            10 => Ok(SyscallNumber::CreateWindow),
+            11 => Ok(SyscallNumber::GetProcessInfo),
+            12 => Ok(SyscallNumber::WaitPid),
+            13 => Ok(SyscallNumber::Fork),
+            14 => Ok(SyscallNumber::Exec),
+            15 => Ok(SyscallNumber::Open),
+            16 => Ok(SyscallNumber::Close),
+            17 => Ok(SyscallNumber::Seek),
+            18 => Ok(SyscallNumber::Pipe),
+            19 => Ok(SyscallNumber::Dup2),
+            20 => Ok(SyscallNumber::SigAction),
+            21 => Ok(SyscallNumber::SigReturn),
+            22 => Ok(SyscallNumber::SigProcMask),
+            23 => Ok(SyscallNumber::Kill),
+            24 => Ok(SyscallNumber::Sleep),
+            25 => Ok(SyscallNumber::GetTime),
+            26 => Ok(SyscallNumber::Mmap),
+            27 => Ok(SyscallNumber::Munmap),
+            28 => Ok(SyscallNumber::Mprotect),
+            29 => Ok(SyscallNumber::Brk),
+            999 => Ok(SyscallNumber::Exit),
+            _ => Err(SyscallError::SyscallNotFound),
+        }
+    }
+}
+
 impl SystemCall {
     pub fn from_number_and_args(
         num: usize,
@@ -103,116 +355,1016 @@ impl SystemCall {
         arg2: usize,
         arg3: usize,
         arg4: usize,
-        _arg5: usize,
-        _arg6: usize,
+        arg5: usize,
+        arg6: usize,
     ) -> Option<Self> {
-        match num {
-            0 => Some(SystemCall::CreateProcess {
+        let call = match SyscallNumber::try_from(num).ok()? {
+            SyscallNumber::CreateProcess => SystemCall::CreateProcess {
                 parent_pid: arg1,
                 name_ptr: arg2 as *const u8,
                 name_len: arg3 as u8,
                 is_out: arg4 != 0,
-            }),
-            1 => Some(SystemCall::TerminateProcess {
+            },
+            SyscallNumber::TerminateProcess => SystemCall::TerminateProcess {
                 pid_to_kill: arg1,
                 exit_code: arg2 as i32,
                 kill_children: arg3 != 0,
-            }),
-            2 => Some(SystemCall::Write {
+            },
+            SyscallNumber::Write => SystemCall::Write {
                 fd: arg1,
                 buffer_ptr: arg2,
                 n_bytes: arg3,
-            }),
-            3 => Some(SystemCall::Read {
+            },
+            SyscallNumber::Read => SystemCall::Read {
                 fd: arg1,
                 buffer_ptr: arg2,
                 n_bytes: arg3,
-            }),
-            4 => Some(SystemCall::GetLine {
+            },
+            SyscallNumber::GetLine => SystemCall::GetLine {
                 fd: arg1,
                 buffer_ptr: arg2,
                 n_bytes: arg3,
-            }),
-            5 => Some(SystemCall::Allocate { size: arg1 }),
-            999 => Some(SystemCall::Exit {
+            },
+            SyscallNumber::Allocate => SystemCall::Allocate { size: arg1 },
+            SyscallNumber::CreateFile => SystemCall::CreateFile {
+                path_ptr: arg1,
+                path_len: arg2,
+            },
+            SyscallNumber::RemoveFile => SystemCall::RemoveFile {
+                path_ptr: arg1,
+                path_len: arg2,
+            },
+            SyscallNumber::LoadFile => SystemCall::LoadFile {
+                path_ptr: arg1,
+                path_len: arg2,
+            },
+            SyscallNumber::UnloadFile => SystemCall::UnloadFile { fd: arg1 },
+            SyscallNumber::CreateWindow => SystemCall::CreateWindow { process_id: arg1 },
+            SyscallNumber::GetProcessInfo => SystemCall::GetProcessInfo {
+                pid: arg1,
+                info_ptr: arg2,
+            },
+            SyscallNumber::WaitPid => SystemCall::WaitPid {
+                pid: arg1,
+                status_ptr: arg2,
+                flags: arg3,
+            },
+            SyscallNumber::Fork => SystemCall::Fork,
+            SyscallNumber::Exec => SystemCall::Exec {
+                path_ptr: arg1,
+                path_len: arg2,
+                argv: arg3,
+                envp: arg4,
+            },
+            SyscallNumber::Open => SystemCall::Open {
+                path_ptr: arg1,
+                path_len: arg2,
+                flags: arg3,
+            },
+            SyscallNumber::Close => SystemCall::Close { fd: arg1 },
+            SyscallNumber::Seek => SystemCall::Seek {
+                fd: arg1,
+                offset: arg2 as i64,
+                whence: arg3,
+            },
+            SyscallNumber::Pipe => SystemCall::Pipe { fds_ptr: arg1 },
+            SyscallNumber::Dup2 => SystemCall::Dup2 {
+                old_fd: arg1,
+                new_fd: arg2,
+            },
+            SyscallNumber::SigAction => SystemCall::SigAction {
+                signal: arg1,
+                action_ptr: arg2,
+                old_action_ptr: arg3,
+            },
+            SyscallNumber::SigReturn => SystemCall::SigReturn,
+            SyscallNumber::SigProcMask => SystemCall::SigProcMask {
+                how: arg1,
+                mask: arg2 as u64,
+            },
+            SyscallNumber::Kill => SystemCall::Kill {
+                pid: arg1,
+                signal: arg2,
+            },
+            SyscallNumber::Sleep => SystemCall::Sleep {
+                nanoseconds: arg1 as u64,
+            },
+            SyscallNumber::GetTime => SystemCall::GetTime,
+            SyscallNumber::Mmap => SystemCall::Mmap {
+                addr: arg1,
+                len: arg2,
+                prot: arg3,
+                flags: arg4,
+                fd: arg5,
+                offset: arg6,
+            },
+            SyscallNumber::Munmap => SystemCall::Munmap {
+                addr: arg1,
+                len: arg2,
+            },
+            SyscallNumber::Mprotect => SystemCall::Mprotect {
+                addr: arg1,
+                len: arg2,
+                prot: arg3,
+            },
+            SyscallNumber::Brk => SystemCall::Brk { addr: arg1 },
+            SyscallNumber::Exit => SystemCall::Exit {
                 return_code: arg1 as u32,
-            }),
-            _ => None,
-        }
+            },
+        };
+        Some(call)
     }
 }
 
-pub fn handle_syscall(pid: usize, call: SystemCall) -> Result<(), SyscallError> {
+/// Runs `call` on behalf of the process `pid`, `frame` holds its user registers.
+/// Takes the process manager lock only for as long as each call needs it,
+/// `Exit` never returns, a successful `Exec` returns into the new program and `SigReturn`
+/// into the code the signal interrupted.
+pub fn handle_syscall(pid: PID, call: SystemCall, frame: &mut SyscallFrame) -> SyscallResult {
     assert!(pid != INVALID_PID);
 
-    let mut pm = PROCESS_MANAGER.lock();
-
     match call {
         SystemCall::CreateProcess {
             parent_pid,
             name_ptr,
             name_len,
             is_out,
-        } => {
-            let priority = 0;
-            if pid != parent_pid && pid != ARCHE_PID {
-                return Err(SyscallError::PermissionDenied);
-            }
-            match pm.create_process(parent_pid, priority, name_ptr, name_len, is_out) {
-                Ok(new_pid) => {
-                    serial_println!("Created process with PID: {}", new_pid);
-                    Ok(())
-                }
-                Err(e) => {
-                    serial_println!("Failed to create process: {:?}", e);
-                    Err(SyscallError::ProcessNotFound)
-                }
-            }
-        }
+        } => sys_create_process(pid, parent_pid, name_ptr, name_len, is_out),
         SystemCall::TerminateProcess {
             pid_to_kill,
             exit_code,
             kill_children,
+        } => sys_terminate_process(pid, pid_to_kill, exit_code, kill_children),
+        SystemCall::Write {
+            fd,
+            buffer_ptr,
+            n_bytes,
+        } => sys_write(pid, fd, &copy_from_user(pid, buffer_ptr, n_bytes)?),
+        SystemCall::Read {
+            fd,
+            buffer_ptr,
+            n_bytes,
+        } => sys_read(pid, fd, buffer_ptr, n_bytes, false),
+        SystemCall::GetLine {
+            fd,
+            buffer_ptr,
+            n_bytes,
+        } => sys_read(pid, fd, buffer_ptr, n_bytes, true),
+        SystemCall::Allocate { size } => sys_allocate(pid, size),
+        SystemCall::CreateFile { path_ptr, path_len } => {
+            let path = user_string(pid, path_ptr, path_len)?;
+            sirius()?.create_file(&path)?;
+            Ok(0)
+        }
+        SystemCall::RemoveFile { path_ptr, path_len } => {
+            let path = user_string(pid, path_ptr, path_len)?;
+            sirius()?.delete(&path)?;
+            Ok(0)
+        }
+        SystemCall::LoadFile { path_ptr, path_len } => {
+            sys_open(pid, &user_string(pid, path_ptr, path_len)?, OpenFlags::READ)
+        }
+        SystemCall::UnloadFile { fd } => sys_close(pid, fd),
+        // TODO: the compositor is owned by the kernel's main loop, there is no way to hand a
+        // window buffer to a process yet
+        SystemCall::CreateWindow { .. } => Err(SyscallError::NotSupported),
+        SystemCall::GetProcessInfo {
+            pid: target_pid,
+            info_ptr,
+        } => sys_get_process_info(pid, target_pid, info_ptr),
+        SystemCall::WaitPid {
+            pid: child_pid,
+            status_ptr,
+            flags,
+        } => sys_wait_pid(pid, child_pid, status_ptr, flags),
+        SystemCall::Fork => sys_fork(pid, frame),
+        SystemCall::Exec {
+            path_ptr,
+            path_len,
+            argv,
+            envp,
         } => {
-            if pid != pid_to_kill {
-                return Err(SyscallError::PermissionDenied);
+            let path = user_string(pid, path_ptr, path_len)?;
+            let mut budget = MAX_ARGS_SIZE;
+            let argv = user_string_array(pid, argv, &mut budget)?;
+            let envp = user_string_array(pid, envp, &mut budget)?;
+            sys_exec(pid, &path, &argv, &envp, frame)
+        }
+        SystemCall::Open {
+            path_ptr,
+            path_len,
+            flags,
+        } => {
+            let flags = OpenFlags::from_bits(flags).ok_or(SyscallError::InvalidArgument)?;
+            sys_open(pid, &user_string(pid, path_ptr, path_len)?, flags)
+        }
+        SystemCall::Close { fd } => sys_close(pid, fd),
+        SystemCall::Seek { fd, offset, whence } => sys_seek(pid, fd, offset, whence),
+        SystemCall::Pipe { fds_ptr } => sys_pipe(pid, fds_ptr),
+        SystemCall::Dup2 { old_fd, new_fd } => sys_dup2(pid, old_fd, new_fd),
+        SystemCall::SigAction {
+            signal,
+            action_ptr,
+            old_action_ptr,
+        } => sys_sigaction(pid, signal, action_ptr, old_action_ptr),
+        SystemCall::SigReturn => sys_sigreturn(pid, frame),
+        SystemCall::SigProcMask { how, mask } => sys_sigprocmask(pid, how, mask),
+        SystemCall::Kill {
+            pid: target_pid,
+            signal,
+        } => sys_kill(pid, target_pid, signal),
+        SystemCall::Sleep { nanoseconds } => sys_sleep(nanoseconds),
+        SystemCall::GetTime => Ok(system_uptime_ns()),
+        SystemCall::Mmap {
+            addr,
+            len,
+            prot,
+            flags,
+            fd,
+            offset,
+        } => sys_mmap(pid, addr, len, prot, flags, fd, offset),
+        SystemCall::Munmap { addr, len } => sys_munmap(pid, addr, len),
+        SystemCall::Mprotect { addr, len, prot } => sys_mprotect(pid, addr, len, prot),
+        SystemCall::Brk { addr } => sys_brk(pid, addr),
+        SystemCall::Exit { return_code } => {
+            serial_println!("Process {} exited with code {}", pid, return_code as i32);
+            scheduler::exit_current(return_code as i32, false)
+        }
+    }
+}
+
+fn sys_create_process(
+    pid: PID,
+    parent_pid: PID,
+    path_ptr: *const u8,
+    path_len: u8,
+    is_out: bool,
+) -> SyscallResult {
+    if pid != parent_pid && pid != ARCHE_PID {
+        return Err(SyscallError::PermissionDenied);
+    }
+    let path = user_string(pid, path_ptr as usize, path_len as usize)?;
+
+    let mut pm = PROCESS_MANAGER.lock();
+    // children never outrank their parent
+    let priority = pm
+        .get_process(parent_pid)?
+        .priority
+        .min(DEFAULT_USER_PRIORITY);
+    match pm.create_process(parent_pid, priority, &path, is_out, &[&path]) {
+        Ok(new_pid) => {
+            serial_println!("Created process {} with PID: {}", path, new_pid);
+            Ok(new_pid as u64)
+        }
+        Err(e) => {
+            serial_println!("Failed to create process {}: {:?}", path, e);
+            Err(e.into())
+        }
+    }
+}
+
+/// A process may terminate itself or one of its children
+fn sys_terminate_process(
+    pid: PID,
+    pid_to_kill: PID,
+    exit_code: i32,
+    kill_children: bool,
+) -> SyscallResult {
+    if pid_to_kill == pid {
+        scheduler::exit_current(exit_code, kill_children);
+    }
+    if pid_to_kill == INVALID_PID {
+        return Err(SyscallError::ProcessNotFound);
+    }
+
+    let mut pm = PROCESS_MANAGER.lock();
+    if pm.get_process(pid_to_kill)?.parent_pid != pid {
+        return Err(SyscallError::PermissionDenied);
+    }
+
+    match pm.terminate_process(pid_to_kill, exit_code, kill_children) {
+        Ok(_) => {
+            serial_println!("Terminated process, PID: {}", pid_to_kill);
+            Ok(0)
+        }
+        Err(e) => {
+            serial_println!("Failed to terminate process: {:?}", e);
+            Err(e.into())
+        }
+    }
+}
+
+fn sys_write(pid: PID, fd: usize, data: &[u8]) -> SyscallResult {
+    let written = open_file(pid, fd)?.lock().write(data)?;
+    Ok(written as u64)
+}
+
+/// With `line` set, stops after the first newline, which is included in the result
+fn sys_read(pid: PID, fd: usize, buffer_ptr: usize, n_bytes: usize, line: bool) -> SyscallResult {
+    let file = open_file(pid, fd)?;
+    let mut buffer = kernel_buffer(n_bytes)?;
+    let read = file.lock().read(&mut buffer, line)?;
+    copy_to_user(pid, buffer_ptr, &buffer[..read])?;
+    Ok(read as u64)
+}
+
+/// Grows the process heap by `size` bytes, returns the start of the new memory
+fn sys_allocate(pid: PID, size: usize) -> SyscallResult {
+    if size == 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let mut pm = PROCESS_MANAGER.lock();
+    let process = pm.get_process_mut(pid)?;
+    let start = process.memory_layout.heap_end;
+    let new_end = start
+        .as_u64()
+        .checked_add(size as u64)
+        .ok_or(SyscallError::OutOfMemory)?;
+    set_heap_end(process, new_end)?;
+    Ok(start.as_u64())
+}
+
+fn sys_brk(pid: PID, addr: usize) -> SyscallResult {
+    let mut pm = PROCESS_MANAGER.lock();
+    let process = pm.get_process_mut(pid)?;
+    if addr != 0 {
+        set_heap_end(process, addr as u64)?;
+    }
+    Ok(process.memory_layout.heap_end.as_u64())
+}
+
+/// Moves the end of the heap within what the stack and the memory limit leave room for
+fn set_heap_end(process: &mut Process, new_end: u64) -> Result<(), SyscallError> {
+    let layout = &process.memory_layout;
+    if new_end >= USER_STACK_TOP - layout.stack_size {
+        return Err(SyscallError::OutOfMemory);
+    }
+    let new_end = VirtAddr::new(new_end);
+    let grow_by = layout
+        .heap_size_at(new_end)
+        .saturating_sub(layout.heap_size_at(layout.heap_end));
+    if process.exceeds_memory_limit(grow_by) {
+        return Err(SyscallError::OutOfMemory);
+    }
+
+    let moved = process.memory_layout.set_heap_end(
+        new_end,
+        &get_user_mem_mgr(),
+        &mut *get_frame_allocator(),
+    );
+    process.account_memory();
+    match moved {
+        true => Ok(()),
+        false => Err(SyscallError::InvalidArgument),
+    }
+}
+
+fn sys_open(pid: PID, path: &str, flags: OpenFlags) -> SyscallResult {
+    if !flags.intersects(OpenFlags::READ | OpenFlags::WRITE) {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let node = {
+        let mut sirius = sirius()?;
+        match sirius.open_file(path) {
+            Err(FileSystemError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+                sirius.create_file(path)?
+            }
+            result => result?,
+        }
+    };
+
+    let file = Arc::new(Mutex::new(OpenFile::file(node, flags)));
+    let mut pm = PROCESS_MANAGER.lock();
+    let fd = pm
+        .get_process_mut(pid)?
+        .file_descriptors
+        .insert(file)
+        .ok_or(SyscallError::NoSpace)?;
+    Ok(fd as u64)
+}
+
+fn sys_close(pid: PID, fd: usize) -> SyscallResult {
+    let mut pm = PROCESS_MANAGER.lock();
+    pm.get_process_mut(pid)?
+        .file_descriptors
+        .remove(fd)
+        .ok_or(SyscallError::InvalidFd)?;
+    Ok(0)
+}
+
+fn sys_seek(pid: PID, fd: usize, offset: i64, whence: usize) -> SyscallResult {
+    let file = open_file(pid, fd)?;
+    let mut file = file.lock();
+    let base = match whence {
+        SEEK_SET => 0,
+        SEEK_CUR => file.offset,
+        SEEK_END => file.size()?,
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    file.offset = base
+        .checked_add_signed(offset as isize)
+        .ok_or(SyscallError::InvalidArgument)?;
+    Ok(file.offset as u64)
+}
+
+fn sys_get_process_info(pid: PID, target_pid: PID, info_ptr: usize) -> SyscallResult {
+    if target_pid == INVALID_PID {
+        return Err(SyscallError::ProcessNotFound);
+    }
+    let info = {
+        let pm = PROCESS_MANAGER.lock();
+        let process = pm.get_process(target_pid)?;
+        ProcessInfo {
+            pid: process.pid as u64,
+            parent_pid: process.parent_pid as u64,
+            priority: process.priority as u64,
+            state: process.state as u64,
+            memory_used: process.resources.memory_used as u64,
+            memory_limit: process.resources.memory_limit as u64,
+            exit_code: process.exit_code.unwrap_or(0) as i64,
+        }
+    };
+
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            (&info as *const ProcessInfo).cast::<u8>(),
+            size_of::<ProcessInfo>(),
+        )
+    };
+    copy_to_user(pid, info_ptr, bytes)?;
+    Ok(0)
+}
+
+/// Blocks until a child exits unless `WAIT_NO_HANG` is set, returns the reaped child's PID
+/// or 0 when there was nothing to reap yet
+fn sys_wait_pid(pid: PID, child_pid: PID, status_ptr: usize, flags: usize) -> SyscallResult {
+    if flags & !WAIT_NO_HANG != 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let wanted = (child_pid != INVALID_PID).then_some(child_pid);
+
+    let status = loop {
+        {
+            let mut pm = PROCESS_MANAGER.lock();
+            if let Some(reaped) = pm.reap_child(pid, wanted)? {
+                break reaped;
+            }
+            if flags & WAIT_NO_HANG != 0 {
+                return Ok(0);
             }
-            match pm.terminate_process(pid_to_kill, exit_code, kill_children) {
-                Ok(_) => {
-                    serial_println!("Terminated process, PID: {}", pid_to_kill);
-                    Ok(())
-                }
-                Err(e) => {
-                    serial_println!("Failed to terminate process: {:?}", e);
-                    Err(SyscallError::ProcessNotFound)
-                }
+            // woken up by terminate_process once one of the children exits
+            pm.wait_for_child(pid)?;
+        }
+        scheduler::yield_now();
+    };
+
+    if status_ptr != 0 {
+        copy_to_user(pid, status_ptr, &status.exit_code.to_ne_bytes())?;
+    }
+    Ok(status.pid as u64)
+}
+
+fn sys_fork(pid: PID, frame: &SyscallFrame) -> SyscallResult {
+    let mut pm = PROCESS_MANAGER.lock();
+    // the child returns from the same syscall, with 0 in rax
+    let context = ExecutionContext::from_syscall_frame(frame, 0);
+    match pm.fork_process(pid, context) {
+        Ok(child_pid) => {
+            serial_println!("Forked process {} into PID: {}", pid, child_pid);
+            Ok(child_pid as u64)
+        }
+        Err(e) => {
+            serial_println!("Failed to fork process {}: {:?}", pid, e);
+            Err(e.into())
+        }
+    }
+}
+
+/// Replaces the program of `pid` and points `frame` at its entry point,
+/// so the syscall returns into the new program with cleared registers
+fn sys_exec(
+    pid: PID,
+    path: &str,
+    argv: &[String],
+    envp: &[String],
+    frame: &mut SyscallFrame,
+) -> SyscallResult {
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    let envp: Vec<&str> = envp.iter().map(String::as_str).collect();
+    let context = match PROCESS_MANAGER.lock().exec_process(pid, path, &argv, &envp) {
+        Ok(context) => context,
+        Err(e) => {
+            serial_println!("Failed to exec {} in process {}: {:?}", path, pid, e);
+            return Err(e.into());
+        }
+    };
+    serial_println!("Process {} now runs {}", pid, path);
+
+    *frame = SyscallFrame {
+        rflags: context.rflags,
+        user_rip: context.rip,
+        user_rsp: context.rsp,
+        ..SyscallFrame::default()
+    };
+    Ok(0)
+}
+
+fn sirius() -> Result<MutexGuard<'static, Sirius>, SyscallError> {
+    Ok(SIRIUS.get().ok_or(SyscallError::NotSupported)?.lock())
+}
+
+fn sys_pipe(pid: PID, fds_ptr: usize) -> SyscallResult {
+    let (reader, writer) = pipe::pipe();
+    let reader = Arc::new(Mutex::new(OpenFile::pipe_reader(reader)));
+    let writer = Arc::new(Mutex::new(OpenFile::pipe_writer(writer)));
+
+    let fds = {
+        let mut pm = PROCESS_MANAGER.lock();
+        let table = &mut pm.get_process_mut(pid)?.file_descriptors;
+        let read_fd = table.insert(reader).ok_or(SyscallError::NoSpace)?;
+        let Some(write_fd) = table.insert(writer) else {
+            table.remove(read_fd);
+            return Err(SyscallError::NoSpace);
+        };
+        [read_fd as i32, write_fd as i32]
+    };
+
+    let mut bytes = [0u8; 2 * size_of::<i32>()];
+    bytes[..4].copy_from_slice(&fds[0].to_ne_bytes());
+    bytes[4..].copy_from_slice(&fds[1].to_ne_bytes());
+    if let Err(e) = copy_to_user(pid, fds_ptr, &bytes) {
+        let mut pm = PROCESS_MANAGER.lock();
+        let table = &mut pm.get_process_mut(pid)?.file_descriptors;
+        table.remove(fds[0] as usize);
+        table.remove(fds[1] as usize);
+        return Err(e);
+    }
+    Ok(0)
+}
+
+fn sys_dup2(pid: PID, old_fd: usize, new_fd: usize) -> SyscallResult {
+    let mut pm = PROCESS_MANAGER.lock();
+    let table = &mut pm.get_process_mut(pid)?.file_descriptors;
+    let file = table.get(old_fd).ok_or(SyscallError::InvalidFd)?;
+    if old_fd != new_fd && !table.set(new_fd, file) {
+        return Err(SyscallError::InvalidFd);
+    }
+    Ok(new_fd as u64)
+}
+
+fn sys_sigaction(
+    pid: PID,
+    signal: usize,
+    action_ptr: usize,
+    old_action_ptr: usize,
+) -> SyscallResult {
+    if !signal::is_valid(signal) {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let action = match action_ptr {
+        0 => None,
+        _ => {
+            let bytes = copy_from_user(pid, action_ptr, size_of::<SignalAction>())?;
+            let action: SignalAction = unsafe { core::ptr::read_unaligned(bytes.as_ptr().cast()) };
+            // the handler is entered and left through sysret or iretq, which fault in ring 0
+            // on addresses outside of userspace
+            let catches = action.handler != SIG_DFL && action.handler != SIG_IGN;
+            if catches
+                && (action.handler >= USER_SPACE_END
+                    || action.restorer == 0
+                    || action.restorer >= USER_SPACE_END)
+            {
+                return Err(SyscallError::InvalidArgument);
             }
+            Some(action)
+        }
+    };
+
+    let old_action = {
+        let mut pm = PROCESS_MANAGER.lock();
+        let signals = &mut pm.get_process_mut(pid)?.signals;
+        match action {
+            Some(action) => signals
+                .set_action(signal, action)
+                .ok_or(SyscallError::InvalidArgument)?,
+            None => signals.action(signal),
+        }
+    };
+
+    if old_action_ptr != 0 {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                (&old_action as *const SignalAction).cast::<u8>(),
+                size_of::<SignalAction>(),
+            )
+        };
+        copy_to_user(pid, old_action_ptr, bytes)?;
+    }
+    Ok(0)
+}
+
+/// Resumes the context saved by `signal::deliver`, the handler returned into its restorer
+/// which made this call right above the `SignalFrame`. A frame which does not hold a ring 3
+/// context kills the process, there is nothing left to return to.
+fn sys_sigreturn(pid: PID, frame: &SyscallFrame) -> SyscallResult {
+    let frame_addr = SignalFrame::address_at_sigreturn(frame.user_rsp);
+    let signal_frame = copy_from_user(pid, frame_addr as usize, size_of::<SignalFrame>())
+        .map(|bytes| unsafe { core::ptr::read_unaligned(bytes.as_ptr().cast::<SignalFrame>()) });
+    let Some(signal_frame) = signal_frame.ok().filter(|f| f.context.is_user()) else {
+        serial_println!("PID {} returned from a signal with a broken frame", pid);
+        scheduler::exit_current(signal::exit_code(signal::SIGSEGV), false);
+    };
+
+    // iretq on the way out, sysret could not restore rcx and r11
+    let mut interrupt_frame = InterruptFrame::default();
+    signal_frame.context.restore_into(&mut interrupt_frame);
+    let fatal = {
+        let mut pm = PROCESS_MANAGER.lock();
+        pm.get_process_mut(pid)?
+            .signals
+            .set_blocked(signal_frame.blocked);
+        pm.deliver_signal(
+            &mut interrupt_frame,
+            &get_user_mem_mgr(),
+            &mut get_frame_allocator(),
+        )
+    };
+    if let Some(signal) = fatal {
+        serial_println!("PID {} killed by signal {}", pid, signal);
+        scheduler::exit_current(signal::exit_code(signal), false);
+    }
+
+    unsafe { return_to_user(&interrupt_frame) }
+}
+
+fn sys_sigprocmask(pid: PID, how: usize, mask: u64) -> SyscallResult {
+    let mut pm = PROCESS_MANAGER.lock();
+    let signals = &mut pm.get_process_mut(pid)?.signals;
+    let blocked = match how {
+        SIG_BLOCK => signals.blocked() | mask,
+        SIG_UNBLOCK => signals.blocked() & !mask,
+        SIG_SETMASK => mask,
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    // signals unblocked here are delivered on the way back
+    Ok(signals.set_blocked(blocked))
+}
+
+/// A process may signal itself or one of its children
+fn sys_kill(pid: PID, target_pid: PID, signal: usize) -> SyscallResult {
+    if signal != 0 && !signal::is_valid(signal) {
+        return Err(SyscallError::InvalidArgument);
+    }
+    if target_pid == INVALID_PID {
+        return Err(SyscallError::ProcessNotFound);
+    }
+
+    let mut pm = PROCESS_MANAGER.lock();
+    if target_pid != pid && pm.get_process(target_pid)?.parent_pid != pid {
+        return Err(SyscallError::PermissionDenied);
+    }
+    if signal != 0 {
+        pm.send_signal(target_pid, signal)?;
+    }
+    Ok(0)
+}
+
+fn sys_sleep(nanoseconds: u64) -> SyscallResult {
+    let deadline = system_uptime_ns().saturating_add(nanoseconds);
+    scheduler::sleep_until(deadline);
+    Ok(0)
+}
+
+/// Page-aligned user range of `len` bytes rounded up to whole pages
+fn page_range(addr: usize, len: usize) -> Result<(VirtAddr, u64), SyscallError> {
+    let size = (len as u64)
+        .checked_next_multiple_of(PAGE_SIZE as u64)
+        .filter(|size| *size > 0)
+        .ok_or(SyscallError::InvalidArgument)?;
+    if !addr.is_multiple_of(PAGE_SIZE) {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let end = (addr as u64).checked_add(size);
+    if end.is_none_or(|end| end > USER_MAPPABLE_END) {
+        return Err(SyscallError::InvalidPtr);
+    }
+    Ok((VirtAddr::new(addr as u64), size))
+}
+
+fn sys_mmap(
+    pid: PID,
+    addr: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    fd: usize,
+    offset: usize,
+) -> SyscallResult {
+    let prot = Protection::from_bits(prot).ok_or(SyscallError::InvalidArgument)?;
+    let flags = MapFlags::from_bits(flags).ok_or(SyscallError::InvalidArgument)?;
+    if flags.contains(MapFlags::SHARED) == flags.contains(MapFlags::PRIVATE) {
+        return Err(SyscallError::InvalidArgument);
+    }
+    if flags.contains(MapFlags::SHARED) {
+        return Err(SyscallError::NotSupported);
+    }
+    let fixed = flags.contains(MapFlags::FIXED);
+    let (start, size) = match page_range(addr, len) {
+        // a hint which is no address to map at is ignored
+        Err(SyscallError::InvalidArgument | SyscallError::InvalidPtr) if !fixed => {
+            page_range(0, len)?
+        }
+        result => result?,
+    };
+    if fixed && start.is_null() {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    // the file is read before taking the process manager, like `Read` does
+    let data = if flags.contains(MapFlags::ANONYMOUS) {
+        None
+    } else {
+        if !offset.is_multiple_of(PAGE_SIZE) {
+            return Err(SyscallError::InvalidArgument);
+        }
+        let file = open_file(pid, fd)?;
+        let file = file.lock();
+        let FileObject::File(node) = &file.object else {
+            return Err(SyscallError::NotSupported);
+        };
+        if !file.flags.contains(OpenFlags::READ) {
+            return Err(SyscallError::PermissionDenied);
+        }
+        let mut buffer = kernel_buffer(size as usize)?;
+        let read = sirius()?.read_node(node, offset, &mut buffer)?;
+        buffer.truncate(read);
+        Some(buffer)
+    };
+
+    let mut pm = PROCESS_MANAGER.lock();
+    let process = pm.get_process_mut(pid)?;
+    if process.exceeds_memory_limit(size) {
+        return Err(SyscallError::OutOfMemory);
+    }
+    let layout = &mut process.memory_layout;
+    let address_space_manager = get_user_mem_mgr();
+    let mut frame_allocator = get_frame_allocator();
+    let start = if fixed {
+        layout.unmap(start, size, &address_space_manager, &mut *frame_allocator);
+        start
+    } else {
+        let hint = (!start.is_null()).then_some(start);
+        layout
+            .find_free_range(hint, size)
+            .ok_or(SyscallError::OutOfMemory)?
+    };
+
+    match data {
+        None => layout.reserve_lazy(start, size, prot.page_flags(), VmaKind::Anonymous),
+        Some(data) => {
+            let vma = Vma {
+                start_virt: start,
+                size_bytes: size,
+                page_flags: prot.page_flags(),
+                lazy: false,
+                kind: VmaKind::File,
+            };
+            layout
+                .map_filled(vma, &data, &address_space_manager, &mut frame_allocator)
+                .map_err(|_| SyscallError::OutOfMemory)?;
         }
+    }
+    process.account_memory();
+    Ok(start.as_u64())
+}
 
-        _ => Err(SyscallError::SyscallNotFound),
+fn sys_munmap(pid: PID, addr: usize, len: usize) -> SyscallResult {
+    let (start, size) = page_range(addr, len)?;
+    let mut pm = PROCESS_MANAGER.lock();
+    let process = pm.get_process_mut(pid)?;
+    process.memory_layout.unmap(
+        start,
+        size,
+        &get_user_mem_mgr(),
+        &mut *get_frame_allocator(),
+    );
+    process.account_memory();
+    Ok(0)
+}
+
+fn sys_mprotect(pid: PID, addr: usize, len: usize, prot: usize) -> SyscallResult {
+    let prot = Protection::from_bits(prot).ok_or(SyscallError::InvalidArgument)?;
+    let (start, size) = page_range(addr, len)?;
+    let mut pm = PROCESS_MANAGER.lock();
+    let protected = pm.get_process_mut(pid)?.memory_layout.protect(
+        start,
+        size,
+        prot.page_flags(),
+        &get_user_mem_mgr(),
+        &get_frame_allocator(),
+    );
+    match protected {
+        true => Ok(0),
+        false => Err(SyscallError::InvalidPtr),
     }
 }
 
-//TODO: fix stacks
-const SYSCALL_STACK_SIZE: usize = 4096 * 16;
-#[repr(align(4096))]
-#[allow(dead_code)]
-struct SyscallStack([u8; SYSCALL_STACK_SIZE]);
-static mut SYSCALL_STACK: SyscallStack = SyscallStack([0; SYSCALL_STACK_SIZE]);
-static STACK_TOP: AtomicU64 = AtomicU64::new(0);
+/// Enters the handler of a pending signal instead of returning to the caller, the saved
+/// context returns to it with the syscall's `result`
+fn deliver_signal(pid: PID, frame: &mut SyscallFrame, result: u64) {
+    let delivery = {
+        let mut pm = PROCESS_MANAGER.lock();
+        let Ok(process) = pm.get_process_mut(pid) else {
+            return;
+        };
+        if !process.signals.has_deliverable() {
+            return;
+        }
 
-pub fn init_syscall_stack() {
-    let top = unsafe {
-        let stack_bottom = core::ptr::addr_of!(SYSCALL_STACK).cast::<u8>();
-        let stack_top_ptr = stack_bottom.add(SYSCALL_STACK_SIZE);
-        VirtAddr::from_ptr(stack_top_ptr).as_u64()
+        let mut context = SignalContext::from_syscall_frame(frame, result);
+        let delivery = signal::deliver(
+            process,
+            &mut context,
+            &get_user_mem_mgr(),
+            &mut get_frame_allocator(),
+        );
+        if let Delivery::Handler(_) = delivery {
+            frame.user_rip = context.rip;
+            frame.user_rsp = context.rsp;
+            frame.rflags = context.rflags;
+            frame.arg1 = context.rdi;
+        }
+        delivery
     };
-    STACK_TOP.store(top, Ordering::SeqCst);
-    serial_println!("Syscall stack top: {:#x}", top);
+
+    if let Delivery::Fatal(signal) = delivery {
+        serial_println!("PID {} killed by signal {}", pid, signal);
+        scheduler::exit_current(signal::exit_code(signal), false);
+    }
+}
+
+/// The open file behind `fd`, the process manager is unlocked again when it is returned
+fn open_file(pid: PID, fd: usize) -> Result<SharedOpenFile, SyscallError> {
+    let pm = PROCESS_MANAGER.lock();
+    pm.get_process(pid)?
+        .file_descriptors
+        .get(fd)
+        .ok_or(SyscallError::InvalidFd)
+}
+
+impl From<UserCopyError> for SyscallError {
+    fn from(value: UserCopyError) -> Self {
+        serial_println!("Rejected user pointer: {:?}", value);
+        SyscallError::InvalidPtr
+    }
+}
+
+/// Backs the lazy pages in the range, and copies shared pages about to be written,
+/// so they can be accessed through the page tables. Returns the address space of `pid`
+fn fault_in_user_range(
+    pid: PID,
+    ptr: usize,
+    len: usize,
+    write: bool,
+) -> Result<PhysAddr, SyscallError> {
+    let mut pm = PROCESS_MANAGER.lock();
+    let layout = &mut pm.get_process_mut(pid)?.memory_layout;
+    layout
+        .populate(
+            user_addr(ptr)?,
+            len as u64,
+            write,
+            &get_user_mem_mgr(),
+            &mut get_frame_allocator(),
+        )
+        .map_err(|e| match e {
+            PageFaultError::OutOfMemory => SyscallError::OutOfMemory,
+            _ => SyscallError::InvalidPtr,
+        })?;
+    Ok(layout.top_page_table_phys)
+}
+
+fn user_addr(ptr: usize) -> Result<VirtAddr, SyscallError> {
+    VirtAddr::try_new(ptr as u64).map_err(|_| SyscallError::InvalidPtr)
+}
+
+/// Zeroed kernel buffer for data on its way to or from userspace
+fn kernel_buffer(len: usize) -> Result<Vec<u8>, SyscallError> {
+    let mut buffer = Vec::new();
+    buffer
+        .try_reserve_exact(len)
+        .map_err(|_| SyscallError::OutOfMemory)?;
+    buffer.resize(len, 0);
+    Ok(buffer)
+}
+
+/// Copies `len` bytes at `ptr` out of the address space of `pid`
+pub fn copy_from_user(pid: PID, ptr: usize, len: usize) -> Result<Vec<u8>, SyscallError> {
+    let mut buffer = kernel_buffer(len)?;
+    if len > 0 {
+        let page_table = fault_in_user_range(pid, ptr, len, false)?;
+        get_user_mem_mgr().copy_from_user(page_table, user_addr(ptr)?, &mut buffer)?;
+    }
+    Ok(buffer)
+}
+
+/// Copies `data` to `ptr` in the address space of `pid`
+pub fn copy_to_user(pid: PID, ptr: usize, data: &[u8]) -> Result<(), SyscallError> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let page_table = fault_in_user_range(pid, ptr, data.len(), true)?;
+    get_user_mem_mgr().copy_to_user(page_table, user_addr(ptr)?, data)?;
+    Ok(())
+}
+
+fn user_string(pid: PID, ptr: usize, len: usize) -> Result<String, SyscallError> {
+    String::from_utf8(copy_from_user(pid, ptr, len)?).map_err(|_| SyscallError::InvalidArgument)
+}
+
+/// Reads the NUL terminated string at `ptr`, which may be at most `max_len` bytes long
+fn user_c_string(pid: PID, ptr: usize, max_len: usize) -> Result<String, SyscallError> {
+    let mut bytes = Vec::new();
+    let mut addr = ptr;
+    loop {
+        // never read past the page, the string may end right before an unmapped one
+        let chunk_len = PAGE_SIZE - addr % PAGE_SIZE;
+        let chunk = copy_from_user(pid, addr, chunk_len)?;
+        if let Some(end) = chunk.iter().position(|&byte| byte == 0) {
+            bytes.extend_from_slice(&chunk[..end]);
+            break;
+        }
+        bytes.extend_from_slice(&chunk);
+        if bytes.len() > max_len {
+            return Err(SyscallError::InvalidArgument);
+        }
+        addr = addr
+            .checked_add(chunk_len)
+            .ok_or(SyscallError::InvalidPtr)?;
+    }
+    if bytes.len() > max_len {
+        return Err(SyscallError::InvalidArgument);
+    }
+    String::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument)
+}
+
+/// Reads the null terminated array of C strings at `ptr`, a null `ptr` is an empty array.
+/// The strings and their pointers are taken off `budget`, running out of it is an error.
+fn user_string_array(
+    pid: PID,
+    ptr: usize,
+    budget: &mut usize,
+) -> Result<Vec<String>, SyscallError> {
+    let mut strings = Vec::new();
+    if ptr == 0 {
+        return Ok(strings);
+    }
+    loop {
+        let entry = strings
+            .len()
+            .checked_mul(size_of::<usize>())
+            .and_then(|offset| ptr.checked_add(offset))
+            .ok_or(SyscallError::InvalidPtr)?;
+        let entry_bytes = copy_from_user(pid, entry, size_of::<usize>())?;
+        let string_ptr = usize::from_ne_bytes(entry_bytes.try_into().unwrap());
+        if string_ptr == 0 {
+            return Ok(strings);
+        }
+
+        let string = user_c_string(pid, string_ptr, *budget)?;
+        *budget = budget
+            .checked_sub(string.len() + 1 + size_of::<usize>())
+            .ok_or(SyscallError::InvalidArgument)?;
+        strings.push(string);
+    }
+}
+
+/// Per-CPU data the syscall entry reaches through `gs` after `swapgs`
+#[repr(C)]
+struct CpuLocal {
+    /// Top of the running process's kernel stack
+    kernel_stack_top: AtomicU64,
+    /// Holds the user stack pointer while the entry switches stacks
+    user_rsp: AtomicU64,
+}
+
+static CPU_LOCAL: CpuLocal = CpuLocal {
+    kernel_stack_top: AtomicU64::new(0),
+    user_rsp: AtomicU64::new(0),
+};
+
+/// Sets the stack the next `syscall` switches to, the scheduler calls it on every task switch
+pub fn set_syscall_stack(stack_top: VirtAddr) {
+    CPU_LOCAL
+        .kernel_stack_top
+        .store(stack_top.as_u64(), Ordering::Relaxed);
 }
 
 #[repr(C)]
+#[derive(Default)]
 pub struct SyscallFrame {
+    pub r15: u64,
     pub r14: u64,
     pub r13: u64,
     pub r12: u64,
@@ -229,26 +1381,26 @@ pub struct SyscallFrame {
 
     pub rflags: u64,   // r11
     pub user_rip: u64, // rcx
-    pub user_rsp: u64, // r15
+    pub user_rsp: u64,
 }
 
 #[unsafe(no_mangle)]
 #[unsafe(naked)]
 /// # Safety
 ///
-/// Must only be invoked by the CPU's `syscall` instruction. The syscall stack must have been
-/// initialized via `init_syscall_stack()` before any syscall is executed, and `STACK_TOP` must
-/// contain its correct top address.
+/// Must only be invoked by the CPU's `syscall` instruction, with interrupts masked through
+/// IA32_FMASK. `init_syscall()` must have pointed the kernel GS base at `CPU_LOCAL`, and the
+/// scheduler must have installed the running process's kernel stack via `set_syscall_stack()`.
 pub unsafe extern "C" fn syscall_handler() -> ! {
     naked_asm!(
-        // save user stack
-        "mov r15, rsp",
-
-        // switch to kernel stack
-        "mov rsp, qword ptr [rip + {stack_top}]",
+        // switch to the process's kernel stack, gs only points at CPU_LOCAL in between the swapgs
+        "swapgs",
+        "mov qword ptr gs:[{user_rsp}], rsp",
+        "mov rsp, qword ptr gs:[{kernel_stack_top}]",
+        "push qword ptr gs:[{user_rsp}]", // user RSP
+        "swapgs",
 
         // save state on kernel stack
-        "push r15", // user RSP
         "push rcx", // user RIP
         "push r11", // user RFLAGS
 
@@ -265,29 +1417,40 @@ pub unsafe extern "C" fn syscall_handler() -> ! {
         "push r12",
         "push r13",
         "push r14",
+        "push r15",
 
-        "mov rdi, rsp",
+        // we are on our own stack now, the process may be preempted from here on
+        "sti",
 
+        "mov rdi, rsp",
         "call {handle_syscall}",
 
+        "pop r15",
         "pop r14",
         "pop r13",
         "pop r12",
         "pop rbx",
         "pop rbp",
 
-        "add rsp, 7*8",
+        // the result stays in rax, the arguments are preserved for the caller
+        "pop r9",
+        "pop r8",
+        "pop r10",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "add rsp, 8",
 
+        // no interrupts on the user stack, sysret restores IF from r11
+        "cli",
         "pop r11",
         "pop rcx",
-        "pop r15",
-
-        // back to user stack
-        "mov rsp, r15",
+        "pop rsp",
 
         "sysretq",
 
-        stack_top = sym STACK_TOP,
+        user_rsp = const offset_of!(CpuLocal, user_rsp),
+        kernel_stack_top = const offset_of!(CpuLocal, kernel_stack_top),
         handle_syscall = sym handle_syscall_inner,
     )
 }
@@ -307,31 +1470,31 @@ unsafe extern "C" fn handle_syscall_inner(frame: *mut SyscallFrame) -> u64 {
         frame.arg6,
     );
 
-    match frame.syscall_num {
-        2 => {
-            let fd = frame.arg1;
-            let buf = frame.arg2 as *const u8;
-            let count = frame.arg3 as usize;
-            serial_println!("WRITE: fd={}, buf={:p}, count={}", fd, buf, count);
+    let call = SystemCall::from_number_and_args(
+        frame.syscall_num as usize,
+        frame.arg1 as usize,
+        frame.arg2 as usize,
+        frame.arg3 as usize,
+        frame.arg4 as usize,
+        frame.arg5 as usize,
+        frame.arg6 as usize,
+    );
 
-            let slice = unsafe { core::slice::from_raw_parts(buf, count) };
-            if let Ok(s) = core::str::from_utf8(slice) {
-                crate::serial_print!("{}", s);
-            }
-            count as u64
-        }
-        997 => {
-            serial_println!("997 returning: {}", frame.arg1 + 4);
-            frame.arg1 + 4
-        }
-        999 => {
-            serial_println!("Process exited");
-            loop {
-                x86_64::instructions::hlt();
-            }
-        }
-        _ => u64::MAX,
+    let pid = scheduler::current_pid();
+    let result = match (call, pid) {
+        (Some(call), Some(pid)) => handle_syscall(pid, call, frame),
+        (None, _) => Err(SyscallError::SyscallNotFound),
+        (_, None) => Err(SyscallError::ProcessNotFound),
+    };
+
+    if let Err(e) = result {
+        serial_println!("Syscall {} failed: {:?}", frame.syscall_num, e);
     }
+    let result = encode_syscall_result(result);
+    if let Some(pid) = pid {
+        deliver_signal(pid, frame, result);
+    }
+    result
 }
 
 pub fn init_syscall() {
@@ -343,11 +1506,11 @@ pub fn init_syscall() {
     }
 
     // setup STAR MSR
-    let user_cs = 0x1Bu64;
-    let user_ss = 0x23u64;
-    let kernel_cs = 0x08u64;
-    let kernel_ss = 0x10u64;
-    let star_value = (user_cs << 48) | (user_ss << 32) | (kernel_cs << 16) | kernel_ss;
+    // syscall loads CS from STAR[47:32] and SS from STAR[47:32] + 8,
+    // sysret loads SS from STAR[63:48] + 8 and CS from STAR[63:48] + 16
+    let kernel_cs = crate::gdt::get_kernel_code_selector().0 as u64;
+    let sysret_base = crate::gdt::get_user_data_selector().0 as u64 - 8;
+    let star_value = (sysret_base << 48) | (kernel_cs << 32);
     unsafe {
         let msr = 0xC0000081u32;
         msr_write(msr, star_value);
@@ -361,12 +1524,16 @@ pub fn init_syscall() {
         msr_write(msr, handler_addr);
     }
 
-    // no RFLAGS masking in syscall RFLAGS save state
-    const IA32_FMASK_MSR_VALUE: u64 = 0u64;
+    // clear IF, TF, DF and AC on entry, the handler enables interrupts once it is on the
+    // kernel stack
+    const IA32_FMASK_MSR_VALUE: u64 = 0x4_0700;
     unsafe {
         let msr = 0xC0000084u32;
         msr_write(msr, IA32_FMASK_MSR_VALUE);
     }
 
+    // swapgs in the syscall entry exchanges the (user) GS base with this
+    KernelGsBase::write(VirtAddr::from_ptr(&CPU_LOCAL));
+
     serial_println!("Syscall MSRs initialized");
 }
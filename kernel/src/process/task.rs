@@ -1,11 +1,27 @@
 use crate::{
     data_structures::vector::Vec,
-    process::{elf_loader::ElfLoadInfo, process_mem::ProcessMemoryLayout},
+    memory::{
+        paging::{MemoryMapFrameAllocator, PAGE_SIZE, no_execute_enabled},
+        stack::GuardedStack,
+        usermem::{USER_STACK_TOP, UserMemoryManager},
+    },
+    process::{
+        elf_loader::ElfLoadInfo,
+        fault::Fault,
+        file_table::FileDescriptorTable,
+        fpu::FpuState,
+        initial_stack::{AT_ENTRY, AT_PAGESZ, AT_PHDR, AT_PHENT, AT_PHNUM, build_initial_stack},
+        process_manager::ProcessError,
+        process_mem::{ProcessMemoryLayout, data_page_flags},
+        signal::SignalState,
+        syscall::SyscallFrame,
+        vma::{Vma, VmaKind},
+    },
     serial_println,
 };
-use alloc::string::String;
+use alloc::{string::String, vec};
 use x86_64::{
-    VirtAddr,
+    PhysAddr, VirtAddr,
     structures::paging::{PageTableFlags, Size4KiB, mapper::MapToError},
 };
 
@@ -13,29 +29,27 @@ use x86_64::{
 pub const INVALID_PID: usize = usize::MAX;
 
 pub const MAX_PRIORITY: u8 = 8;
+/// Priority of processes started from the shell, below arche so the shell stays responsive
+pub const DEFAULT_USER_PRIORITY: u8 = MAX_PRIORITY / 2;
 pub const RFLAGS_DEFAULT: u64 = 0x202;
 pub const DEFAULT_NEW_PROCESS_STACK_SIZE: u64 = 1024 * 1024;
+/// Inaccessible area right below the user stack, so an overflow faults instead of running
+/// into whatever is mapped below
+pub const USER_STACK_GUARD_SIZE: u64 = 64 * 1024;
+/// Lowest address of the stack and its guard, every image has to end below it
+pub const USER_STACK_AREA_START: u64 =
+    USER_STACK_TOP - DEFAULT_NEW_PROCESS_STACK_SIZE - USER_STACK_GUARD_SIZE;
+pub const KERNEL_STACK_SIZE: usize = 64 * 1024;
 
 pub type PID = usize;
 
-//TODO: temporary until no scheduler
-static CURRENT_PROCESS: spin::Once<spin::Mutex<Option<Process>>> = spin::Once::new();
-
-pub fn set_current_process(process: Process) {
-    CURRENT_PROCESS.call_once(|| spin::Mutex::new(Some(process)));
-}
-
-pub fn get_current_process() -> &'static spin::Mutex<Option<Process>> {
-    CURRENT_PROCESS
-        .get()
-        .expect("Current process not initialized")
-}
-
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ProcessState {
     Ready,
     Running,
+    /// Blocked until something wakes it up, e.g. a child exiting
     Waiting,
+    /// Exited but not reaped by its parent yet
     Terminated,
 }
 
@@ -65,33 +79,64 @@ pub struct ExecutionContext {
     pub r14: u64,
     pub r15: u64,
 
-    //TODO: sse? maybe somewhere seperate because aint storing the whole avx
     pub rip: u64,
     pub rflags: u64,
+    pub cs: u64,
+    pub ss: u64,
 
     pub page_table_base_phys: u64,
 }
 
-//TODO: when we have a fs/vfs
-pub struct FileDescriptor {
-    pub handle: usize,
+/// Stack a user process runs on in ring 0, during its syscalls and interrupts from ring 3.
+/// It comes from the guarded stack region, overflowing it faults.
+pub struct KernelStack {
+    stack: GuardedStack,
+}
+
+impl KernelStack {
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        Ok(Self {
+            stack: GuardedStack::new(KERNEL_STACK_SIZE)?,
+        })
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.stack.top()
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.stack.bottom()
+    }
 }
 
 pub struct Process {
     pub pid: PID,
     pub parent_pid: PID,
     pub priority: u8,
+    /// Priority the scheduler currently uses, lowered when the process hogs the CPU
+    pub dynamic_priority: u8,
     pub state: ProcessState,
     pub name: String,
     pub children: Vec<PID>,
-    pub file_descriptors: Vec<FileDescriptor>,
+    pub file_descriptors: FileDescriptorTable,
+    pub signals: SignalState,
 
     pub resources: ProcessResources,
     pub exit_code: Option<i32>,
+    /// Uptime in nanoseconds a sleeping process waits for
+    pub wake_at: Option<u64>,
+    /// Set while the process is blocked in WaitPid, only a child exiting wakes it then
+    pub waits_for_child: bool,
+    /// Set when a CPU exception from ring 3 terminated the process
+    pub fault: Option<Fault>,
     pub is_out: bool,
 
     pub execution_context: ExecutionContext,
+    /// FPU, SSE and AVX registers, only up to date while another task owns the FPU
+    pub fpu_state: FpuState,
     pub memory_layout: ProcessMemoryLayout,
+    /// Kernel tasks run on the stack they were started on and have none
+    pub kernel_stack: Option<KernelStack>,
 }
 
 unsafe impl Send for Process {}
@@ -117,9 +162,40 @@ impl ExecutionContext {
             r15: 0,
             rip: entry_point,
             rflags: RFLAGS_DEFAULT,
+            cs: crate::gdt::get_user_code_selector().0 as u64,
+            ss: crate::gdt::get_user_data_selector().0 as u64,
             page_table_base_phys,
         }
     }
+
+    /// Context a process resumes at after the syscall described by `frame`
+    pub fn from_syscall_frame(frame: &SyscallFrame, page_table_base_phys: u64) -> Self {
+        Self {
+            rbx: frame.rbx,
+            rdx: frame.arg3,
+            rsi: frame.arg2,
+            rdi: frame.arg1,
+            rbp: frame.rbp,
+            r8: frame.arg5,
+            r9: frame.arg6,
+            r10: frame.arg4,
+            r12: frame.r12,
+            r13: frame.r13,
+            r14: frame.r14,
+            r15: frame.r15,
+            rflags: frame.rflags,
+            ..Self::new(frame.user_rip, frame.user_rsp, page_table_base_phys)
+        }
+    }
+
+    /// Context of a task running in ring 0; it is filled in when the task gets preempted
+    pub fn new_kernel(page_table_base_phys: u64) -> Self {
+        Self {
+            cs: crate::gdt::get_kernel_code_selector().0 as u64,
+            ss: crate::gdt::get_kernel_data_selector().0 as u64,
+            ..Self::new(0, 0, page_table_base_phys)
+        }
+    }
 }
 
 impl Process {
@@ -150,112 +226,275 @@ impl Process {
     //     }
     // }
 
+    pub fn new_kernel_task(
+        pid: PID,
+        name: &str,
+        priority: u8,
+        resources: ProcessResources,
+        page_table_base_phys: PhysAddr,
+    ) -> Self {
+        Self {
+            pid,
+            parent_pid: pid,
+            priority,
+            dynamic_priority: priority,
+            state: ProcessState::Running,
+            name: String::from(name),
+            children: Vec::new(),
+            file_descriptors: FileDescriptorTable::new(),
+            signals: SignalState::new(),
+            resources,
+            exit_code: None,
+            wake_at: None,
+            waits_for_child: false,
+            fault: None,
+            is_out: true,
+            execution_context: ExecutionContext::new_kernel(page_table_base_phys.as_u64()),
+            fpu_state: FpuState::new(),
+            memory_layout: ProcessMemoryLayout::existing(page_table_base_phys),
+            kernel_stack: None,
+        }
+    }
+
+    pub fn is_kernel_task(&self) -> bool {
+        self.execution_context.cs & 0b11 == 0
+    }
+
     pub fn create_with_elf(
         elf_info: &ElfLoadInfo,
         name: &str,
         pid: PID,
         parent_pid: PID,
-    ) -> Result<Self, MapToError<Size4KiB>> {
+        argv: &[&str],
+        envp: &[&str],
+    ) -> Result<Self, ProcessError> {
         serial_println!("Process::create_with_elf()");
+        // taken first, it simply goes again if the image can not be loaded
+        let kernel_stack = KernelStack::new()?;
+        let (memory_layout, context) = Self::load_image(elf_info, argv, envp)?;
+
+        let mut process = Self {
+            pid,
+            parent_pid,
+            priority: 1,
+            dynamic_priority: 1,
+            state: ProcessState::Ready,
+            name: String::from(name),
+            children: Vec::new(),
+            file_descriptors: FileDescriptorTable::with_console(),
+            signals: SignalState::new(),
+            resources: ProcessResources::default(),
+            exit_code: None,
+            wake_at: None,
+            waits_for_child: false,
+            fault: None,
+            is_out: true,
+            execution_context: context,
+            fpu_state: FpuState::new(),
+            memory_layout,
+            kernel_stack: Some(kernel_stack),
+        };
+        process.account_memory();
+        Ok(process)
+    }
+
+    /// Replaces the program the process runs with `elf_info`, keeping its PID, parent,
+    /// descriptors and blocked signals. The FPU state starts over too.
+    /// Returns the old memory layout, the caller has to release it once the process no longer
+    /// runs in it. On failure the process is left as it was.
+    pub fn exec(
+        &mut self,
+        elf_info: &ElfLoadInfo,
+        name: &str,
+        argv: &[&str],
+        envp: &[&str],
+    ) -> Result<ProcessMemoryLayout, ProcessError> {
+        let (memory_layout, context) = Self::load_image(elf_info, argv, envp)?;
+
+        self.name = String::from(name);
+        self.execution_context = context;
+        self.fpu_state = FpuState::new();
+        self.signals.reset_handlers();
+        let old_layout = core::mem::replace(&mut self.memory_layout, memory_layout);
+        self.account_memory();
+        Ok(old_layout)
+    }
+
+    /// Sets `resources.memory_used` to the bytes of address space the process has mapped
+    pub fn account_memory(&mut self) {
+        self.resources.memory_used = self.memory_layout.mapped_bytes() as usize;
+    }
+
+    /// Whether mapping `extra_bytes` more would take the process past its memory limit
+    pub fn exceeds_memory_limit(&self, extra_bytes: u64) -> bool {
+        (self.resources.memory_used as u64).saturating_add(extra_bytes)
+            > self.resources.memory_limit as u64
+    }
+
+    /// Maps the segments of `elf_info` into a new address space with a stack on top holding
+    /// the arguments, the returned context starts at the entry point. On failure the new
+    /// address space is released again.
+    fn load_image(
+        elf_info: &ElfLoadInfo,
+        argv: &[&str],
+        envp: &[&str],
+    ) -> Result<(ProcessMemoryLayout, ExecutionContext), ProcessError> {
         //TODO: safer
         let address_space_manager = &crate::memory::get_user_mem_mgr();
         let mut frame_allocator = crate::memory::get_frame_allocator();
 
         let mut memory_layout =
             ProcessMemoryLayout::new(address_space_manager, &mut frame_allocator)?;
+        match Self::fill_image(
+            &mut memory_layout,
+            elf_info,
+            argv,
+            envp,
+            address_space_manager,
+            &mut frame_allocator,
+        ) {
+            Ok(context) => Ok((memory_layout, context)),
+            Err(e) => {
+                memory_layout.release(address_space_manager, &mut *frame_allocator);
+                Err(e)
+            }
+        }
+    }
 
+    /// Maps the segments and the stack of `load_image` into `memory_layout`. Everything mapped
+    /// is covered by an area by the time anything can fail, so releasing the layout frees it.
+    fn fill_image(
+        memory_layout: &mut ProcessMemoryLayout,
+        elf_info: &ElfLoadInfo,
+        argv: &[&str],
+        envp: &[&str],
+        address_space_manager: &UserMemoryManager,
+        frame_allocator: &mut MemoryMapFrameAllocator,
+    ) -> Result<ExecutionContext, ProcessError> {
         for segment in &elf_info.segments {
             let vaddr = VirtAddr::new(segment.vaddr);
             let in_memory_size = segment.in_memory_size;
 
-            // TODO: Parse actual segment flags from ELF
             let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
-            flags |= PageTableFlags::WRITABLE;
+            if segment.is_writable() {
+                flags |= PageTableFlags::WRITABLE;
+            }
+            if !segment.is_executable() && no_execute_enabled() {
+                flags |= PageTableFlags::NO_EXECUTE;
+            }
 
             serial_println!(
-                "  Mapping ELF segment: vaddr={:#x}, size={:#x}",
+                "  Mapping ELF segment: vaddr={:#x}, size={:#x}, flags={:?}",
                 vaddr.as_u64(),
-                in_memory_size
+                in_memory_size,
+                flags
             );
 
-            address_space_manager.map_virt_mem_region(
-                memory_layout.top_page_table_phys,
-                vaddr,
-                in_memory_size,
-                flags,
-                &mut frame_allocator,
-            )?;
-
-            let phys_addr = address_space_manager
-                .translate_user_virt_to_phys(memory_layout.top_page_table_phys, vaddr)
-                .expect("Failed to translate user virtual address to physical");
-            let hhdm_vaddr = phys_addr.as_u64() + address_space_manager.phys_offset;
-
-            // copy segment data
-            unsafe {
-                core::ptr::copy_nonoverlapping(
-                    segment.data.as_ptr(),
-                    hhdm_vaddr as *mut u8,
-                    segment.in_file_size as usize,
-                );
+            // pages holding file data are loaded now, the bss past them comes in on demand
+            let file_size = segment.in_file_size;
+            if file_size > 0 {
+                // whole pages get mapped, segments never share one
+                let image_start = vaddr.align_down(PAGE_SIZE as u64);
+                let image_end = (vaddr + file_size).align_up(PAGE_SIZE as u64);
+                memory_layout.vmas.insert(Vma {
+                    start_virt: image_start,
+                    size_bytes: image_end - image_start,
+                    page_flags: flags,
+                    lazy: false,
+                    kind: VmaKind::Image,
+                });
+                address_space_manager.map_virt_mem_region(
+                    memory_layout.top_page_table_phys,
+                    vaddr,
+                    file_size,
+                    flags,
+                    frame_allocator,
+                )?;
+
+                // the frames may not be physically contiguous, copy page by page;
+                // they come zeroed, which takes care of the bss in the last page
+                address_space_manager
+                    .load_into_user(
+                        memory_layout.top_page_table_phys,
+                        vaddr,
+                        &segment.data[..file_size as usize],
+                    )
+                    .expect("ELF segment was just mapped");
+                serial_println!("  Copied {} bytes to {:#x}", file_size, vaddr.as_u64());
             }
-            serial_println!(
-                "  Copied {} bytes to {:#x}",
-                segment.in_file_size,
-                vaddr.as_u64()
-            );
 
-            // zero-fill the bss section
-            if segment.in_memory_size > segment.in_file_size {
-                let bss_start = vaddr + segment.in_file_size;
-                let bss_size = segment.in_memory_size - segment.in_file_size;
-                unsafe {
-                    core::ptr::write_bytes(bss_start.as_mut_ptr::<u8>(), 0u8, bss_size as usize);
-                }
-                serial_println!(
-                    "  Zeroed BSS: {:#x} bytes at {:#x}",
-                    bss_size,
-                    bss_start.as_u64()
+            // without file data nothing got mapped, the first page has to be reserved too
+            let lazy_start = match file_size {
+                0 => vaddr.align_down(PAGE_SIZE as u64),
+                _ => (vaddr + file_size).align_up(PAGE_SIZE as u64),
+            };
+            let segment_end = (vaddr + in_memory_size).align_up(PAGE_SIZE as u64);
+            if lazy_start < segment_end {
+                memory_layout.reserve_lazy(
+                    lazy_start,
+                    segment_end - lazy_start,
+                    flags,
+                    VmaKind::Image,
                 );
             }
-
-            // track mapped region
-            memory_layout
-                .mapped_regions
-                .push(crate::process::process_mem::MappedMemoryRegion {
-                    start_virt: vaddr,
-                    size_bytes: in_memory_size,
-                    page_flags: flags,
-                });
         }
 
         let stack_size = DEFAULT_NEW_PROCESS_STACK_SIZE;
-        let stack_top = address_space_manager.create_main_stack(
-            memory_layout.top_page_table_phys,
+        let stack_top = VirtAddr::new(USER_STACK_TOP);
+        memory_layout.reserve_lazy(
+            stack_top - stack_size,
             stack_size,
-            &mut frame_allocator,
-        )?;
+            data_page_flags(),
+            VmaKind::Stack,
+        );
+        memory_layout.vmas.insert(Vma {
+            start_virt: VirtAddr::new(USER_STACK_AREA_START),
+            size_bytes: USER_STACK_GUARD_SIZE,
+            page_flags: PageTableFlags::empty(),
+            lazy: false,
+            kind: VmaKind::Guard,
+        });
         memory_layout.stack_top = stack_top;
+        memory_layout.stack_size = stack_size;
+
+        let mut auxv = vec![
+            (AT_ENTRY, elf_info.entry_point),
+            (AT_PAGESZ, PAGE_SIZE as u64),
+        ];
+        if elf_info.program_headers_vaddr != 0 {
+            auxv.extend([
+                (AT_PHDR, elf_info.program_headers_vaddr),
+                (AT_PHENT, elf_info.program_header_size),
+                (AT_PHNUM, elf_info.program_header_count),
+            ]);
+        }
+        let initial_stack = build_initial_stack(stack_top, argv, envp, &auxv);
+        if initial_stack.bytes.len() as u64 > stack_size {
+            return Err(ProcessError::ArgumentsTooLong);
+        }
+        memory_layout
+            .populate(
+                initial_stack.rsp,
+                initial_stack.bytes.len() as u64,
+                true,
+                address_space_manager,
+                frame_allocator,
+            )
+            .map_err(|_| ProcessError::OutOfMemory)?;
+        address_space_manager
+            .load_into_user(
+                memory_layout.top_page_table_phys,
+                initial_stack.rsp,
+                &initial_stack.bytes,
+            )
+            .expect("initial stack was just populated");
 
         let context = ExecutionContext::new(
             elf_info.entry_point,
-            stack_top.as_u64(),
+            initial_stack.rsp.as_u64(),
             memory_layout.top_page_table_phys.as_u64(),
         );
 
-        Ok(Self {
-            pid,
-            parent_pid,
-            priority: 1,
-            state: ProcessState::Ready,
-            name: String::from(name),
-            children: Vec::new(),
-            file_descriptors: Vec::new(),
-            resources: ProcessResources::default(),
-            exit_code: None,
-            is_out: true,
-            execution_context: context,
-            memory_layout,
-        })
+        Ok(context)
     }
 }
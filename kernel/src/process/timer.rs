@@ -0,0 +1,51 @@
+use crate::process::task::PID;
+use alloc::collections::BinaryHeap;
+use core::cmp::Reverse;
+
+/// Deadlines sleeping tasks wait for, in nanoseconds of uptime, earliest first.
+/// Entries are never removed early, whoever pops one checks it still matters.
+pub struct TimerQueue {
+    deadlines: BinaryHeap<Reverse<(u64, PID)>>,
+}
+
+impl Default for TimerQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimerQueue {
+    pub fn new() -> Self {
+        Self {
+            deadlines: BinaryHeap::with_capacity(16),
+        }
+    }
+
+    pub fn add(&mut self, deadline_ns: u64, pid: PID) {
+        self.deadlines.push(Reverse((deadline_ns, pid)));
+    }
+
+    /// Takes out the earliest deadline if it is not after `now_ns`. Does not allocate,
+    /// so it can run on the timer interrupt.
+    pub fn pop_expired(&mut self, now_ns: u64) -> Option<(u64, PID)> {
+        let Reverse((deadline_ns, _)) = self.deadlines.peek()?;
+        if *deadline_ns > now_ns {
+            return None;
+        }
+        self.deadlines.pop().map(|Reverse(entry)| entry)
+    }
+
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.deadlines
+            .peek()
+            .map(|Reverse((deadline_ns, _))| *deadline_ns)
+    }
+
+    pub fn len(&self) -> usize {
+        self.deadlines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deadlines.is_empty()
+    }
+}
@@ -0,0 +1,250 @@
+use crate::memory::paging::{PAGE_SIZE, no_execute_enabled};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use x86_64::{VirtAddr, structures::paging::PageTableFlags};
+
+bitflags! {
+    /// What the pages of a mapping may be used for, `Mmap` and `Mprotect` take them as `prot`.
+    /// Any access at all allows reading.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Protection: usize {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXEC = 1 << 2;
+    }
+}
+
+bitflags! {
+    /// How `Mmap` maps, exactly one of `SHARED` and `PRIVATE` has to be given
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct MapFlags: usize {
+        /// Writes are seen by everyone mapping the same memory, not supported yet
+        const SHARED = 1 << 0;
+        const PRIVATE = 1 << 1;
+        /// Map exactly at the given address, replacing whatever was mapped there
+        const FIXED = 1 << 4;
+        /// Zeroed memory instead of the contents of a file
+        const ANONYMOUS = 1 << 5;
+    }
+}
+
+impl Protection {
+    /// Leaf flags of pages with this protection, without any access they are not `PRESENT`
+    pub fn page_flags(self) -> PageTableFlags {
+        if self.is_empty() {
+            return PageTableFlags::empty();
+        }
+        let mut flags = PageTableFlags::PRESENT;
+        if self.contains(Protection::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.contains(Protection::EXEC) && no_execute_enabled() {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// What a virtual memory area was created for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    /// Segment of the program's ELF image
+    Image,
+    Stack,
+    /// Never accessible, keeps an overflowing stack from running into other memory
+    Guard,
+    Heap,
+    /// Zeroed memory from `Mmap`
+    Anonymous,
+    /// Private copy of a file from `Mmap`, writes are not carried back to the file
+    File,
+}
+
+/// Range of a process's address space with the same protection for every page
+#[derive(Debug, Clone)]
+pub struct Vma {
+    pub start_virt: VirtAddr,
+    pub size_bytes: u64,
+    /// Leaf flags of its pages, without `PRESENT` no access is allowed at all
+    pub page_flags: PageTableFlags,
+    /// Reserved only, each page gets a zeroed frame the first time it is touched
+    pub lazy: bool,
+    pub kind: VmaKind,
+}
+
+impl Vma {
+    pub fn end(&self) -> VirtAddr {
+        self.start_virt + self.size_bytes
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start_virt && addr < self.end()
+    }
+
+    /// Cuts the area in two at `addr`, which has to lie inside of it. Returns the upper part.
+    fn split_off(&mut self, addr: VirtAddr) -> Vma {
+        let upper = Vma {
+            start_virt: addr,
+            size_bytes: self.end() - addr,
+            ..self.clone()
+        };
+        self.size_bytes = addr - self.start_virt;
+        upper
+    }
+}
+
+/// Areas of an address space ordered by start, they never overlap
+#[derive(Debug, Clone, Default)]
+pub struct VmaTree {
+    areas: BTreeMap<u64, Vma>,
+}
+
+impl VmaTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `vma`, whose range must not overlap any other area. It is merged into an area
+    /// right below it which is alike, like a heap growing in steps.
+    pub fn insert(&mut self, vma: Vma) {
+        debug_assert!(!self.overlaps(vma.start_virt, vma.size_bytes));
+        if let Some((_, below)) = self.areas.range_mut(..vma.start_virt.as_u64()).next_back()
+            && below.end() == vma.start_virt
+            && below.page_flags == vma.page_flags
+            && below.lazy == vma.lazy
+            && below.kind == vma.kind
+        {
+            below.size_bytes += vma.size_bytes;
+            return;
+        }
+        self.areas.insert(vma.start_virt.as_u64(), vma);
+    }
+
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.areas
+            .range(..=addr.as_u64())
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    pub fn overlaps(&self, start: VirtAddr, size_bytes: u64) -> bool {
+        let end = start.as_u64().saturating_add(size_bytes);
+        self.find(start).is_some() || self.areas.range(start.as_u64()..end).next().is_some()
+    }
+
+    /// Whether an area of `kind` takes in any byte of the range
+    pub fn overlaps_kind(&self, start: VirtAddr, size_bytes: u64, kind: VmaKind) -> bool {
+        let end = start.as_u64().saturating_add(size_bytes);
+        self.find(start).is_some_and(|vma| vma.kind == kind)
+            || self
+                .areas
+                .range(start.as_u64()..end)
+                .any(|(_, vma)| vma.kind == kind)
+    }
+
+    /// Whether every byte of the range belongs to some area
+    pub fn covers(&self, start: VirtAddr, size_bytes: u64) -> bool {
+        let end = start + size_bytes;
+        let mut addr = start;
+        while addr < end {
+            match self.find(addr) {
+                Some(vma) => addr = vma.end(),
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Lowest page-aligned start of `size_bytes` free bytes within `[lowest, highest)`
+    pub fn find_free(
+        &self,
+        size_bytes: u64,
+        lowest: VirtAddr,
+        highest: VirtAddr,
+    ) -> Option<VirtAddr> {
+        let page_size = PAGE_SIZE as u64;
+        let mut candidate = lowest.align_up(page_size);
+        if let Some(vma) = self.find(candidate) {
+            candidate = vma.end().align_up(page_size);
+        }
+        for vma in self.areas.range(candidate.as_u64()..).map(|(_, vma)| vma) {
+            if vma.start_virt.as_u64() >= candidate.as_u64().saturating_add(size_bytes) {
+                break;
+            }
+            candidate = vma.end().align_up(page_size);
+        }
+        let end = candidate.as_u64().checked_add(size_bytes)?;
+        (end <= highest.as_u64()).then_some(candidate)
+    }
+
+    /// Takes the parts of all areas within the range out of the tree, areas sticking out of it
+    /// are split and keep what lies outside
+    pub fn remove_range(&mut self, start: VirtAddr, size_bytes: u64) -> Vec<Vma> {
+        let end = start + size_bytes;
+        self.split_at(start);
+        self.split_at(end);
+        let starts: Vec<u64> = self
+            .areas
+            .range(start.as_u64()..end.as_u64())
+            .map(|(start, _)| *start)
+            .collect();
+        starts
+            .into_iter()
+            .filter_map(|start| self.areas.remove(&start))
+            .collect()
+    }
+
+    /// Gives every page in the range the leaf flags `page_flags`, splitting the areas sticking
+    /// out of it. Returns the areas now in the range.
+    pub fn protect(
+        &mut self,
+        start: VirtAddr,
+        size_bytes: u64,
+        page_flags: PageTableFlags,
+    ) -> Vec<Vma> {
+        let end = start + size_bytes;
+        self.split_at(start);
+        self.split_at(end);
+        self.areas
+            .range_mut(start.as_u64()..end.as_u64())
+            .map(|(_, vma)| {
+                vma.page_flags = page_flags;
+                vma.clone()
+            })
+            .collect()
+    }
+
+    /// Makes sure no area crosses `addr`
+    fn split_at(&mut self, addr: VirtAddr) {
+        let Some(vma) = self
+            .areas
+            .range_mut(..addr.as_u64())
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+        else {
+            return;
+        };
+        let upper = vma.split_off(addr);
+        self.areas.insert(addr.as_u64(), upper);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+
+    /// Empties the tree, returning every area
+    pub fn take_all(&mut self) -> Vec<Vma> {
+        core::mem::take(&mut self.areas).into_values().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.areas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.areas.is_empty()
+    }
+}
@@ -1,9 +1,17 @@
 use crate::{
     events::event_buffer::{AsciiChar, EVENT_BUFFER, Keys},
+    memory::allocator,
+    process::{
+        file_table::OpenFile,
+        pipe::pipe,
+        process_manager::{ARCHE_PID, PROCESS_MANAGER},
+        signal::SIGINT,
+        task::{DEFAULT_USER_PRIORITY, PID},
+    },
     serial_println,
 };
 
-use alloc::format;
+use alloc::{format, sync::Arc, vec::Vec};
 use core::cmp::min;
 use embedded_graphics::{
     mono_font::{MonoFont, MonoTextStyle, ascii::FONT_8X13},
@@ -11,8 +19,11 @@ use embedded_graphics::{
     prelude::*,
     text::{Alignment, LineHeight, Text, TextStyle, TextStyleBuilder},
 };
+use spin::Mutex;
 
 const DEBUG_PRINT: bool = false;
+/// Leaks `heap leaks` lists on screen, serial gets all of them
+const HEAP_LEAKS_SHOWN: usize = 10;
 
 const CHARACTER_WIDTH: usize = 8; // FONT_8X13 width
 const CHARACTER_HEIGHT: usize = 13;
@@ -146,6 +157,8 @@ pub struct Theophe<D: DrawTarget<Color = Rgb888>> {
     max_chars_per_line: usize,
     lines: [Line; MAX_LINES],
     last_command: Line,
+    /// Processes of the last pipeline, Ctrl+C interrupts them
+    foreground: Vec<PID>,
 }
 
 impl<D: DrawTarget<Color = Rgb888>> Theophe<D> {
@@ -160,6 +173,7 @@ impl<D: DrawTarget<Color = Rgb888>> Theophe<D> {
             max_chars_per_line,
             lines: [Line::new(); MAX_LINES],
             last_command: Line::new(),
+            foreground: Vec::new(),
         }
     }
 
@@ -168,7 +182,7 @@ impl<D: DrawTarget<Color = Rgb888>> Theophe<D> {
     }
 
     pub fn update(&mut self) {
-        let mut dirty = false;
+        let mut dirty = self.report_exited_children();
         loop {
             let event = EVENT_BUFFER.read();
             match event {
@@ -179,6 +193,7 @@ impl<D: DrawTarget<Color = Rgb888>> Theophe<D> {
                     } else if let Some(c) = char::from_u32(v) {
                         match c {
                             AsciiChar::BACKSPACE => self.backspace(),
+                            AsciiChar::END_OF_TEXT => self.interrupt_foreground(),
                             AsciiChar::NEWLINE | AsciiChar::CARRIAGE_RETURN => {
                                 self.last_command = self.lines[self.curr_line_idx];
                                 let cmd = self.last_command;
@@ -201,6 +216,38 @@ impl<D: DrawTarget<Color = Rgb888>> Theophe<D> {
         }
     }
 
+    /// Reaps the programs started with `run` and prints how they exited
+    fn report_exited_children(&mut self) -> bool {
+        let mut reported = false;
+        loop {
+            let reaped = PROCESS_MANAGER.lock().reap_child(ARCHE_PID, None);
+            let Ok(Some(status)) = reaped else {
+                return reported;
+            };
+            match status.fault {
+                Some(fault) => self.write_line(&format!(
+                    "PID {} exited with code {} ({})",
+                    status.pid, status.exit_code, fault
+                )),
+                None => self.write_line(&format!(
+                    "PID {} exited with code {}",
+                    status.pid, status.exit_code
+                )),
+            }
+            reported = true;
+        }
+    }
+
+    /// Sends `SIGINT` to the processes of the last pipeline, like Ctrl+C in any other shell
+    fn interrupt_foreground(&mut self) {
+        self.write_line("^C");
+        let mut pm = PROCESS_MANAGER.lock();
+        for pid in self.foreground.drain(..) {
+            // the ones already reaped are simply gone
+            let _ = pm.send_signal(pid, SIGINT);
+        }
+    }
+
     fn execute_command(&mut self, line: &Line) {
         let s = line.as_str().trim();
         if s.is_empty() {
@@ -294,6 +341,13 @@ impl<D: DrawTarget<Color = Rgb888>> Theophe<D> {
                     }
                 }
             }
+            "run" => {
+                if args.is_empty() {
+                    self.write_line("usage: run <path> [args...] [| <path> [args...]]...");
+                } else {
+                    self.run_pipeline(args);
+                }
+            }
             "demo" => match args {
                 "start" | "start -uv" => {
                     let uv = args == "start -uv";
@@ -311,15 +365,123 @@ impl<D: DrawTarget<Color = Rgb888>> Theophe<D> {
                 }
                 _ => self.write_line("usage: demo start [-uv] | stop"),
             },
+            "heap" => match args {
+                "" => self.show_heap_stats(),
+                "debug on" | "debug off" => {
+                    allocator::set_heap_debug(args == "debug on");
+                    self.write_line(&format!("heap: {}", args));
+                }
+                "leaks" => self.show_heap_leaks(),
+                _ => self.write_line("usage: heap [debug on | debug off | leaks]"),
+            },
             "help" => {
                 self.write_str(
-                    "Available commands: \n - ls [-l] [dir]\n - cat <path>\n - mkdir <path>\n - demo start [-uv] | stop\n",
+                    "Available commands: \n - ls [-l] [dir]\n - cat <path>\n - mkdir <path>\n - run <path> [args...] [| <path> [args...]]...\n - demo start [-uv] | stop\n - heap [debug on | debug off | leaks]\n",
                 );
             }
             _ => {}
         }
     }
 
+    fn show_heap_stats(&mut self) {
+        let stats = allocator::heap_stats();
+        self.write_line(&format!(
+            "heap: {} KiB used of {} KiB, peak {} KiB",
+            stats.heap_used / 1024,
+            stats.heap_size / 1024,
+            stats.peak_heap_used / 1024
+        ));
+        self.write_line(&format!("{:>6} {:>9} {:>9}", "block", "allocated", "free"));
+        for class in stats.size_classes {
+            self.write_line(&format!(
+                "{:>6} {:>9} {:>9}",
+                class.block_size, class.allocated, class.free
+            ));
+        }
+        self.write_line(&format!("{:>6} {:>9}", "large", stats.large_allocations));
+        if stats.debug_enabled || stats.tracked_allocations > 0 {
+            self.write_line(&format!(
+                "debug {}: {} tracked, {} untracked, {} corrupted",
+                if stats.debug_enabled { "on" } else { "off" },
+                stats.tracked_allocations,
+                stats.untracked_allocations,
+                stats.corruptions
+            ));
+        }
+    }
+
+    /// Lists the oldest live allocations made in debug mode, all of them go to serial
+    fn show_heap_leaks(&mut self) {
+        let leaks = allocator::heap_leaks();
+        self.write_line(&format!(
+            "{} live tracked allocations, full list on serial",
+            leaks.len()
+        ));
+        for record in leaks.iter().take(HEAP_LEAKS_SHOWN) {
+            // the innermost frames are the allocator's own, the last one is the furthest out
+            let caller = record.call_site.iter().rev().find(|addr| **addr != 0);
+            self.write_line(&format!(
+                "#{} {:#x} {} bytes from {:#x}",
+                record.sequence,
+                record.ptr,
+                record.size,
+                caller.copied().unwrap_or(0)
+            ));
+        }
+        allocator::dump_heap_leaks();
+    }
+
+    /// Starts every `|` separated command of `line`, each one's stdout feeds the next one's
+    /// stdin. Stops at the first command which fails to start.
+    fn run_pipeline(&mut self, line: &str) {
+        let commands: Vec<&str> = line.split('|').map(str::trim).collect();
+        if commands.iter().any(|command| command.is_empty()) {
+            self.write_line("run: empty command in pipeline");
+            return;
+        }
+
+        let mut messages = Vec::new();
+        self.foreground.clear();
+        {
+            // the processes can not run before their descriptors are wired up,
+            // the scheduler does not switch while the process manager is locked
+            let mut pm = PROCESS_MANAGER.lock();
+            let mut previous_reader = None;
+            for (index, command) in commands.iter().enumerate() {
+                // the program gets its own path as argv[0], like from any other shell
+                let argv: Vec<&str> = command.split_whitespace().collect();
+                let path = argv[0];
+                let pid =
+                    match pm.create_process(ARCHE_PID, DEFAULT_USER_PRIORITY, path, true, &argv) {
+                        Ok(pid) => pid,
+                        Err(e) => {
+                            messages.push(format!("run: failed to start {}: {:?}", path, e));
+                            break;
+                        }
+                    };
+
+                let table = &mut pm
+                    .get_process_mut(pid)
+                    .expect("process was just created")
+                    .file_descriptors;
+                if let Some(reader) = previous_reader.take() {
+                    table.set(0, Arc::new(Mutex::new(OpenFile::pipe_reader(reader))));
+                }
+                if index + 1 < commands.len() {
+                    let (reader, writer) = pipe();
+                    table.set(1, Arc::new(Mutex::new(OpenFile::pipe_writer(writer))));
+                    previous_reader = Some(reader);
+                }
+                self.foreground.push(pid);
+                messages.push(format!("started {} with PID {}", path, pid));
+            }
+        }
+
+        for message in messages {
+            self.write_line(&message);
+        }
+    }
+
     fn recall_last_command(&mut self) {
         if !self.last_command.is_empty() {
             self.lines[self.curr_line_idx] = self.last_command;
@@ -65,11 +65,19 @@ pub fn run_all_tests() -> ! {
 }
 
 /// Initialises GDT, IDT, paging, and heap — sufficient for most integration tests.
+/// The frame allocator is handed on to the memory globals, so the heap can grow.
 pub fn init_with_heap(hhdm_offset: u64, memory_map: &'static [&'static limine::memmap::Entry]) {
     crate::init_globals();
     let mut mapper = unsafe { crate::memory::paging::init_offset_page_table(hhdm_offset) };
     let mut frame_allocator =
-        unsafe { crate::memory::paging::MemoryMapFrameAllocator::init(memory_map) };
+        unsafe { crate::memory::paging::MemoryMapFrameAllocator::init(memory_map, hhdm_offset) };
     crate::memory::allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap init failed");
+
+    let (kernel_page_table, _) = x86_64::registers::control::Cr3::read();
+    let user_mem_mgr = crate::memory::usermem::UserMemoryManager::new(
+        kernel_page_table.start_address(),
+        hhdm_offset,
+    );
+    crate::memory::init_memory_globals(frame_allocator, user_mem_mgr);
 }
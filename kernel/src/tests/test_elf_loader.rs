@@ -7,13 +7,17 @@ extern crate kernel;
 use core::panic::PanicInfo;
 use kernel::{
     LIMINE_BASE_REVISION,
-    process::elf_loader::{ElfLoadError, ElfLoadInfo},
+    process::{
+        elf_loader::{ElfLoadError, ElfLoadInfo, PIE_LOAD_BASE},
+        initial_stack::{AT_NULL, AT_PAGESZ, AT_RANDOM, build_initial_stack},
+    },
     testing::{test_case, test_panic_handler},
 };
 use limine::{
     BaseRevision, RequestsEndMarker, RequestsStartMarker,
     request::{HhdmRequest, MemmapRequest},
 };
+use x86_64::VirtAddr;
 
 #[used]
 #[unsafe(link_section = ".requests_start_marker")]
@@ -86,3 +90,103 @@ fn reject_empty() {
         Err(ElfLoadError::ParseError(_))
     ));
 }
+
+#[test_case]
+fn crafted_headers_are_rejected() {
+    let read_u64 = |elf: &[u8], at: usize| u64::from_le_bytes(elf[at..at + 8].try_into().unwrap());
+
+    // e_entry far outside the image
+    let mut elf = FIRST_ELF.to_vec();
+    elf[24..32].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(matches!(
+        ElfLoadInfo::from_elf_data(&elf),
+        Err(ElfLoadError::InvalidEntryPoint(_))
+    ));
+
+    // p_memsz of the first PT_LOAD running past the end of the address space
+    let mut elf = FIRST_ELF.to_vec();
+    let program_headers = read_u64(&elf, 32) as usize;
+    let header_size = u16::from_le_bytes([elf[54], elf[55]]) as usize;
+    let load = (0..)
+        .map(|index| program_headers + index * header_size)
+        .find(|at| u32::from_le_bytes(elf[*at..*at + 4].try_into().unwrap()) == 1)
+        .unwrap();
+    elf[load + 40..load + 48].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(matches!(
+        ElfLoadInfo::from_elf_data(&elf),
+        Err(ElfLoadError::InvalidSegment(_))
+    ));
+}
+
+#[test_case]
+fn segments_keep_flags_and_do_not_share_pages() {
+    let info = ElfLoadInfo::from_elf_data(FIRST_ELF).expect("valid ELF should parse");
+    assert!(
+        info.segments.iter().any(|s| s.is_executable()),
+        "code should be executable"
+    );
+    for pair in info.segments.windows(2) {
+        let prev_end = pair[0].vaddr + pair[0].in_memory_size;
+        assert!(
+            pair[1].vaddr >= prev_end.next_multiple_of(4096),
+            "segments must not share a page"
+        );
+    }
+}
+
+#[test_case]
+fn position_independent_image_moves_with_its_base() {
+    let slide = 0x10_0000;
+    let low = ElfLoadInfo::from_elf_data_at(FIRST_ELF, PIE_LOAD_BASE).unwrap();
+    let high = ElfLoadInfo::from_elf_data_at(FIRST_ELF, PIE_LOAD_BASE + slide).unwrap();
+
+    assert!(low.min_vaddr >= PIE_LOAD_BASE);
+    assert_eq!(high.entry_point - low.entry_point, slide);
+    assert_eq!(
+        high.program_headers_vaddr - low.program_headers_vaddr,
+        slide
+    );
+    for (low, high) in low.segments.iter().zip(&high.segments) {
+        assert_eq!(high.vaddr - low.vaddr, slide);
+    }
+}
+
+#[test_case]
+fn program_headers_are_found() {
+    let info = ElfLoadInfo::from_elf_data(FIRST_ELF).expect("valid ELF should parse");
+    assert!(info.program_header_count > 0);
+    assert!(
+        info.segments
+            .iter()
+            .any(|s| { (s.vaddr..s.vaddr + s.in_file_size).contains(&info.program_headers_vaddr) }),
+        "program headers should be loaded with a segment"
+    );
+}
+
+#[test_case]
+fn initial_stack_follows_the_abi() {
+    let top = VirtAddr::new(0x7fff_0000_0000);
+    let stack = build_initial_stack(top, &["prog", "-v"], &["HOME=/"], &[(AT_PAGESZ, 4096)]);
+    assert!(stack.rsp.is_aligned(16u64));
+    assert_eq!(stack.bytes.len() as u64, top - stack.rsp);
+
+    let word = |index: usize| {
+        u64::from_ne_bytes(stack.bytes[index * 8..index * 8 + 8].try_into().unwrap())
+    };
+    let string_at = |addr: u64| {
+        let start = (addr - stack.rsp.as_u64()) as usize;
+        let len = stack.bytes[start..].iter().position(|&b| b == 0).unwrap();
+        core::str::from_utf8(&stack.bytes[start..start + len]).unwrap()
+    };
+
+    assert_eq!(word(0), 2);
+    assert_eq!(string_at(word(1)), "prog");
+    assert_eq!(string_at(word(2)), "-v");
+    assert_eq!(word(3), 0);
+    assert_eq!(string_at(word(4)), "HOME=/");
+    assert_eq!(word(5), 0);
+    assert_eq!((word(6), word(7)), (AT_PAGESZ, 4096));
+    assert_eq!(word(8), AT_RANDOM);
+    assert!((stack.rsp.as_u64()..top.as_u64() - 16).contains(&word(9)));
+    assert_eq!((word(10), word(11)), (AT_NULL, 0));
+}
@@ -4,16 +4,19 @@
 extern crate alloc;
 extern crate kernel;
 
+use alloc::sync::Arc;
 use core::panic::PanicInfo;
 use kernel::{
     LIMINE_BASE_REVISION,
     filesystem::{fat32::test_data::create_fat32_image, init_filesystem, sirius::get_sirius},
+    process::file_table::{FileDescriptorTable, OpenFile, OpenFlags},
     testing::{test_case, test_panic_handler},
 };
 use limine::{
     BaseRevision, RequestsEndMarker, RequestsStartMarker,
     request::{HhdmRequest, MemmapRequest},
 };
+use spin::Mutex;
 
 #[used]
 #[unsafe(link_section = ".requests_start_marker")]
@@ -106,3 +109,38 @@ fn test_create_and_delete() {
         "newfile.txt still present after delete"
     );
 }
+
+#[test_case]
+fn open_file_reads_from_its_cursor() {
+    let node = get_sirius()
+        .open_file("HELLO.TXT")
+        .expect("open_file failed");
+    let mut file = OpenFile::file(node, OpenFlags::READ);
+
+    let mut whole = [0u8; 64];
+    let size = file.read(&mut whole, false).expect("read failed");
+    assert_eq!(file.offset, size);
+    assert_eq!(file.size().unwrap(), size);
+
+    file.offset = 1;
+    let mut rest = [0u8; 64];
+    let read = file.read(&mut rest, false).expect("read failed");
+    assert_eq!(&rest[..read], &whole[1..size]);
+    assert!(file.write(b"x").is_err(), "file was opened read only");
+}
+
+#[test_case]
+fn descriptors_start_with_the_console_and_are_reused() {
+    let mut table = FileDescriptorTable::with_console();
+    assert_eq!(table.open_count(), 3);
+    assert!(Arc::ptr_eq(&table.get(1).unwrap(), &table.get(2).unwrap()));
+
+    let node = get_sirius()
+        .open_file("HELLO.TXT")
+        .expect("open_file failed");
+    let file = Arc::new(Mutex::new(OpenFile::file(node, OpenFlags::READ)));
+    assert_eq!(table.insert(file.clone()), Some(3));
+    assert!(table.remove(0).is_some());
+    assert_eq!(table.insert(file), Some(0));
+    assert!(table.remove(7).is_none());
+}
@@ -8,6 +8,10 @@ use alloc::{boxed::Box, vec::Vec};
 use core::panic::PanicInfo;
 use kernel::{
     LIMINE_BASE_REVISION,
+    memory::{
+        allocator::{heap_leaks, heap_stats, set_heap_debug},
+        get_frame_allocator,
+    },
     testing::{test_case, test_panic_handler},
 };
 use limine::{
@@ -87,3 +91,66 @@ fn no_leak() {
         let _ = Vec::<u8>::with_capacity(alloc_size);
     }
 }
+
+#[test_case]
+fn heap_grows_past_its_initial_size() {
+    let size = kernel::memory::allocator::HEAP_SIZE_BYTES * 2;
+    let mut buffer = Vec::<u8>::with_capacity(size);
+    buffer.resize(size, 0xA5);
+    assert_eq!(buffer[0], 0xA5);
+    assert_eq!(buffer[size - 1], 0xA5);
+}
+
+#[test_case]
+fn heap_grows_while_the_frame_allocator_is_held() {
+    let frame_allocator = get_frame_allocator();
+    let stats = heap_stats();
+    // just past what is left, one grow step covers it
+    let size = stats.heap_size - stats.heap_used + 64 * 1024;
+    let buffer = Vec::<u8>::with_capacity(size);
+    assert!(heap_stats().heap_size > stats.heap_size);
+    drop(frame_allocator);
+    drop(buffer);
+}
+
+#[test_case]
+fn size_class_counts_follow_allocations() {
+    // 32 byte blocks
+    let class = 2;
+    let before = heap_stats().size_classes[class];
+    assert_eq!(before.block_size, 32);
+    let block = Box::new([0u8; 32]);
+    assert_eq!(
+        heap_stats().size_classes[class].allocated,
+        before.allocated + 1
+    );
+    drop(block);
+    let after = heap_stats().size_classes[class];
+    assert_eq!(after.allocated, before.allocated);
+    assert!(after.free >= 1);
+    assert!(heap_stats().peak_heap_used >= heap_stats().heap_used);
+}
+
+#[test_case]
+fn debug_mode_tracks_allocations_and_catches_overflows() {
+    set_heap_debug(true);
+    let live = Box::new(7u64);
+    let live_ptr = &*live as *const u64 as usize;
+    assert!(
+        heap_leaks()
+            .iter()
+            .any(|record| record.ptr == live_ptr && record.size == 8 && record.call_site[0] != 0)
+    );
+
+    let corruptions = heap_stats().corruptions;
+    let mut buffer = Vec::<u8>::with_capacity(16);
+    // one byte past the end lands in the back redzone
+    unsafe { buffer.as_mut_ptr().add(16).write(0) };
+    drop(buffer);
+    assert_eq!(heap_stats().corruptions, corruptions + 1);
+
+    set_heap_debug(false);
+    // still recognized as tracked after debug mode is off
+    drop(live);
+    assert!(!heap_leaks().iter().any(|record| record.ptr == live_ptr));
+}
@@ -50,7 +50,7 @@ extern "C" fn kmain() -> ! {
         .entries();
     init_globals();
     let mut mapper = unsafe { init_offset_page_table(hhdm_offset) };
-    let mut frame_allocator = unsafe { MemoryMapFrameAllocator::init(memory_map) };
+    let mut frame_allocator = unsafe { MemoryMapFrameAllocator::init(memory_map, hhdm_offset) };
 
     serial_print!("test_page_mapping::create_mapping...\t");
     test_create_mapping(&mut mapper, &mut frame_allocator);
@@ -0,0 +1,316 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+extern crate kernel;
+
+use core::panic::PanicInfo;
+use kernel::{
+    LIMINE_BASE_REVISION,
+    memory::{get_user_mem_mgr, stack},
+    process::{
+        ProcessManager,
+        fault::{Exception, Fault},
+        fpu::{self, FpuState},
+        process_manager::ARCHE_PID,
+        scheduler::{SCHEDULER_BOOST_TICKS, SCHEDULER_QUANTUM_TICKS, Scheduler},
+        signal::{SIGSEGV, exit_code},
+        task::{
+            KERNEL_STACK_SIZE, KernelStack, MAX_PRIORITY, Process, ProcessResources, ProcessState,
+        },
+    },
+    testing::{test_case, test_panic_handler},
+};
+use limine::{
+    BaseRevision, RequestsEndMarker, RequestsStartMarker,
+    request::{HhdmRequest, MemmapRequest},
+};
+
+#[used]
+#[unsafe(link_section = ".requests_start_marker")]
+static _START: RequestsStartMarker = RequestsStartMarker::new();
+#[used]
+#[unsafe(link_section = ".requests")]
+static BASE_REVISION: BaseRevision = BaseRevision::with_revision(LIMINE_BASE_REVISION);
+#[used]
+#[unsafe(link_section = ".requests")]
+static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
+#[used]
+#[unsafe(link_section = ".requests")]
+static MEMORY_MAP_REQUEST: MemmapRequest = MemmapRequest::new();
+#[used]
+#[unsafe(link_section = ".requests_end_marker")]
+static _END: RequestsEndMarker = RequestsEndMarker::new();
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kmain() -> ! {
+    assert!(BASE_REVISION.is_supported());
+    let hhdm_offset = HHDM_REQUEST.response().expect("no HHDM").offset;
+    let memory_map = MEMORY_MAP_REQUEST
+        .response()
+        .expect("no memory map")
+        .entries();
+    kernel::testing::init_with_heap(hhdm_offset, memory_map);
+    kernel::testing::run_all_tests()
+}
+
+#[test_case]
+fn round_robin_order() {
+    let mut scheduler = Scheduler::new();
+    scheduler.enqueue(1, 1);
+    scheduler.enqueue(2, 1);
+    scheduler.enqueue(3, 1);
+
+    let first = scheduler.pick_next().unwrap();
+    scheduler.enqueue(first, 1);
+    assert_eq!(scheduler.pick_next(), Some(2));
+    assert_eq!(scheduler.pick_next(), Some(3));
+    assert_eq!(scheduler.pick_next(), Some(1));
+    assert_eq!(scheduler.pick_next(), None);
+}
+
+#[test_case]
+fn higher_priority_first() {
+    let mut scheduler = Scheduler::new();
+    scheduler.enqueue(1, 1);
+    scheduler.enqueue(2, MAX_PRIORITY);
+    scheduler.enqueue(3, 5);
+    scheduler.enqueue(4, MAX_PRIORITY);
+
+    assert_eq!(scheduler.highest_ready_priority(), Some(MAX_PRIORITY));
+    assert_eq!(scheduler.pick_next(), Some(2));
+    assert_eq!(scheduler.pick_next(), Some(4));
+    assert_eq!(scheduler.pick_next(), Some(3));
+    assert_eq!(scheduler.pick_next(), Some(1));
+}
+
+#[test_case]
+fn enqueue_is_idempotent() {
+    let mut scheduler = Scheduler::new();
+    scheduler.enqueue(7, 1);
+    scheduler.enqueue(7, 3);
+    assert_eq!(scheduler.ready_count(), 1);
+    assert_eq!(scheduler.highest_ready_priority(), Some(3));
+}
+
+#[test_case]
+fn removed_pid_is_not_picked() {
+    let mut scheduler = Scheduler::new();
+    scheduler.enqueue(1, 1);
+    scheduler.enqueue(2, 1);
+    scheduler.remove(1);
+    assert_eq!(scheduler.pick_next(), Some(2));
+    assert_eq!(scheduler.pick_next(), None);
+}
+
+#[test_case]
+fn quantum_expires() {
+    let mut scheduler = Scheduler::new();
+    scheduler.set_current(1, 0);
+    for _ in 1..SCHEDULER_QUANTUM_TICKS {
+        assert!(!scheduler.tick());
+    }
+    assert!(scheduler.tick());
+}
+
+#[test_case]
+fn custom_time_slice() {
+    let mut scheduler = Scheduler::new();
+    scheduler.set_current(1, 2);
+    assert!(!scheduler.tick());
+    assert!(scheduler.tick());
+}
+
+#[test_case]
+fn boost_restores_base_priority() {
+    let mut scheduler = Scheduler::new();
+    scheduler.enqueue(1, 0);
+    scheduler.enqueue(2, 3);
+
+    let mut boosted = false;
+    for _ in 0..SCHEDULER_BOOST_TICKS {
+        boosted |= scheduler.boost_due();
+    }
+    assert!(boosted);
+
+    scheduler.boost(|pid| if pid == 1 { 8 } else { 3 });
+    assert_eq!(scheduler.pick_next(), Some(1));
+}
+
+#[test_case]
+fn kernel_stacks_are_separate() {
+    let first = KernelStack::new().unwrap();
+    let second = KernelStack::new().unwrap();
+
+    assert!(first.top().is_aligned(16u64));
+    assert!(second.top().is_aligned(16u64));
+    assert!(first.top().as_u64().abs_diff(second.top().as_u64()) >= KERNEL_STACK_SIZE as u64);
+
+    // right below each stack lies its unmapped guard
+    let user_mem_mgr = get_user_mem_mgr();
+    let kernel_page_table = user_mem_mgr.kernel_page_table_phys;
+    let below = first.bottom() - 8u64;
+    assert!(user_mem_mgr.is_page_mapped(kernel_page_table, first.bottom()));
+    assert!(!user_mem_mgr.is_page_mapped(kernel_page_table, below));
+    assert!(stack::is_stack_overflow(
+        below.as_u64(),
+        first.bottom().as_u64()
+    ));
+    assert!(!stack::is_stack_overflow(
+        below.as_u64(),
+        second.bottom().as_u64()
+    ));
+}
+
+#[test_case]
+fn faulted_child_is_reaped_with_its_fault() {
+    let mut pm = ProcessManager::new();
+    pm.init_arche();
+    let page_table = x86_64::registers::control::Cr3::read().0.start_address();
+    let mut child = Process::new_kernel_task(
+        pm.allocate_pid(),
+        "child",
+        1,
+        ProcessResources::default(),
+        page_table,
+    );
+    child.parent_pid = ARCHE_PID;
+    let child = pm.spawn(child);
+
+    let fault = Fault {
+        exception: Exception::GeneralProtection(0),
+        rip: 0x40_1000,
+    };
+    assert_eq!(fault.exception.signal(), SIGSEGV);
+    pm.terminate_for_fault(child, fault, SIGSEGV).unwrap();
+    // the parent is untouched and the child no longer scheduled
+    assert!(pm.get_process(ARCHE_PID).is_ok());
+    assert!(!core::iter::from_fn(|| pm.scheduler.pick_next()).any(|pid| pid == child));
+
+    let status = pm.reap_child(ARCHE_PID, Some(child)).unwrap().unwrap();
+    assert_eq!(status.pid, child);
+    assert_eq!(status.exit_code, exit_code(SIGSEGV));
+    assert_eq!(status.fault, Some(fault));
+}
+
+fn read_mxcsr() -> u32 {
+    let mut mxcsr = 0u32;
+    unsafe { core::arch::asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack)) };
+    mxcsr
+}
+
+fn write_mxcsr(mxcsr: u32) {
+    unsafe { core::arch::asm!("ldmxcsr [{}]", in(reg) &mxcsr, options(nostack)) };
+}
+
+#[test_case]
+fn fpu_state_follows_the_task_using_it() {
+    let state = FpuState::new();
+    assert_eq!(state.control_word(), 0x37F);
+    assert_eq!(state.mxcsr(), 0x1F80);
+    assert!(state.size() >= fpu::area_size() && fpu::area_size() >= 512);
+
+    let mut pm = ProcessManager::new();
+    pm.init_arche();
+    let page_table = x86_64::registers::control::Cr3::read().0.start_address();
+    let child = pm.allocate_pid();
+    pm.spawn(Process::new_kernel_task(
+        child,
+        "child",
+        1,
+        ProcessResources::default(),
+        page_table,
+    ));
+
+    pm.scheduler.set_current(ARCHE_PID, 0);
+    pm.claim_fpu();
+    // flush to zero, something a default state does not have
+    write_mxcsr(0x9F80);
+
+    pm.scheduler.set_current(child, 0);
+    pm.claim_fpu();
+    assert_eq!(read_mxcsr(), 0x1F80);
+    assert_eq!(pm.get_process(ARCHE_PID).unwrap().fpu_state.mxcsr(), 0x9F80);
+
+    pm.scheduler.set_current(ARCHE_PID, 0);
+    pm.claim_fpu();
+    assert_eq!(read_mxcsr(), 0x9F80);
+    write_mxcsr(0x1F80);
+}
+
+#[test_case]
+fn sleepers_wake_once_their_deadline_passes() {
+    let mut pm = ProcessManager::new();
+    pm.init_arche();
+    let page_table = x86_64::registers::control::Cr3::read().0.start_address();
+    let mut spawn_task = |name| {
+        let pid = pm.allocate_pid();
+        pm.spawn(Process::new_kernel_task(
+            pid,
+            name,
+            1,
+            ProcessResources::default(),
+            page_table,
+        ))
+    };
+    let early = spawn_task("early");
+    let late = spawn_task("late");
+    while pm.scheduler.pick_next().is_some() {}
+
+    pm.sleep_until(late, 2_000).unwrap();
+    pm.sleep_until(early, 1_000).unwrap();
+    pm.wake_expired(500);
+    assert_eq!(pm.get_process(early).unwrap().state, ProcessState::Waiting);
+
+    pm.wake_expired(1_500);
+    assert_eq!(pm.get_process(early).unwrap().state, ProcessState::Ready);
+    assert_eq!(pm.get_process(late).unwrap().state, ProcessState::Waiting);
+    assert_eq!(pm.scheduler.pick_next(), Some(early));
+
+    // sleeping again makes the old deadline stale
+    pm.sleep_until(late, 3_000).unwrap();
+    pm.wake_expired(2_500);
+    assert_eq!(pm.get_process(late).unwrap().state, ProcessState::Waiting);
+    pm.wake_expired(3_000);
+    assert_eq!(pm.get_process(late).unwrap().state, ProcessState::Ready);
+    assert_eq!(pm.get_process(late).unwrap().wake_at, None);
+}
+
+#[test_case]
+fn only_parents_in_wait_pid_are_woken_by_an_exiting_child() {
+    let mut pm = ProcessManager::new();
+    pm.init_arche();
+    let page_table = x86_64::registers::control::Cr3::read().0.start_address();
+    let mut spawn_task = |name, parent_pid| {
+        let pid = pm.allocate_pid();
+        let mut process =
+            Process::new_kernel_task(pid, name, 1, ProcessResources::default(), page_table);
+        if let Some(parent_pid) = parent_pid {
+            process.parent_pid = parent_pid;
+        }
+        pm.spawn(process)
+    };
+    let parent = spawn_task("parent", None);
+    let sleepy_child = spawn_task("sleepy child", Some(parent));
+    let child = spawn_task("child", Some(parent));
+    while pm.scheduler.pick_next().is_some() {}
+
+    // a sleeping parent keeps sleeping until its deadline
+    pm.sleep_until(parent, 1_000).unwrap();
+    pm.terminate_process(sleepy_child, 0, false).unwrap();
+    assert_eq!(pm.get_process(parent).unwrap().state, ProcessState::Waiting);
+    pm.wake_expired(1_000);
+    assert_eq!(pm.get_process(parent).unwrap().state, ProcessState::Ready);
+    while pm.scheduler.pick_next().is_some() {}
+
+    pm.wait_for_child(parent).unwrap();
+    pm.terminate_process(child, 0, false).unwrap();
+    assert_eq!(pm.get_process(parent).unwrap().state, ProcessState::Ready);
+    assert!(!pm.get_process(parent).unwrap().waits_for_child);
+    assert_eq!(pm.scheduler.pick_next(), Some(parent));
+}
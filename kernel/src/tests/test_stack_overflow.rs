@@ -4,14 +4,24 @@
 
 extern crate kernel;
 
+use core::arch::asm;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
 use kernel::{
-    LIMINE_BASE_REVISION, gdt, serial_print,
+    LIMINE_BASE_REVISION, gdt,
+    memory::stack::{self, GuardedStack},
+    serial_print,
     testing::{QemuExitCode, exit_qemu, test_panic_handler},
 };
 use lazy_static::lazy_static;
-use limine::{BaseRevision, RequestsEndMarker, RequestsStartMarker};
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use limine::{
+    BaseRevision, RequestsEndMarker, RequestsStartMarker,
+    request::{HhdmRequest, MemmapRequest},
+};
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+};
 
 #[used]
 #[unsafe(link_section = ".requests_start_marker")]
@@ -20,9 +30,18 @@ static _START: RequestsStartMarker = RequestsStartMarker::new();
 #[unsafe(link_section = ".requests")]
 static BASE_REVISION: BaseRevision = BaseRevision::with_revision(LIMINE_BASE_REVISION);
 #[used]
+#[unsafe(link_section = ".requests")]
+static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
+#[used]
+#[unsafe(link_section = ".requests")]
+static MEMORY_MAP_REQUEST: MemmapRequest = MemmapRequest::new();
+#[used]
 #[unsafe(link_section = ".requests_end_marker")]
 static _END: RequestsEndMarker = RequestsEndMarker::new();
 
+/// Bottom of the guarded stack the recursion runs on
+static STACK_BOTTOM: AtomicU64 = AtomicU64::new(0);
+
 lazy_static! {
     static ref TEST_IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
@@ -43,9 +62,30 @@ fn panic(info: &PanicInfo) -> ! {
 #[unsafe(no_mangle)]
 extern "C" fn kmain() -> ! {
     assert!(BASE_REVISION.is_supported());
+    let hhdm_offset = HHDM_REQUEST.response().expect("no HHDM").offset;
+    let memory_map = MEMORY_MAP_REQUEST
+        .response()
+        .expect("no memory map")
+        .entries();
+    // also moves the double fault handler onto a guarded stack
+    kernel::testing::init_with_heap(hhdm_offset, memory_map);
     serial_print!("test_stack_overflow::stack_overflow...\t");
-    gdt::init();
     TEST_IDT.load();
+
+    let stack = GuardedStack::new(64 * 1024).expect("failed to map the test stack");
+    STACK_BOTTOM.store(stack.bottom().as_u64(), Ordering::Relaxed);
+    unsafe {
+        asm!(
+            "mov rsp, {top}",
+            "call {entry}",
+            top = in(reg) stack.top().as_u64(),
+            entry = sym overflow_entry,
+            options(noreturn),
+        );
+    }
+}
+
+extern "C" fn overflow_entry() -> ! {
     stack_overflow();
     panic!("execution continued after stack overflow");
 }
@@ -57,9 +97,18 @@ fn stack_overflow() {
 }
 
 extern "x86-interrupt" fn test_double_fault_handler(
-    _frame: InterruptStackFrame,
+    frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
     // TODO: investigate why printing someting here sometimes causes a triple fault instead of a clean exit
-    exit_qemu(QemuExitCode::Success)
+    let addr = Cr2::read_raw();
+    let bottom = STACK_BOTTOM.load(Ordering::Relaxed);
+    // the fault has to be diagnosed as an overflow, right below the stack that overflowed
+    let diagnosed = stack::is_stack_overflow(addr, frame.stack_pointer.as_u64())
+        && addr < bottom
+        && bottom - addr <= 4096;
+    match diagnosed {
+        true => exit_qemu(QemuExitCode::Success),
+        false => exit_qemu(QemuExitCode::Failed),
+    }
 }
@@ -0,0 +1,222 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+extern crate kernel;
+
+use core::panic::PanicInfo;
+use kernel::{
+    LIMINE_BASE_REVISION,
+    process::{
+        pipe::{BrokenPipe, pipe},
+        signal::{
+            Disposition, SIG_IGN, SIGCHLD, SIGINT, SIGKILL, SIGSEGV, SIGTERM, SignalAction,
+            SignalState, signal_bit,
+        },
+        syscall::{SyscallError, SyscallNumber, SystemCall, encode_syscall_result},
+    },
+    testing::{test_case, test_panic_handler},
+};
+use limine::{
+    BaseRevision, RequestsEndMarker, RequestsStartMarker,
+    request::{HhdmRequest, MemmapRequest},
+};
+
+#[used]
+#[unsafe(link_section = ".requests_start_marker")]
+static _START: RequestsStartMarker = RequestsStartMarker::new();
+#[used]
+#[unsafe(link_section = ".requests")]
+static BASE_REVISION: BaseRevision = BaseRevision::with_revision(LIMINE_BASE_REVISION);
+#[used]
+#[unsafe(link_section = ".requests")]
+static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
+#[used]
+#[unsafe(link_section = ".requests")]
+static MEMORY_MAP_REQUEST: MemmapRequest = MemmapRequest::new();
+#[used]
+#[unsafe(link_section = ".requests_end_marker")]
+static _END: RequestsEndMarker = RequestsEndMarker::new();
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kmain() -> ! {
+    assert!(BASE_REVISION.is_supported());
+    let hhdm_offset = HHDM_REQUEST.response().expect("no HHDM").offset;
+    let memory_map = MEMORY_MAP_REQUEST
+        .response()
+        .expect("no memory map")
+        .entries();
+    kernel::testing::init_with_heap(hhdm_offset, memory_map);
+    kernel::testing::run_all_tests()
+}
+
+#[test_case]
+fn every_syscall_number_decodes() {
+    let numbers = [
+        SyscallNumber::CreateProcess,
+        SyscallNumber::TerminateProcess,
+        SyscallNumber::Write,
+        SyscallNumber::Read,
+        SyscallNumber::GetLine,
+        SyscallNumber::Allocate,
+        SyscallNumber::CreateFile,
+        SyscallNumber::RemoveFile,
+        SyscallNumber::LoadFile,
+        SyscallNumber::UnloadFile,
+        SyscallNumber::CreateWindow,
+        SyscallNumber::GetProcessInfo,
+        SyscallNumber::WaitPid,
+        SyscallNumber::Fork,
+        SyscallNumber::Exec,
+        SyscallNumber::Open,
+        SyscallNumber::Close,
+        SyscallNumber::Seek,
+        SyscallNumber::Pipe,
+        SyscallNumber::Dup2,
+        SyscallNumber::SigAction,
+        SyscallNumber::SigReturn,
+        SyscallNumber::SigProcMask,
+        SyscallNumber::Kill,
+        SyscallNumber::Sleep,
+        SyscallNumber::GetTime,
+        SyscallNumber::Mmap,
+        SyscallNumber::Munmap,
+        SyscallNumber::Mprotect,
+        SyscallNumber::Brk,
+        SyscallNumber::Exit,
+    ];
+    for number in numbers {
+        assert_eq!(SyscallNumber::try_from(number as usize), Ok(number));
+        assert!(SystemCall::from_number_and_args(number as usize, 0, 0, 0, 0, 0, 0).is_some());
+    }
+}
+
+#[test_case]
+fn unknown_syscall_is_rejected() {
+    assert_eq!(
+        SyscallNumber::try_from(997),
+        Err(SyscallError::SyscallNotFound)
+    );
+    assert!(SystemCall::from_number_and_args(997, 0, 0, 0, 0, 0, 0).is_none());
+}
+
+#[test_case]
+fn arguments_are_decoded() {
+    let call = SystemCall::from_number_and_args(
+        SyscallNumber::GetProcessInfo as usize,
+        3,
+        0x1000,
+        0,
+        0,
+        0,
+        0,
+    );
+    assert!(matches!(
+        call,
+        Some(SystemCall::GetProcessInfo {
+            pid: 3,
+            info_ptr: 0x1000
+        })
+    ));
+}
+
+#[test_case]
+fn errors_are_negated_in_result() {
+    assert_eq!(encode_syscall_result(Ok(42)), 42);
+    assert_eq!(
+        encode_syscall_result(Err(SyscallError::InvalidFd)) as i64,
+        -(SyscallError::InvalidFd as i64)
+    );
+}
+
+#[test_case]
+fn pipe_delivers_lines_and_reports_closed_ends() {
+    let (reader, writer) = pipe();
+    assert_eq!(writer.write(b"first\nsecond"), Ok(12));
+
+    let mut buffer = [0u8; 32];
+    let read = reader.read(&mut buffer, true);
+    assert_eq!(&buffer[..read], b"first\n");
+    drop(writer);
+    // what was written before the writer closed can still be read, then it is EOF
+    let read = reader.read(&mut buffer, false);
+    assert_eq!(&buffer[..read], b"second");
+    assert_eq!(reader.read(&mut buffer, false), 0);
+
+    let (reader, writer) = pipe();
+    drop(reader);
+    assert_eq!(writer.write(b"lost"), Err(BrokenPipe));
+}
+
+#[test_case]
+fn signals_are_delivered_lowest_first_unless_blocked() {
+    let mut signals = SignalState::new();
+    signals.raise(SIGTERM);
+    signals.raise(SIGINT);
+    signals.raise(SIGCHLD);
+    // SIGCHLD is ignored by default and never becomes pending
+    assert_eq!(signals.pending(), signal_bit(SIGINT) | signal_bit(SIGTERM));
+
+    signals.set_blocked(signal_bit(SIGINT) | signal_bit(SIGKILL));
+    assert_eq!(signals.blocked(), signal_bit(SIGINT));
+    assert_eq!(signals.take_deliverable(), Some(SIGTERM));
+    assert_eq!(signals.take_deliverable(), None);
+
+    signals.set_blocked(0);
+    assert_eq!(signals.take_deliverable(), Some(SIGINT));
+    assert!(!signals.has_deliverable());
+}
+
+#[test_case]
+fn signal_actions_survive_fork_but_not_exec() {
+    let handler = SignalAction {
+        handler: 0x40_1000,
+        mask: signal_bit(SIGTERM),
+        restorer: 0x40_2000,
+    };
+    let ignore = SignalAction {
+        handler: SIG_IGN,
+        ..SignalAction::default()
+    };
+    let mut signals = SignalState::new();
+    assert_eq!(
+        signals.set_action(SIGINT, handler),
+        Some(SignalAction::default())
+    );
+    assert_eq!(
+        signals.set_action(SIGTERM, ignore),
+        Some(SignalAction::default())
+    );
+    assert_eq!(signals.set_action(SIGKILL, handler), None);
+    signals.raise(SIGINT);
+
+    let mut child = signals.fork();
+    assert_eq!(child.pending(), 0);
+    assert_eq!(child.disposition(SIGINT), Disposition::Handle(handler));
+
+    child.reset_handlers();
+    assert_eq!(child.disposition(SIGINT), Disposition::Terminate);
+    assert_eq!(child.disposition(SIGTERM), Disposition::Ignore);
+}
+
+#[test_case]
+fn faults_can_not_be_blocked_or_ignored() {
+    let mut signals = SignalState::new();
+    signals.set_action(
+        SIGSEGV,
+        SignalAction {
+            handler: SIG_IGN,
+            ..SignalAction::default()
+        },
+    );
+    signals.set_blocked(signal_bit(SIGSEGV));
+
+    signals.force(SIGSEGV);
+    assert_eq!(signals.disposition(SIGSEGV), Disposition::Terminate);
+    assert_eq!(signals.take_deliverable(), Some(SIGSEGV));
+}
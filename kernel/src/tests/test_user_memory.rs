@@ -0,0 +1,631 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+extern crate kernel;
+
+use core::panic::PanicInfo;
+use kernel::{
+    LIMINE_BASE_REVISION,
+    memory::{
+        get_frame_allocator, get_user_mem_mgr,
+        paging::{FRAMES_PER_HUGE_PAGE, MemoryMapFrameAllocator, PAGE_SIZE},
+        usermem::{USER_SPACE_END, UserCopyError, UserMemoryManager},
+    },
+    process::{
+        elf_loader::ElfLoadInfo,
+        process_manager::{ARCHE_PID, ProcessError},
+        process_mem::{MMAP_BASE, PageFaultError, ProcessMemoryLayout},
+        syscall::{SyscallError, SyscallFrame, SystemCall, handle_syscall},
+        task::{DEFAULT_NEW_PROCESS_STACK_SIZE, Process},
+        vma::{MapFlags, Protection, Vma, VmaKind},
+    },
+    testing::{test_case, test_panic_handler},
+};
+use limine::{
+    BaseRevision, RequestsEndMarker, RequestsStartMarker,
+    request::{HhdmRequest, MemmapRequest},
+};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    registers::control::Cr3,
+    structures::paging::{FrameAllocator, FrameDeallocator, PageTableFlags},
+};
+
+#[used]
+#[unsafe(link_section = ".requests_start_marker")]
+static _START: RequestsStartMarker = RequestsStartMarker::new();
+#[used]
+#[unsafe(link_section = ".requests")]
+static BASE_REVISION: BaseRevision = BaseRevision::with_revision(LIMINE_BASE_REVISION);
+#[used]
+#[unsafe(link_section = ".requests")]
+static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
+#[used]
+#[unsafe(link_section = ".requests")]
+static MEMORY_MAP_REQUEST: MemmapRequest = MemmapRequest::new();
+#[used]
+#[unsafe(link_section = ".requests_end_marker")]
+static _END: RequestsEndMarker = RequestsEndMarker::new();
+
+// During static analysis (clippy) the user binary may not be built yet.
+#[cfg(not(clippy))]
+const FIRST_ELF: &[u8] = include_bytes!("../../../target/user/programs/first/first");
+#[cfg(clippy)]
+const FIRST_ELF: &[u8] = &[];
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info)
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn kmain() -> ! {
+    assert!(BASE_REVISION.is_supported());
+    let hhdm_offset = HHDM_REQUEST.response().expect("no HHDM").offset;
+    let memory_map = MEMORY_MAP_REQUEST
+        .response()
+        .expect("no memory map")
+        .entries();
+    kernel::init_globals();
+    let mut mapper = unsafe { kernel::memory::paging::init_offset_page_table(hhdm_offset) };
+    let mut frame_allocator = unsafe { MemoryMapFrameAllocator::init(memory_map, hhdm_offset) };
+    kernel::memory::allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap init failed");
+
+    let (kernel_page_table, _) = Cr3::read();
+    let user_mem_mgr = UserMemoryManager::new(kernel_page_table.start_address(), hhdm_offset);
+    kernel::memory::init_memory_globals(frame_allocator, user_mem_mgr);
+
+    kernel::testing::run_all_tests()
+}
+
+const DATA_PAGE: u64 = 0x40_0000;
+const READ_ONLY_PAGE: u64 = 0x40_2000;
+
+/// Address space with two writable pages at `DATA_PAGE` followed by one read-only page
+fn user_address_space() -> PhysAddr {
+    let user_mem_mgr = get_user_mem_mgr();
+    let mut frame_allocator = get_frame_allocator();
+    let page_table = user_mem_mgr
+        .allocate_new_address_space(&mut frame_allocator)
+        .unwrap();
+    user_mem_mgr
+        .map_virt_mem_region(
+            page_table,
+            VirtAddr::new(DATA_PAGE),
+            0x2000,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            &mut frame_allocator,
+        )
+        .unwrap();
+    user_mem_mgr
+        .map_virt_mem_region(
+            page_table,
+            VirtAddr::new(READ_ONLY_PAGE),
+            0x1000,
+            PageTableFlags::PRESENT,
+            &mut frame_allocator,
+        )
+        .unwrap();
+    page_table
+}
+
+#[test_case]
+fn copy_roundtrip_across_pages() {
+    let page_table = user_address_space();
+    let user_mem_mgr = get_user_mem_mgr();
+    let data: [u8; 64] = core::array::from_fn(|i| i as u8);
+    // straddles the boundary between the two data pages
+    let addr = VirtAddr::new(DATA_PAGE + 0x1000 - 32);
+
+    user_mem_mgr.copy_to_user(page_table, addr, &data).unwrap();
+    let mut read_back = [0u8; 64];
+    user_mem_mgr
+        .copy_from_user(page_table, addr, &mut read_back)
+        .unwrap();
+    assert_eq!(data, read_back);
+}
+
+#[test_case]
+fn kernel_address_is_rejected() {
+    let page_table = user_address_space();
+    let user_mem_mgr = get_user_mem_mgr();
+    let kernel_addr = VirtAddr::from_ptr(&HHDM_REQUEST);
+    let mut buffer = [0u8; 8];
+
+    assert_eq!(
+        user_mem_mgr.copy_from_user(page_table, kernel_addr, &mut buffer),
+        Err(UserCopyError::NotUserAddress(kernel_addr))
+    );
+    assert!(
+        user_mem_mgr
+            .translate_user_virt_to_phys(page_table, kernel_addr)
+            .is_none()
+    );
+}
+
+#[test_case]
+fn unmapped_address_is_rejected() {
+    let page_table = user_address_space();
+    let user_mem_mgr = get_user_mem_mgr();
+    let unmapped = VirtAddr::new(DATA_PAGE - 0x1000);
+    let mut buffer = [0u8; 8];
+
+    assert_eq!(
+        user_mem_mgr.copy_from_user(page_table, unmapped, &mut buffer),
+        Err(UserCopyError::NotMapped(unmapped))
+    );
+}
+
+#[test_case]
+fn read_only_page_is_not_written() {
+    let page_table = user_address_space();
+    let user_mem_mgr = get_user_mem_mgr();
+    let addr = VirtAddr::new(READ_ONLY_PAGE);
+    let mut buffer = [0u8; 8];
+
+    assert_eq!(
+        user_mem_mgr.copy_to_user(page_table, addr, &[1; 8]),
+        Err(UserCopyError::NotWritable(addr))
+    );
+    assert!(
+        user_mem_mgr
+            .copy_from_user(page_table, addr, &mut buffer)
+            .is_ok()
+    );
+}
+
+#[test_case]
+fn released_address_space_is_unmapped() {
+    let page_table = user_address_space();
+    let user_mem_mgr = get_user_mem_mgr();
+    let mut frame_allocator = get_frame_allocator();
+    let free_before = frame_allocator.free_frames();
+
+    user_mem_mgr.unmap_virt_mem_region(
+        page_table,
+        VirtAddr::new(DATA_PAGE),
+        0x3000,
+        &mut *frame_allocator,
+    );
+    assert!(
+        user_mem_mgr
+            .translate_user_virt_to_phys(page_table, VirtAddr::new(DATA_PAGE))
+            .is_none()
+    );
+
+    user_mem_mgr.free_address_space(page_table, &mut *frame_allocator);
+    // 3 data pages, the PDPT, PD and PT and the PML4 itself
+    assert_eq!(frame_allocator.free_frames(), free_before + 7);
+}
+
+#[test_case]
+fn kernel_addresses_are_never_mapped_for_userspace() {
+    let user_mem_mgr = get_user_mem_mgr();
+    let mut frame_allocator = get_frame_allocator();
+    let page_table = user_mem_mgr
+        .allocate_new_address_space(&mut frame_allocator)
+        .unwrap();
+    let free_before = frame_allocator.free_frames();
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    assert!(
+        user_mem_mgr
+            .map_virt_mem_region(
+                page_table,
+                VirtAddr::new(USER_SPACE_END),
+                0x1000,
+                flags,
+                &mut frame_allocator,
+            )
+            .is_err()
+    );
+    // nor a range only ending in the kernel half
+    assert!(
+        user_mem_mgr
+            .map_virt_mem_region(
+                page_table,
+                VirtAddr::new(USER_SPACE_END - 0x1000),
+                0x2000,
+                flags,
+                &mut frame_allocator,
+            )
+            .is_err()
+    );
+    assert_eq!(frame_allocator.free_frames(), free_before);
+
+    user_mem_mgr.free_address_space(page_table, &mut *frame_allocator);
+}
+
+#[test_case]
+fn freed_frame_is_reused() {
+    let mut frame_allocator = get_frame_allocator();
+    let frame = frame_allocator.allocate_frame().unwrap();
+    let free_before = frame_allocator.free_frames();
+
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    assert_eq!(frame_allocator.free_frames(), free_before + 1);
+    assert_eq!(frame_allocator.allocate_frame(), Some(frame));
+    assert_eq!(
+        frame_allocator.used_frames() + frame_allocator.free_frames(),
+        frame_allocator.total_frames()
+    );
+}
+
+#[test_case]
+fn contiguous_frames_are_aligned() {
+    let mut frame_allocator = get_frame_allocator();
+    let free_before = frame_allocator.free_frames();
+
+    let start = frame_allocator.allocate_contiguous(4, 4).unwrap();
+    assert!(start.start_address().is_aligned(4 * 4096u64));
+    let huge = frame_allocator.allocate_huge_frame().unwrap();
+    assert!(huge.start_address().is_aligned(2 * 1024 * 1024u64));
+    assert_eq!(
+        frame_allocator.free_frames(),
+        free_before - 4 - FRAMES_PER_HUGE_PAGE
+    );
+
+    unsafe {
+        frame_allocator.deallocate_contiguous(start, 4);
+        frame_allocator.deallocate_huge_frame(huge);
+    }
+    assert_eq!(frame_allocator.free_frames(), free_before);
+}
+
+#[test_case]
+fn lazy_region_is_backed_on_fault() {
+    let user_mem_mgr = get_user_mem_mgr();
+    let mut frame_allocator = get_frame_allocator();
+    let mut layout = ProcessMemoryLayout::new(&user_mem_mgr, &mut frame_allocator).unwrap();
+    let lazy_start = VirtAddr::new(DATA_PAGE);
+    layout.reserve_lazy(
+        lazy_start,
+        0x2000,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        VmaKind::Anonymous,
+    );
+    layout.reserve_lazy(
+        VirtAddr::new(READ_ONLY_PAGE),
+        0x1000,
+        PageTableFlags::PRESENT,
+        VmaKind::Anonymous,
+    );
+    let page_table = layout.top_page_table_phys;
+
+    let outside = VirtAddr::new(DATA_PAGE - 8);
+    assert_eq!(
+        layout.handle_page_fault(outside, false, &user_mem_mgr, &mut frame_allocator),
+        Err(PageFaultError::NotInRegion(outside))
+    );
+    let read_only = VirtAddr::new(READ_ONLY_PAGE);
+    assert_eq!(
+        layout.handle_page_fault(read_only, true, &user_mem_mgr, &mut frame_allocator),
+        Err(PageFaultError::AccessViolation(read_only))
+    );
+
+    let addr = lazy_start + 0x10u64;
+    assert!(!user_mem_mgr.is_page_mapped(page_table, addr));
+    assert_eq!(
+        layout.handle_page_fault(addr, true, &user_mem_mgr, &mut frame_allocator),
+        Ok(())
+    );
+    assert!(user_mem_mgr.is_page_mapped(page_table, addr));
+    // a second fault on a backed page is a protection violation
+    assert_eq!(
+        layout.handle_page_fault(addr, true, &user_mem_mgr, &mut frame_allocator),
+        Err(PageFaultError::AccessViolation(addr))
+    );
+
+    layout
+        .populate(
+            lazy_start,
+            0x2000,
+            false,
+            &user_mem_mgr,
+            &mut frame_allocator,
+        )
+        .unwrap();
+    assert!(user_mem_mgr.is_page_mapped(page_table, lazy_start + 0x1000u64));
+    layout.release(&user_mem_mgr, &mut *frame_allocator);
+}
+
+#[test_case]
+fn forked_pages_are_copied_on_write() {
+    let user_mem_mgr = get_user_mem_mgr();
+    let mut frame_allocator = get_frame_allocator();
+    let mut parent = ProcessMemoryLayout::new(&user_mem_mgr, &mut frame_allocator).unwrap();
+    let addr = VirtAddr::new(DATA_PAGE);
+    parent.reserve_lazy(
+        addr,
+        0x1000,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        VmaKind::Anonymous,
+    );
+    parent
+        .populate(addr, 0x1000, true, &user_mem_mgr, &mut frame_allocator)
+        .unwrap();
+    user_mem_mgr
+        .copy_to_user(parent.top_page_table_phys, addr, b"parent")
+        .unwrap();
+
+    let mut child = parent.fork(&user_mem_mgr, &mut frame_allocator).unwrap();
+    let shared = user_mem_mgr
+        .translate_user_virt_to_phys(parent.top_page_table_phys, addr)
+        .unwrap();
+    assert_eq!(
+        user_mem_mgr.translate_user_virt_to_phys(child.top_page_table_phys, addr),
+        Some(shared)
+    );
+    // both sides lost write access until the fault handler copies the page
+    assert_eq!(
+        user_mem_mgr.copy_to_user(child.top_page_table_phys, addr, b"child"),
+        Err(UserCopyError::NotWritable(addr))
+    );
+
+    assert_eq!(
+        child.handle_page_fault(addr, true, &user_mem_mgr, &mut frame_allocator),
+        Ok(())
+    );
+    let copy = user_mem_mgr
+        .translate_user_virt_to_phys(child.top_page_table_phys, addr)
+        .unwrap();
+    assert_ne!(copy, shared);
+    let mut buffer = [0u8; 6];
+    user_mem_mgr
+        .copy_from_user(child.top_page_table_phys, addr, &mut buffer)
+        .unwrap();
+    assert_eq!(&buffer, b"parent");
+
+    // the parent is the last owner now and keeps its frame
+    assert_eq!(
+        parent.handle_page_fault(addr, true, &user_mem_mgr, &mut frame_allocator),
+        Ok(())
+    );
+    assert_eq!(
+        user_mem_mgr.translate_user_virt_to_phys(parent.top_page_table_phys, addr),
+        Some(shared)
+    );
+
+    child.release(&user_mem_mgr, &mut *frame_allocator);
+    parent.release(&user_mem_mgr, &mut *frame_allocator);
+}
+
+#[test_case]
+fn exec_replaces_the_address_space() {
+    let elf_info = ElfLoadInfo::from_elf_data(FIRST_ELF).unwrap();
+    let mut process = Process::create_with_elf(&elf_info, "first", 42, 1, &["first"], &[]).unwrap();
+    let old_page_table = process.memory_layout.top_page_table_phys;
+
+    let mut old_layout = process.exec(&elf_info, "again", &["again"], &[]).unwrap();
+    assert_eq!(old_layout.top_page_table_phys, old_page_table);
+    assert_ne!(process.memory_layout.top_page_table_phys, old_page_table);
+    assert_eq!(
+        process.execution_context.page_table_base_phys,
+        process.memory_layout.top_page_table_phys.as_u64()
+    );
+    assert_eq!(process.execution_context.rip, elf_info.entry_point);
+    assert_eq!((process.pid, process.parent_pid), (42, 1));
+    assert_eq!(process.name, "again");
+
+    let user_mem_mgr = get_user_mem_mgr();
+    let mut frame_allocator = get_frame_allocator();
+    old_layout.release(&user_mem_mgr, &mut *frame_allocator);
+    process
+        .memory_layout
+        .release(&user_mem_mgr, &mut *frame_allocator);
+}
+
+#[test_case]
+fn arguments_larger_than_the_stack_are_refused() {
+    let elf_info = ElfLoadInfo::from_elf_data(FIRST_ELF).unwrap();
+    let long = "x".repeat(DEFAULT_NEW_PROCESS_STACK_SIZE as usize);
+    let mut process = Process::create_with_elf(&elf_info, "first", 42, 1, &["first"], &[]).unwrap();
+    let page_table = process.memory_layout.top_page_table_phys;
+
+    let free_before = get_frame_allocator().free_frames();
+    assert!(matches!(
+        process.exec(&elf_info, "again", &["again", &long], &[]),
+        Err(ProcessError::ArgumentsTooLong)
+    ));
+    // the half built address space is gone again and the process keeps its own
+    assert_eq!(get_frame_allocator().free_frames(), free_before);
+    assert_eq!(process.memory_layout.top_page_table_phys, page_table);
+
+    let user_mem_mgr = get_user_mem_mgr();
+    let mut frame_allocator = get_frame_allocator();
+    process
+        .memory_layout
+        .release(&user_mem_mgr, &mut *frame_allocator);
+}
+
+#[test_case]
+fn mappings_are_split_by_mprotect_and_munmap() {
+    let user_mem_mgr = get_user_mem_mgr();
+    let mut frame_allocator = get_frame_allocator();
+    let mut layout = ProcessMemoryLayout::new(&user_mem_mgr, &mut frame_allocator).unwrap();
+    let page = PAGE_SIZE as u64;
+    let read_write = (Protection::READ | Protection::WRITE).page_flags();
+
+    let start = layout.find_free_range(None, 4 * page).unwrap();
+    assert_eq!(start, VirtAddr::new(MMAP_BASE));
+    layout.reserve_lazy(start, 4 * page, read_write, VmaKind::Anonymous);
+    // an occupied hint is ignored
+    assert_eq!(
+        layout.find_free_range(Some(start + page), page),
+        Some(start + 4 * page)
+    );
+    layout
+        .populate(start, 4 * page, true, &user_mem_mgr, &mut frame_allocator)
+        .unwrap();
+    let page_table = layout.top_page_table_phys;
+
+    let read_only = start + page;
+    assert!(layout.protect(
+        read_only,
+        page,
+        Protection::READ.page_flags(),
+        &user_mem_mgr,
+        &frame_allocator
+    ));
+    assert_eq!(layout.vmas.len(), 3);
+    assert_eq!(
+        user_mem_mgr.copy_to_user(page_table, read_only, b"x"),
+        Err(UserCopyError::NotWritable(read_only))
+    );
+    assert_eq!(
+        layout.handle_page_fault(read_only, true, &user_mem_mgr, &mut frame_allocator),
+        Err(PageFaultError::AccessViolation(read_only))
+    );
+    user_mem_mgr
+        .copy_to_user(page_table, start + 2 * page, b"x")
+        .unwrap();
+    // nothing changes unless the whole range is mapped
+    assert!(!layout.protect(
+        start + 3 * page,
+        2 * page,
+        Protection::empty().page_flags(),
+        &user_mem_mgr,
+        &frame_allocator
+    ));
+
+    let free_before = frame_allocator.free_frames();
+    layout.unmap(start + 2 * page, page, &user_mem_mgr, &mut *frame_allocator);
+    assert_eq!(frame_allocator.free_frames(), free_before + 1);
+    assert!(!user_mem_mgr.is_page_mapped(page_table, start + 2 * page));
+    assert_eq!(layout.vmas.len(), 3);
+    assert_eq!(layout.find_free_range(None, page), Some(start + 2 * page));
+
+    let file_start = layout.find_free_range(None, 2 * page).unwrap();
+    let vma = Vma {
+        start_virt: file_start,
+        size_bytes: 2 * page,
+        page_flags: Protection::READ.page_flags(),
+        lazy: false,
+        kind: VmaKind::File,
+    };
+    layout
+        .map_filled(vma, b"hello", &user_mem_mgr, &mut frame_allocator)
+        .unwrap();
+    let mut buffer = [0xFFu8; 8];
+    user_mem_mgr
+        .copy_from_user(page_table, file_start, &mut buffer)
+        .unwrap();
+    assert_eq!(&buffer, b"hello\0\0\0");
+    assert_eq!(
+        user_mem_mgr.copy_to_user(page_table, file_start, b"x"),
+        Err(UserCopyError::NotWritable(file_start))
+    );
+
+    layout.release(&user_mem_mgr, &mut *frame_allocator);
+}
+
+#[test_case]
+fn the_last_user_page_is_refused() {
+    let last_page = (USER_SPACE_END - PAGE_SIZE as u64) as usize;
+    let mut frame = SyscallFrame::default();
+    let calls = [
+        SystemCall::Munmap {
+            addr: last_page,
+            len: PAGE_SIZE,
+        },
+        SystemCall::Mprotect {
+            addr: last_page,
+            len: PAGE_SIZE,
+            prot: Protection::READ.bits(),
+        },
+        SystemCall::Mmap {
+            addr: last_page,
+            len: PAGE_SIZE,
+            prot: (Protection::READ | Protection::WRITE).bits(),
+            flags: (MapFlags::PRIVATE | MapFlags::ANONYMOUS | MapFlags::FIXED).bits(),
+            fd: 0,
+            offset: 0,
+        },
+    ];
+    for call in calls {
+        assert_eq!(
+            handle_syscall(ARCHE_PID, call, &mut frame),
+            Err(SyscallError::InvalidPtr)
+        );
+    }
+}
+
+#[test_case]
+fn shrinking_the_heap_frees_its_pages() {
+    let user_mem_mgr = get_user_mem_mgr();
+    let mut frame_allocator = get_frame_allocator();
+    let mut layout = ProcessMemoryLayout::new(&user_mem_mgr, &mut frame_allocator).unwrap();
+    let page = PAGE_SIZE as u64;
+    let heap_start = layout.heap_start;
+    let page_table = layout.top_page_table_phys;
+
+    assert!(layout.set_heap_end(heap_start + page, &user_mem_mgr, &mut *frame_allocator));
+    assert!(layout.set_heap_end(
+        heap_start + 3 * page - 8,
+        &user_mem_mgr,
+        &mut *frame_allocator
+    ));
+    // growing in steps still makes a single area
+    assert_eq!(layout.vmas.len(), 1);
+    assert_eq!(layout.mapped_bytes(), 3 * page);
+    layout
+        .populate(
+            heap_start,
+            3 * page,
+            true,
+            &user_mem_mgr,
+            &mut frame_allocator,
+        )
+        .unwrap();
+
+    let free_before = frame_allocator.free_frames();
+    assert!(layout.set_heap_end(heap_start + page + 1, &user_mem_mgr, &mut *frame_allocator));
+    assert_eq!(frame_allocator.free_frames(), free_before + 1);
+    assert!(user_mem_mgr.is_page_mapped(page_table, heap_start + page));
+    assert!(!user_mem_mgr.is_page_mapped(page_table, heap_start + 2 * page));
+    assert_eq!(layout.mapped_bytes(), 2 * page);
+
+    assert!(!layout.set_heap_end(heap_start - 1u64, &user_mem_mgr, &mut *frame_allocator));
+    assert_eq!(layout.heap_end, heap_start + page + 1);
+    assert!(layout.set_heap_end(heap_start, &user_mem_mgr, &mut *frame_allocator));
+    assert!(layout.vmas.is_empty());
+
+    layout.release(&user_mem_mgr, &mut *frame_allocator);
+}
+
+#[test_case]
+fn user_stack_overflow_hits_its_guard() {
+    let elf_info = ElfLoadInfo::from_elf_data(FIRST_ELF).unwrap();
+    let mut process = Process::create_with_elf(&elf_info, "first", 42, 1, &["first"], &[]).unwrap();
+    let user_mem_mgr = get_user_mem_mgr();
+    let mut frame_allocator = get_frame_allocator();
+    let layout = &mut process.memory_layout;
+
+    let stack_bottom = layout.stack_top - layout.stack_size;
+    assert_eq!(
+        layout.handle_page_fault(stack_bottom, true, &user_mem_mgr, &mut frame_allocator),
+        Ok(())
+    );
+    let below = stack_bottom - 8u64;
+    assert_eq!(
+        layout.handle_page_fault(below, true, &user_mem_mgr, &mut frame_allocator),
+        Err(PageFaultError::StackOverflow(below))
+    );
+    // the guard is an area of its own, so nothing else gets mapped there
+    assert_eq!(
+        layout.vma_containing(below).map(|vma| vma.kind),
+        Some(VmaKind::Guard)
+    );
+    // and it can not be made accessible
+    assert!(!layout.protect(
+        below.align_down(PAGE_SIZE as u64),
+        2 * PAGE_SIZE as u64,
+        (Protection::READ | Protection::WRITE).page_flags(),
+        &user_mem_mgr,
+        &frame_allocator
+    ));
+
+    layout.release(&user_mem_mgr, &mut *frame_allocator);
+}
@@ -40,6 +40,7 @@ bitflags! {
         const AES = 1 << 12;
         const RDRAND = 1 << 13;
         const HYPERVISOR = 1 << 14; // indicates running inside a VM
+        const NX = 1 << 15; // No-Execute page protection
     }
 }
 
@@ -178,6 +179,14 @@ pub unsafe fn init_cpu_info() {
         features |= CpuFeatureFlags::HYPERVISOR;
     }
 
+    let (max_extended_leaf, _, _, _) = unsafe { cpuid(0x8000_0000) };
+    if max_extended_leaf >= 0x8000_0001 {
+        let (_, _, _, ext_feat_edx) = unsafe { cpuid(0x8000_0001) };
+        if ext_feat_edx & (1 << 20) != 0 {
+            features |= CpuFeatureFlags::NX;
+        }
+    }
+
     let cache_line_size = ((feat_ebx >> 8) & 0xFF) as u8 * 8;
     let apic_id = ((feat_ebx >> 24) & 0xFF) as u8;
     let cpu_family = ((feat_edx >> 8) & 0xF) as u8;
@@ -261,6 +270,9 @@ impl CpuFeatureFlags {
         if self.contains(CpuFeatureFlags::HYPERVISOR) {
             features.push("Hypervisor");
         }
+        if self.contains(CpuFeatureFlags::NX) {
+            features.push("NX");
+        }
 
         features
     }
@@ -2,17 +2,30 @@
 
 extern int main(int argc, char **argv);
 
-void _start(void) {
-    int argc = 0;
-    char **argv = (void*)0;
-    
+char **environ;
+
+// the kernel starts us with rsp pointing at argc, followed by the argv and envp arrays
+__asm__(
+    ".global _start\n"
+    "_start:\n"
+    "    xor %rbp, %rbp\n"
+    "    mov %rsp, %rdi\n"
+    "    and $-16, %rsp\n"
+    "    call _start_c\n"
+    "    hlt\n");
+
+void _start_c(long *stack) {
+    int argc = (int)stack[0];
+    char **argv = (char **)(stack + 1);
+    environ = argv + argc + 1;
+
     int ret = main(argc, argv);
-    
+
     sys_exit(ret);
-    
+
     while(1);
 }
 
 void __stack_chk_fail(void) {
     sys_exit(1);
-}
\ No newline at end of file
+}
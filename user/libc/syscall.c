@@ -0,0 +1,9 @@
+#include "syscall.h"
+
+// a handler returns here, the kernel finds the signal frame right above the stack pointer
+__asm__(
+    ".global sys_sigreturn\n"
+    "sys_sigreturn:\n"
+    "    mov $21, %eax\n" // SYS_SIGRETURN
+    "    syscall\n"
+    "    hlt\n");
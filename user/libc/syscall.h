@@ -15,9 +15,126 @@
 #define SYS_UNLOAD_FILE 9
 #define SYS_CREATE_WINDOW 10
 #define SYS_GET_PROCESS_INFO 11
+#define SYS_WAIT_PID 12
+#define SYS_FORK 13
+#define SYS_EXEC 14
+#define SYS_OPEN 15
+#define SYS_CLOSE 16
+#define SYS_SEEK 17
+#define SYS_PIPE 18
+#define SYS_DUP2 19
+#define SYS_SIGACTION 20
+#define SYS_SIGRETURN 21
+#define SYS_SIGPROCMASK 22
+#define SYS_KILL 23
+#define SYS_SLEEP 24
+#define SYS_GET_TIME 25
+#define SYS_MMAP 26
+#define SYS_MUNMAP 27
+#define SYS_MPROTECT 28
+#define SYS_BRK 29
 
 #define SYS_EXIT 999
 
+/* Syscalls return a non-negative result or a negated error code */
+#define E_INVALID_PTR 1
+#define E_PERMISSION_DENIED 2
+#define E_OUT_OF_MEMORY 3
+#define E_PROCESS_NOT_FOUND 4
+#define E_INVALID_FD 5
+#define E_INVALID_ARGUMENT 6
+#define E_FILE_NOT_FOUND 7
+#define E_FILE_EXISTS 8
+#define E_NO_SPACE 9
+#define E_IO_ERROR 10
+#define E_NOT_SUPPORTED 11
+#define E_BROKEN_PIPE 12
+#define E_ARGUMENT_LIST_TOO_LONG 13
+#define E_SYSCALL_NOT_FOUND 999
+
+#define SYS_FAILED(ret) ((long)(ret) < 0)
+
+#define PROCESS_STATE_READY 0
+#define PROCESS_STATE_RUNNING 1
+#define PROCESS_STATE_WAITING 2
+#define PROCESS_STATE_TERMINATED 3
+
+#define WAIT_ANY_CHILD (-1)
+#define WAIT_NO_HANG 1
+
+#define STDIN_FILENO 0
+#define STDOUT_FILENO 1
+#define STDERR_FILENO 2
+
+/* sys_open flags */
+#define O_READ 1
+#define O_WRITE 2
+#define O_CREATE 4
+#define O_APPEND 8
+
+#define SEEK_SET 0
+#define SEEK_CUR 1
+#define SEEK_END 2
+
+/* Mmap and Mprotect protections, PROT_NONE allows no access at all */
+#define PROT_NONE 0
+#define PROT_READ 1
+#define PROT_WRITE 2
+#define PROT_EXEC 4
+
+#define MAP_SHARED 0x01
+#define MAP_PRIVATE 0x02
+#define MAP_FIXED 0x10
+#define MAP_ANONYMOUS 0x20
+
+/* signal numbers, a process killed by one exits with 128 + its number */
+#define SIGINT 2
+#define SIGILL 4
+#define SIGBUS 7
+#define SIGFPE 8
+#define SIGKILL 9
+#define SIGSEGV 11
+#define SIGPIPE 13
+#define SIGTERM 15
+#define SIGCHLD 17
+
+#define SIGMASK(sig) (1ULL << (sig))
+
+typedef void (*sighandler_t)(int);
+
+#define SIG_DFL ((sighandler_t)0)
+#define SIG_IGN ((sighandler_t)1)
+
+/* sys_sigprocmask operations */
+#define SIG_BLOCK 0
+#define SIG_UNBLOCK 1
+#define SIG_SETMASK 2
+
+struct sigaction {
+    sighandler_t handler;
+    /* blocked on top of the handled signal while the handler runs */
+    uint64_t mask;
+    /* the handler returns into this, it has to make the sigreturn syscall */
+    void (*restorer)(void);
+};
+
+/* registers of the interrupted code, saved on the stack while a handler runs */
+struct sigcontext {
+    uint64_t r15, r14, r13, r12, r11, r10, r9, r8;
+    uint64_t rbp, rdi, rsi, rdx, rcx, rbx, rax;
+    uint64_t rip, rflags, rsp;
+};
+
+struct process_info {
+    uint64_t pid;
+    uint64_t parent_pid;
+    uint64_t priority;
+    uint64_t state;
+    uint64_t memory_used;
+    uint64_t memory_limit;
+    int64_t exit_code;
+};
+
 static inline long syscall6(
     long num,
     long arg1,
@@ -46,14 +163,26 @@ static inline long syscall6(
     return ret;
 }
 
+static inline long syscall4(long num, long arg1, long arg2, long arg3, long arg4) {
+    return syscall6(num, arg1, arg2, arg3, arg4, 0, 0);
+}
+
 static inline long syscall3(long num, long arg1, long arg2, long arg3) {
     return syscall6(num, arg1, arg2, arg3, 0, 0, 0);
 }
 
+static inline long syscall2(long num, long arg1, long arg2) {
+    return syscall6(num, arg1, arg2, 0, 0, 0, 0);
+}
+
 static inline long syscall1(long num, long arg1) {
     return syscall6(num, arg1, 0, 0, 0, 0, 0);
 }
 
+static inline long syscall0(long num) {
+    return syscall6(num, 0, 0, 0, 0, 0, 0);
+}
+
 static inline void sys_exit(int code) {
     syscall1(SYS_EXIT, code);
     __builtin_unreachable();
@@ -67,8 +196,142 @@ static inline long sys_read(int fd, void *buf, size_t count) {
     return syscall3(SYS_READ, fd, (long)buf, count);
 }
 
+static inline long sys_get_line(int fd, char *buf, size_t count) {
+    return syscall3(SYS_GET_LINE, fd, (long)buf, count);
+}
+
+/* returns NULL when the kernel couldn't provide the memory */
 static inline void* sys_allocate(size_t size) {
-    return (void*)syscall1(SYS_ALLOCATE, size);
+    long ret = syscall1(SYS_ALLOCATE, size);
+    return SYS_FAILED(ret) ? NULL : (void*)ret;
+}
+
+static inline long sys_create_process(long parent_pid, const char *name, uint8_t name_len, int is_out) {
+    return syscall4(SYS_CREATE_PROCESS, parent_pid, (long)name, name_len, is_out);
+}
+
+static inline long sys_terminate_process(long pid, int exit_code, int kill_children) {
+    return syscall3(SYS_TERMINATE_PROCESS, pid, exit_code, kill_children);
+}
+
+static inline long sys_create_file(const char *path, size_t path_len) {
+    return syscall2(SYS_CREATE_FILE, (long)path, path_len);
+}
+
+static inline long sys_remove_file(const char *path, size_t path_len) {
+    return syscall2(SYS_REMOVE_FILE, (long)path, path_len);
+}
+
+/* returns a file descriptor usable with sys_read, sys_get_line and sys_write */
+static inline long sys_load_file(const char *path, size_t path_len) {
+    return syscall2(SYS_LOAD_FILE, (long)path, path_len);
+}
+
+static inline long sys_unload_file(int fd) {
+    return syscall1(SYS_UNLOAD_FILE, fd);
+}
+
+static inline long sys_create_window(long pid) {
+    return syscall1(SYS_CREATE_WINDOW, pid);
+}
+
+static inline long sys_get_process_info(long pid, struct process_info *info) {
+    return syscall2(SYS_GET_PROCESS_INFO, pid, (long)info);
+}
+
+// returns the reaped child's pid, 0 with WAIT_NO_HANG when no child has exited yet
+static inline long sys_wait_pid(long pid, int *status, int flags) {
+    return syscall3(SYS_WAIT_PID, pid, (long)status, flags);
+}
+
+// returns the child's pid in the parent and 0 in the child
+static inline long sys_fork(void) {
+    return syscall1(SYS_FORK, 0);
+}
+
+// environment the program was started with, set up by crt0
+extern char **environ;
+
+// only returns on failure, argv and envp are null terminated
+static inline long sys_exec(const char *path, size_t path_len, char *const argv[],
+                            char *const envp[]) {
+    return syscall4(SYS_EXEC, (long)path, path_len, (long)argv, (long)envp);
+}
+
+// returns the new file descriptor
+static inline long sys_open(const char *path, size_t path_len, int flags) {
+    return syscall3(SYS_OPEN, (long)path, path_len, flags);
+}
+
+static inline long sys_close(int fd) {
+    return syscall1(SYS_CLOSE, fd);
+}
+
+// returns the new position of the cursor
+static inline long sys_seek(int fd, long offset, int whence) {
+    return syscall3(SYS_SEEK, fd, offset, whence);
+}
+
+// fds[0] is the read end, fds[1] the write end
+static inline long sys_pipe(int fds[2]) {
+    return syscall1(SYS_PIPE, (long)fds);
+}
+
+// returns new_fd
+static inline long sys_dup2(int old_fd, int new_fd) {
+    return syscall2(SYS_DUP2, old_fd, new_fd);
+}
+
+// returns from a signal handler, defined in syscall.c
+void sys_sigreturn(void);
+
+// act or old may be null
+static inline long sys_sigaction(int sig, const struct sigaction *act, struct sigaction *old) {
+    return syscall3(SYS_SIGACTION, sig, (long)act, (long)old);
+}
+
+// installs handler for sig, returning from it resumes the interrupted code
+static inline long sys_signal(int sig, sighandler_t handler) {
+    struct sigaction act = { handler, 0, sys_sigreturn };
+    return sys_sigaction(sig, &act, NULL);
+}
+
+// returns the previously blocked signals
+static inline long sys_sigprocmask(int how, uint64_t mask) {
+    return syscall2(SYS_SIGPROCMASK, how, (long)mask);
+}
+
+// pid has to be the caller or one of its children, sig 0 only checks that
+static inline long sys_kill(long pid, int sig) {
+    return syscall2(SYS_KILL, pid, sig);
+}
+
+// blocks for at least ns nanoseconds
+static inline long sys_sleep(uint64_t ns) {
+    return syscall1(SYS_SLEEP, (long)ns);
+}
+
+// nanoseconds since boot
+static inline long sys_get_time(void) {
+    return syscall0(SYS_GET_TIME);
+}
+
+// addr is only a hint without MAP_FIXED, returns where the mapping went
+static inline void *sys_mmap(void *addr, uint64_t len, int prot, int flags, int fd, uint64_t offset) {
+    return (void *)syscall6(SYS_MMAP, (long)addr, (long)len, prot, flags, fd, (long)offset);
+}
+
+static inline long sys_munmap(void *addr, uint64_t len) {
+    return syscall2(SYS_MUNMAP, (long)addr, (long)len);
+}
+
+static inline long sys_mprotect(void *addr, uint64_t len, int prot) {
+    return syscall3(SYS_MPROTECT, (long)addr, (long)len, prot);
+}
+
+/* Moves the end of the heap to addr and returns the end afterwards, NULL only queries it */
+static inline void *sys_brk(void *addr) {
+    return (void *)syscall1(SYS_BRK, (long)addr);
 }
 
-#endif
\ No newline at end of file
+#endif
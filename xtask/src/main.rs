@@ -385,7 +385,7 @@ fn cflags() -> Vec<&'static str> {
         "-nostdinc",
         "-fno-builtin",
         "-fno-stack-protector",
-        "-fno-pie",
+        "-fpie",
         "-mno-red-zone",
         "-m64",
         "-Wall",
@@ -452,7 +452,15 @@ fn build_user(root: &Path) {
         compile_c(&src, &obj, &[]);
 
         run(Command::new(ld())
-            .args(["-T", "linker.ld", "-nostdlib", "-static", "-no-pie"])
+            // static-pie, the kernel picks the load base and applies the relocations
+            .args([
+                "-nostdlib",
+                "-static",
+                "-pie",
+                "--no-dynamic-linker",
+                "-e",
+                "_start",
+            ])
             .arg("-o")
             .arg(out_dir.join(name))
             .arg(&crt0_o)